@@ -1,38 +1,90 @@
+// Offline work only: the build must not need the network or any environment
+// beyond what cargo sets. The SVD for debuggers is fetched by tools/fetch-svd.
 use std::env;
 use std::fs;
-use std::io::Write;
-use std::path::Path;
+use std::path::PathBuf;
+
+#[allow(dead_code)]
+#[path = "src/rom_meta.rs"]
+mod rom_meta;
+
+use rom_meta::{parse_meta, DEFAULT_IPF, QUIRK_NAMES};
 
 fn main() {
-    // Retrieve the target chip series from the environment variable
-    let target = env::var("CHIPSERIE").expect("CHIPSERIE not provided");
-
-    let filename = format!("{}.svd.patched", target);
-    let url = format!("https://stm32-rs.github.io/stm32-rs/{}", &filename);
-    let output_path = Path::new(&filename);
-
-    // Check if the file already exists
-    if output_path.exists() {
-        println!(
-            "SVD file already exists at {:?}, skipping download.",
-            output_path
-        );
-    } else {
-        // Ensure the output directory exists
-        if let Some(parent) = output_path.parent() {
-            fs::create_dir_all(parent).expect("Failed to create directory for SVD file");
-        }
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/rom_meta.rs");
+    bundle_roms();
+}
 
-        // Download the file
-        println!("Downloading SVD file from {}...", url);
-        let response = reqwest::blocking::get(&url).expect("Failed to fetch SVD file");
-        let content = response.text().expect("Failed to read response body");
+// Largest program that fits between 0x200 and the end of the 4 KiB memory
+const MAX_ROM_BYTES: usize = 4096 - 0x200;
+// Write $OUT_DIR/roms.rs with a table of every .ch8 file in roms/, sorted by
+// name, for src/roms.rs to include. A ROM that can't be loaded fails the
+// build here rather than on the board.
+fn bundle_roms() {
+    println!("cargo:rerun-if-changed=roms");
+    let manifest_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let rom_dir = manifest_dir.join("roms");
 
-        // Write the downloaded content to the file
-        let mut file = fs::File::create(output_path).expect("Failed to create SVD file");
-        file.write_all(content.as_bytes())
-            .expect("Failed to write SVD file");
+    let mut roms: Vec<(String, PathBuf)> = fs::read_dir(&rom_dir)
+        .expect("Failed to read the roms directory")
+        .map(|entry| entry.expect("Failed to read the roms directory").path())
+        .filter(|path| {
+            path.extension()
+                .is_some_and(|ext| ext.eq_ignore_ascii_case("ch8"))
+        })
+        .map(|path| {
+            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+            (name, path)
+        })
+        .collect();
+    roms.sort_by_key(|(name, _)| name.to_lowercase());
+    if roms.is_empty() {
+        panic!("No .ch8 ROMs found in {:?}", rom_dir);
+    }
+
+    // Also the speed of ROMs that don't come from roms/
+    let mut table = format!(
+        "pub const DEFAULT_IPF: u16 = {};\n\npub static ROMS: &[Rom] = &[\n",
+        DEFAULT_IPF
+    );
+    for (name, path) in &roms {
+        let bytes = fs::read(path).unwrap_or_else(|e| panic!("Failed to read {:?}: {}", path, e));
+        if bytes.is_empty() || bytes.len() > MAX_ROM_BYTES {
+            panic!(
+                "ROM {:?} is {} bytes, it must be between 1 and {} bytes",
+                path,
+                bytes.len(),
+                MAX_ROM_BYTES
+            );
+        }
+        let meta_path = path.with_extension("meta");
+        let meta = parse_meta(&meta_path)
+            .unwrap_or_else(|e| panic!("Invalid ROM metadata in {:?}: {}", meta_path, e));
 
-        println!("SVD file saved to {:?}", output_path);
+        let sha1 = sha1_smol::Sha1::from(&bytes).digest().bytes();
+        let quirks: Vec<String> = QUIRK_NAMES
+            .iter()
+            .map(|quirk| format!("{}: {}", quirk, meta.quirks.contains(quirk)))
+            .collect();
+        let keymap = match meta.keymap {
+            Some(keys) => format!("Some(KeyMap {{ keys: {:?} }})", keys),
+            None => String::from("None"),
+        };
+        table.push_str(&format!(
+            "    Rom {{\n        name: {:?},\n        bytes: include_bytes!({:?}),\n        \
+             sha1: {:?},\n        quirks: Quirks {{ {} }},\n        keymap: {},\n        \
+             ipf: {},\n    }},\n",
+            name,
+            path,
+            sha1,
+            quirks.join(", "),
+            keymap,
+            meta.ipf
+        ));
     }
+    table.push_str("];\n");
+
+    let out_path = PathBuf::from(env::var("OUT_DIR").unwrap()).join("roms.rs");
+    fs::write(&out_path, table).expect("Failed to write the ROM table");
 }
@@ -1,7 +1,8 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 512K
+  /* The last two 128K sectors (0x08040000) are kept free for flash storage */
+  FLASH (rx) : ORIGIN = 0x08000000, LENGTH = 256K
   RAM (rwx) : ORIGIN = 0x20000000, LENGTH = 128K
 }
 
@@ -0,0 +1,73 @@
+// Sound output that does not depend on the board. CHIP-8 has one beeper that
+// sounds while sound_timer is nonzero. Sinks are told what to play whenever
+// it changes: a buzzer on the board, a WAV file on the host.
+use crate::chip8::Chip8;
+
+// Stored form: magic, tone (LE), volume, checksum
+pub const AUDIO_SETTINGS_BYTES: usize = 5;
+const AUDIO_SETTINGS_MAGIC: u8 = 0xA5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct AudioSettings {
+    // Pitch of the beep
+    pub tone_hz: u16,
+    // 0 is silent, 100 is as loud as the output goes
+    pub volume: u8,
+}
+
+impl Default for AudioSettings {
+    fn default() -> AudioSettings {
+        AudioSettings {
+            tone_hz: 440,
+            volume: 50,
+        }
+    }
+}
+
+impl AudioSettings {
+    pub fn to_bytes(self) -> [u8; AUDIO_SETTINGS_BYTES] {
+        let tone = self.tone_hz.to_le_bytes();
+        let mut bytes = [AUDIO_SETTINGS_MAGIC, tone[0], tone[1], self.volume, 0];
+        bytes[AUDIO_SETTINGS_BYTES - 1] = checksum(&bytes[..AUDIO_SETTINGS_BYTES - 1]);
+        bytes
+    }
+
+    // None if the bytes are not valid stored settings
+    pub fn from_bytes(bytes: &[u8]) -> Option<AudioSettings> {
+        if bytes.len() < AUDIO_SETTINGS_BYTES || bytes[0] != AUDIO_SETTINGS_MAGIC {
+            return None;
+        }
+        if checksum(&bytes[..AUDIO_SETTINGS_BYTES - 1]) != bytes[AUDIO_SETTINGS_BYTES - 1] {
+            return None;
+        }
+        Some(AudioSettings {
+            tone_hz: u16::from_le_bytes([bytes[1], bytes[2]]),
+            volume: bytes[3].min(100),
+        })
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) ^ 0xFF
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Sound {
+    Silent,
+    Tone,
+}
+
+// What the emulator should be playing right now
+pub fn sound_for(chip8: &Chip8) -> Sound {
+    if chip8.sound_timer == 0 {
+        Sound::Silent
+    } else {
+        Sound::Tone
+    }
+}
+
+pub trait AudioSink {
+    // May be called with the same sound over and over, sinks should only act
+    // on changes
+    fn play(&mut self, sound: Sound, settings: &AudioSettings);
+}
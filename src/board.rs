@@ -0,0 +1,201 @@
+// Pin map for the STM32F411 board. Everything that cares about which pin is
+// wired to what takes its pins from here, so a different wiring only needs
+// changes in this file.
+use crate::keymap::BUTTON_COUNT;
+use crate::keypad::{COLS, ROWS};
+use stm32f4xx_hal::gpio::{gpioa, gpiob, ErasedPin, Input, Output, PinState, PushPull};
+use stm32f4xx_hal::pac::RCC;
+use stm32f4xx_hal::prelude::*;
+use stm32f4xx_hal::rcc::Clocks;
+
+#[cfg(all(feature = "i2c", feature = "spi"))]
+compile_error!("features \"i2c\" and \"spi\" select the display bus and are mutually exclusive");
+#[cfg(not(any(feature = "i2c", feature = "spi")))]
+compile_error!("enable one of the \"i2c\" or \"spi\" features to select the display bus");
+#[cfg(not(any(
+    feature = "panel-ssd1306",
+    feature = "panel-ssd1306-128x32",
+    feature = "panel-ssd1309",
+    feature = "panel-sh1106",
+    feature = "panel-st7565",
+)))]
+compile_error!("enable one of the \"panel-*\" features to select the panel controller");
+#[cfg(any(
+    all(feature = "panel-ssd1306", feature = "panel-ssd1306-128x32"),
+    all(feature = "panel-ssd1306", feature = "panel-ssd1309"),
+    all(feature = "panel-ssd1306", feature = "panel-sh1106"),
+    all(feature = "panel-ssd1306", feature = "panel-st7565"),
+    all(feature = "panel-ssd1306-128x32", feature = "panel-ssd1309"),
+    all(feature = "panel-ssd1306-128x32", feature = "panel-sh1106"),
+    all(feature = "panel-ssd1306-128x32", feature = "panel-st7565"),
+    all(feature = "panel-ssd1309", feature = "panel-sh1106"),
+    all(feature = "panel-ssd1309", feature = "panel-st7565"),
+    all(feature = "panel-sh1106", feature = "panel-st7565"),
+))]
+compile_error!("the \"panel-*\" features select the panel controller and are mutually exclusive");
+
+// I2C1 - SCL is PB6 and SDA is PB7; they are set to Alternate Function 4
+#[cfg(feature = "i2c")]
+pub struct DisplayPins {
+    pub scl: gpiob::PB6,
+    pub sda: gpiob::PB7,
+}
+
+// SPI display - CS is PA1, DC is PB6 and RST is PB7, plain push-pull
+// outputs. The panel sits on SPI1 next to the SD card, see spi_bus.rs
+#[cfg(feature = "spi")]
+pub struct DisplayPins {
+    pub cs: gpioa::PA1<Output<PushPull>>,
+    pub dc: gpiob::PB6<Output<PushPull>>,
+    pub rst: gpiob::PB7<Output<PushPull>>,
+}
+
+// USB OTG FS - D- is PA11 and D+ is PA12 (Alternate Function 10)
+pub struct UsbPins {
+    pub dm: gpioa::PA11,
+    pub dp: gpioa::PA12,
+}
+
+// USART2 for ROM uploads - TX is PA2 and RX is PA3 (Alternate Function 7)
+pub struct UartPins {
+    pub tx: gpioa::PA2,
+    pub rx: gpioa::PA3,
+}
+
+// SPI1 - SCK is PA5, MISO is PA6 and MOSI is PA7 (Alternate Function 5).
+// The SD card is on it in every build, the SPI display as well
+pub struct Spi1Pins {
+    pub sck: gpioa::PA5,
+    pub miso: gpioa::PA6,
+    pub mosi: gpioa::PA7,
+}
+
+// microSD card chip select, PA4
+pub struct SdPins {
+    pub cs: gpioa::PA4<Output<PushPull>>,
+}
+
+// EXTI lines of the keypad columns, all on port A. The power module takes
+// them over to wake up on a key press
+pub const KEYPAD_COL_LINES: [u8; COLS] = [8, 9, 10, 15];
+
+// EXTI lines of the gamepad buttons in Button order, and of the button
+pub const GAMEPAD_LINES: [u8; BUTTON_COUNT] = [12, 13, 14, 15, 8, 9];
+pub const BUTTON_IN_LINE: u8 = 1;
+
+// An EXTI line listens to one port only, so every input above needs a line
+// of its own. The keypad columns share 8, 9 and 15 with the gamepad: they
+// only use them while the core is stopped, and stop_until_key in power.rs
+// gives the lines back to the gamepad before anything else runs. An EXTI
+// source added later has to go on a line none of these use
+const _: () = {
+    let mut lines = 1u32 << BUTTON_IN_LINE;
+    let mut i = 0;
+    while i < GAMEPAD_LINES.len() {
+        assert!(lines & 1 << GAMEPAD_LINES[i] == 0);
+        lines |= 1 << GAMEPAD_LINES[i];
+        i += 1;
+    }
+    let mut i = 0;
+    while i < KEYPAD_COL_LINES.len() {
+        assert!(KEYPAD_COL_LINES[i] != BUTTON_IN_LINE);
+        i += 1;
+    }
+};
+
+pub type KeypadRow = ErasedPin<Output<PushPull>>;
+pub type KeypadCol = ErasedPin<Input>;
+pub type GamepadButton = ErasedPin<Input>;
+
+pub struct Pins {
+    // Piezo buzzer, TIM2 channel 1 (Alternate Function 1)
+    pub buzzer: gpioa::PA0,
+    pub display: DisplayPins,
+    // Hex keypad - rows PB3, PB4, PB5, PB10 are driven, columns PA8, PA9,
+    // PA10, PA15 are pull-down inputs. PB3, PB4 and PA15 are JTAG pins,
+    // which are free since the probe uses SWD
+    pub keypad_rows: [KeypadRow; ROWS],
+    pub keypad_cols: [KeypadCol; COLS],
+    // PB0 drives one side of the button, PB1 reads the other side
+    pub button_out: gpiob::PB0<Output<PushPull>>,
+    pub button_in: gpiob::PB1<Input>,
+    // D-pad and A/B buttons in Button order - PB12, PB13, PB14, PB15, PB8,
+    // PB9, pull-up inputs switched to ground, on GAMEPAD_LINES
+    pub gamepad: [GamepadButton; BUTTON_COUNT],
+    pub usb: UsbPins,
+    pub uart: UartPins,
+    pub spi1: Spi1Pins,
+    pub sd: SdPins,
+}
+
+// 96 MHz from the 25 MHz crystal. USB needs an exact 48 MHz, which the PLL
+// can only make from the crystal at a 96 MHz system clock, not at 100 MHz
+pub fn clocks(rcc: RCC) -> Clocks {
+    rcc.constrain()
+        .cfgr
+        .use_hse(25.MHz())
+        .sysclk(96.MHz())
+        .require_pll48clk()
+        .freeze()
+}
+
+impl Pins {
+    pub fn new(gpioa: gpioa::Parts, gpiob: gpiob::Parts) -> Pins {
+        #[cfg(feature = "i2c")]
+        let display = DisplayPins {
+            scl: gpiob.pb6.internal_pull_up(true),
+            sda: gpiob.pb7.internal_pull_up(true),
+        };
+        #[cfg(feature = "spi")]
+        let display = DisplayPins {
+            cs: gpioa.pa1.into_push_pull_output_in_state(PinState::High),
+            dc: gpiob.pb6.into_push_pull_output(),
+            rst: gpiob.pb7.into_push_pull_output(),
+        };
+
+        Pins {
+            buzzer: gpioa.pa0,
+            display,
+            keypad_rows: [
+                gpiob.pb3.into_push_pull_output().erase(),
+                gpiob.pb4.into_push_pull_output().erase(),
+                gpiob.pb5.into_push_pull_output().erase(),
+                gpiob.pb10.into_push_pull_output().erase(),
+            ],
+            keypad_cols: [
+                gpioa.pa8.into_pull_down_input().erase(),
+                gpioa.pa9.into_pull_down_input().erase(),
+                gpioa.pa10.into_pull_down_input().erase(),
+                gpioa.pa15.into_pull_down_input().erase(),
+            ],
+            button_out: gpiob.pb0.into_push_pull_output(),
+            button_in: gpiob.pb1.into_pull_down_input(),
+            gamepad: [
+                gpiob.pb12.into_pull_up_input().erase(),
+                gpiob.pb13.into_pull_up_input().erase(),
+                gpiob.pb14.into_pull_up_input().erase(),
+                gpiob.pb15.into_pull_up_input().erase(),
+                gpiob.pb8.into_pull_up_input().erase(),
+                gpiob.pb9.into_pull_up_input().erase(),
+            ],
+            usb: UsbPins {
+                dm: gpioa.pa11,
+                dp: gpioa.pa12,
+            },
+            uart: UartPins {
+                tx: gpioa.pa2,
+                rx: gpioa.pa3,
+            },
+            spi1: Spi1Pins {
+                sck: gpioa.pa5,
+                miso: gpioa.pa6,
+                mosi: gpioa.pa7,
+            },
+            // Deselected, so the card stays off the bus while the display
+            // is set up
+            sd: SdPins {
+                cs: gpioa.pa4.into_push_pull_output_in_state(PinState::High),
+            },
+        }
+    }
+}
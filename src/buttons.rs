@@ -0,0 +1,115 @@
+// Gamepad and menu buttons on EXTI interrupts. Any edge (re)starts a one-shot
+// TIM3 delay; once the contacts have been quiet for DEBOUNCE_US all buttons
+// are sampled and every change is pushed onto a lock-free queue. The input
+// task drains the queue, so a press and release that both happen between
+// two of its ticks still arrive as two events. The app binds the EXTI and
+// TIM3 interrupts to `on_edge` and `on_timer`.
+use crate::board::{GamepadButton, BUTTON_IN_LINE, GAMEPAD_LINES};
+use crate::input::{self, ButtonEvent};
+use crate::keymap::{button_bit, Button, ButtonMask, BUTTON_COUNT};
+use heapless::spsc::{Consumer, Producer, Queue};
+use stm32f4xx_hal::gpio::{gpiob, Edge, ExtiPin, Input, PinExt};
+use stm32f4xx_hal::pac::{EXTI, TIM3};
+use stm32f4xx_hal::syscfg::SysCfg;
+use stm32f4xx_hal::timer::{CounterUs, Event, Flag};
+use stm32f4xx_hal::{prelude::*, rcc::Clocks};
+
+// The queue holds QUEUE_LEN - 1 events, plenty for a few frames of mashing
+pub const QUEUE_LEN: usize = 32;
+pub type ButtonQueue = Queue<ButtonEvent, QUEUE_LEN>;
+pub type ButtonEvents = Consumer<'static, ButtonEvent, QUEUE_LEN>;
+
+const DEBOUNCE_US: u32 = 5_000;
+
+pub struct Buttons {
+    gamepad: [GamepadButton; BUTTON_COUNT],
+    menu: gpiob::PB1<Input>,
+    timer: CounterUs<TIM3>,
+    events: Producer<'static, ButtonEvent, QUEUE_LEN>,
+    // Last debounced state
+    state: ButtonMask,
+}
+
+impl Buttons {
+    pub fn new(
+        mut gamepad: [GamepadButton; BUTTON_COUNT],
+        mut menu: gpiob::PB1<Input>,
+        syscfg: &mut SysCfg,
+        exti: &mut EXTI,
+        tim3: TIM3,
+        clocks: &Clocks,
+        queue: &'static mut ButtonQueue,
+    ) -> (Buttons, ButtonEvents) {
+        for (pin, &line) in gamepad.iter_mut().zip(GAMEPAD_LINES.iter()) {
+            assert_eq!(pin.pin_id(), line, "gamepad pin off its EXTI line");
+            pin.make_interrupt_source(syscfg);
+            pin.trigger_on_edge(exti, Edge::RisingFalling);
+            pin.enable_interrupt(exti);
+        }
+        assert_eq!(
+            menu.pin_id(),
+            BUTTON_IN_LINE,
+            "button pin off its EXTI line"
+        );
+        menu.make_interrupt_source(syscfg);
+        menu.trigger_on_edge(exti, Edge::RisingFalling);
+        menu.enable_interrupt(exti);
+
+        let mut timer = tim3.counter_us(clocks);
+        timer.listen(Event::Update);
+
+        let (producer, consumer) = queue.split();
+        let mut buttons = Buttons {
+            gamepad,
+            menu,
+            timer,
+            events: producer,
+            state: 0,
+        };
+        // Buttons held at power on only report their release
+        buttons.state = buttons.sample();
+        (buttons, consumer)
+    }
+
+    // Any of the EXTI lines
+    pub fn on_edge(&mut self) {
+        for pin in self.gamepad.iter_mut() {
+            if pin.check_interrupt() {
+                pin.clear_interrupt_pending_bit();
+            }
+        }
+        if self.menu.check_interrupt() {
+            self.menu.clear_interrupt_pending_bit();
+        }
+        // Restarting pushes the sample back past the last bounce
+        let _ = self.timer.start(DEBOUNCE_US.micros());
+    }
+
+    // The debounce delay has run out
+    pub fn on_timer(&mut self) {
+        self.timer.clear_flags(Flag::Update);
+        let _ = self.timer.cancel();
+        let state = self.sample();
+        for event in input::button_events(self.state, state) {
+            // With the queue full a change is left out of the state, so it
+            // is reported again after the next edge
+            if self.events.enqueue(event).is_ok() {
+                self.state ^= button_bit(event.button);
+            }
+        }
+    }
+
+    fn sample(&self) -> ButtonMask {
+        // The gamepad buttons pull to ground, the menu button to PB0 (high)
+        let mut mask = 0;
+        for (i, pin) in self.gamepad.iter().enumerate() {
+            if pin.is_low() {
+                mask |= 1 << i;
+            }
+        }
+        if self.menu.is_high() {
+            mask |= button_bit(Button::Menu);
+        }
+        mask
+    }
+}
@@ -0,0 +1,69 @@
+// Piezo buzzer on PA0, driven by TIM2 channel 1 PWM. The beep is a square
+// wave at the tone frequency with the duty cycle setting the volume.
+use crate::audio::{AudioSettings, AudioSink, Sound};
+use core::cell::RefCell;
+use cortex_m::interrupt::Mutex;
+use stm32f4xx_hal::gpio::gpioa;
+use stm32f4xx_hal::pac::TIM2;
+use stm32f4xx_hal::timer::{Channel, Channel1, PwmHz};
+use stm32f4xx_hal::{prelude::*, rcc::Clocks};
+
+type Output = PwmHz<TIM2, Channel1<TIM2>>;
+
+// Shared with the fault screen, which silences it
+static OUTPUT: Mutex<RefCell<Option<Output>>> = Mutex::new(RefCell::new(None));
+
+pub struct Buzzer {
+    playing: Sound,
+    settings: AudioSettings,
+}
+
+impl Buzzer {
+    pub fn new(tim2: TIM2, pin: gpioa::PA0, clocks: &Clocks) -> Buzzer {
+        let settings = AudioSettings::default();
+        let mut pwm = tim2.pwm_hz(Channel1::new(pin), (settings.tone_hz as u32).Hz(), clocks);
+        pwm.disable(Channel::C1);
+        cortex_m::interrupt::free(|cs| OUTPUT.borrow(cs).replace(Some(pwm)));
+        Buzzer {
+            playing: Sound::Silent,
+            settings,
+        }
+    }
+}
+
+impl AudioSink for Buzzer {
+    fn play(&mut self, sound: Sound, settings: &AudioSettings) {
+        if sound == self.playing && *settings == self.settings {
+            return;
+        }
+        self.playing = sound;
+        self.settings = *settings;
+        let volume = settings.volume.min(100) as u32;
+        cortex_m::interrupt::free(|cs| {
+            let mut output = OUTPUT.borrow(cs).borrow_mut();
+            let Some(pwm) = output.as_mut() else {
+                return;
+            };
+            match sound {
+                Sound::Silent => pwm.disable(Channel::C1),
+                Sound::Tone => {
+                    pwm.set_period((settings.tone_hz as u32).Hz());
+                    // A square wave at half duty is as loud as it gets
+                    let duty = pwm.get_max_duty() as u32 * volume / 200;
+                    pwm.set_duty(Channel::C1, duty as u16);
+                    pwm.enable(Channel::C1);
+                }
+            }
+        });
+    }
+}
+
+// Silence the buzzer for good, for the fault screen. The fault may have hit
+// while the output was borrowed, then it is left alone.
+pub fn shutdown() {
+    cortex_m::interrupt::free(|cs| {
+        if let Ok(Some(pwm)) = OUTPUT.borrow(cs).try_borrow_mut().as_deref_mut() {
+            pwm.disable(Channel::C1);
+        }
+    });
+}
@@ -1,13 +1,116 @@
+use crate::logging;
+
 const MEMORY_SIZE: usize = 4096;
 const REGISTER_COUNT: usize = 16;
 const STACK_SIZE: usize = 16;
 pub const SCREEN_WIDTH: usize = 64;
 pub const SCREEN_HEIGHT: usize = 32;
+pub const KEY_COUNT: usize = 16;
 const FONTSET_SIZE: usize = 80;
 const FONTSET_START_ADDRESS: usize = 0x50;
 const PROGRAM_START_ADDRESS: usize = 0x200;
 
-type OpcodeHandler = fn(&mut Chip8, u16);
+// Save state: magic, registers, I, PC, delay and sound timers, return
+// stack, SP, quirks, memory, then the screen packed 8 pixels to a byte
+const STATE_MAGIC: u8 = 0xC8;
+pub const STATE_BYTES: usize = 1
+    + REGISTER_COUNT
+    + 2
+    + 2
+    + 2
+    + STACK_SIZE * 2
+    + 1
+    + 1
+    + MEMORY_SIZE
+    + SCREEN_WIDTH * SCREEN_HEIGHT / 8;
+
+// Anything that can report which of the 16 CHIP-8 keys are held down: a hex
+// keypad, a few buttons through a per-ROM key map, a host keyboard...
+pub trait InputSource {
+    // Set keys[k] to nonzero for every pressed key k, and to 0 otherwise
+    fn read_keys(&mut self, keys: &mut [u8; KEY_COUNT]);
+}
+
+impl<S: InputSource + ?Sized> InputSource for &mut S {
+    fn read_keys(&mut self, keys: &mut [u8; KEY_COUNT]) {
+        (**self).read_keys(keys)
+    }
+}
+
+// Behaviours that differ between CHIP-8 interpreters, each ROM expects the
+// ones it was written against. All off is the CHIP-48/SUPER-CHIP behaviour
+// most games assume.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Quirks {
+    // 8XY6/8XYE shift Vy into Vx instead of shifting Vx in place
+    pub shift_vy: bool,
+    // FX55/FX65 leave I pointing past the last register stored or loaded
+    pub load_store_increment_i: bool,
+    // BXNN jumps to XNN + VX instead of BNNN jumping to NNN + V0
+    pub jump_vx: bool,
+    // 8XY1/8XY2/8XY3 clear VF
+    pub vf_reset: bool,
+}
+
+impl Quirks {
+    // One bit per quirk in the order above, as save states and the debugger
+    // carry them
+    pub fn to_bits(self) -> u8 {
+        self.shift_vy as u8
+            | (self.load_store_increment_i as u8) << 1
+            | (self.jump_vx as u8) << 2
+            | (self.vf_reset as u8) << 3
+    }
+
+    pub fn from_bits(bits: u8) -> Quirks {
+        Quirks {
+            shift_vy: bits & 1 != 0,
+            load_store_increment_i: bits & 2 != 0,
+            jump_vx: bits & 4 != 0,
+            vf_reset: bits & 8 != 0,
+        }
+    }
+}
+
+// Appends fields to a save state
+struct StateWriter<'a> {
+    bytes: &'a mut [u8],
+    at: usize,
+}
+
+impl StateWriter<'_> {
+    fn put(&mut self, field: &[u8]) {
+        self.bytes[self.at..self.at + field.len()].copy_from_slice(field);
+        self.at += field.len();
+    }
+}
+
+type OpcodeHandler = fn(&mut Chip8, u16) -> Result<(), Chip8Error>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum Chip8Error {
+    UnknownOpcode(u16),
+    StackOverflow,
+    StackUnderflow,
+    // The program counter or I points outside of the 4kb memory
+    PcOutOfBounds(u16),
+    MemoryOutOfBounds(u16),
+    ProgramTooLarge(usize),
+}
+
+impl core::fmt::Display for Chip8Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Chip8Error::UnknownOpcode(opcode) => write!(f, "unknown opcode {:04X}", opcode),
+            Chip8Error::StackOverflow => write!(f, "stack overflow"),
+            Chip8Error::StackUnderflow => write!(f, "stack underflow"),
+            Chip8Error::PcOutOfBounds(pc) => write!(f, "PC out of bounds {:04X}", pc),
+            Chip8Error::MemoryOutOfBounds(i) => write!(f, "I out of bounds {:04X}", i),
+            Chip8Error::ProgramTooLarge(len) => write!(f, "program too large ({} bytes)", len),
+        }
+    }
+}
 
 pub struct Chip8 {
     pub memory: [u8; MEMORY_SIZE],       // 4kb memory
@@ -19,7 +122,8 @@ pub struct Chip8 {
     pub sound_timer: u8,
     pub return_stack: [u16; STACK_SIZE], // return_stack with 16 levels
     pub stack_pointer: u8,               // return_stack pointer
-    pub keys: [u8; REGISTER_COUNT],
+    pub keys: [u8; KEY_COUNT],
+    pub quirks: Quirks,
     pub jump_table: [OpcodeHandler; 16],
 }
 
@@ -35,7 +139,8 @@ impl Chip8 {
             sound_timer: 0,
             return_stack: [0; STACK_SIZE],
             stack_pointer: 0,
-            keys: [0; REGISTER_COUNT],
+            keys: [0; KEY_COUNT],
+            quirks: Quirks::default(),
             jump_table: Chip8::create_jump_table(),
         };
         chip8.load_fonts();
@@ -87,42 +192,125 @@ impl Chip8 {
         ]
     }
 
-    pub fn load_program(&mut self, program: &[u8]) {
+    pub fn poll_input<S: InputSource + ?Sized>(&mut self, source: &mut S) {
+        source.read_keys(&mut self.keys);
+    }
+
+    pub fn load_program(&mut self, program: &[u8]) -> Result<(), Chip8Error> {
+        if program.len() > MEMORY_SIZE - PROGRAM_START_ADDRESS {
+            return Err(Chip8Error::ProgramTooLarge(program.len()));
+        }
         for (i, &byte) in program.iter().enumerate() {
             self.memory[0x200 + i] = byte;
         }
+        logging::debug!("loaded {} bytes", program.len());
+        Ok(())
     }
 
-    pub fn emulate_cycle(&mut self) {
-        let opcode = self.fetch_opcode();
+    pub fn save_state(&self) -> [u8; STATE_BYTES] {
+        let mut state = [0; STATE_BYTES];
+        let mut fields = StateWriter {
+            bytes: &mut state,
+            at: 0,
+        };
+        fields.put(&[STATE_MAGIC]);
+        fields.put(&self.registers);
+        fields.put(&self.index_register.to_le_bytes());
+        fields.put(&self.program_counter.to_le_bytes());
+        fields.put(&[self.delay_timer, self.sound_timer]);
+        for address in self.return_stack {
+            fields.put(&address.to_le_bytes());
+        }
+        fields.put(&[self.stack_pointer, self.quirks.to_bits()]);
+        fields.put(&self.memory);
+        for pixels in self.screen.chunks_exact(8) {
+            let packed = pixels
+                .iter()
+                .fold(0u8, |byte, &pixel| (byte << 1) | (pixel & 1));
+            fields.put(&[packed]);
+        }
+        state
+    }
+
+    // None if the bytes are not a save state
+    pub fn from_state(state: &[u8]) -> Option<Chip8> {
+        if state.len() != STATE_BYTES || state[0] != STATE_MAGIC {
+            return None;
+        }
+        let mut fields = state[1..].iter().copied();
+        let mut next = || fields.next().unwrap();
+        let mut chip8 = Chip8::new();
+        chip8.registers = core::array::from_fn(|_| next());
+        chip8.index_register = u16::from_le_bytes([next(), next()]);
+        chip8.program_counter = u16::from_le_bytes([next(), next()]);
+        chip8.delay_timer = next();
+        chip8.sound_timer = next();
+        chip8.return_stack = core::array::from_fn(|_| u16::from_le_bytes([next(), next()]));
+        chip8.stack_pointer = next();
+        chip8.quirks = Quirks::from_bits(next());
+        chip8.memory = core::array::from_fn(|_| next());
+        for pixels in chip8.screen.chunks_exact_mut(8) {
+            let packed = next();
+            for (bit, pixel) in pixels.iter_mut().enumerate() {
+                *pixel = (packed >> (7 - bit)) & 1;
+            }
+        }
+        if chip8.stack_pointer as usize > STACK_SIZE {
+            return None;
+        }
+        Some(chip8)
+    }
+
+    // On error the program counter is left on the faulting instruction
+    pub fn emulate_cycle(&mut self) -> Result<(), Chip8Error> {
+        let pc = self.program_counter;
+        let opcode = self.fetch_opcode()?;
         self.program_counter += 2;
         let index = (opcode & 0xF000) >> 12;
         let handler = self.jump_table[index as usize];
-        handler(self, opcode);
-
-        if self.delay_timer > 0 {
-            self.delay_timer -= 1;
-        }
-        if self.sound_timer > 0 {
-            self.sound_timer -= 1;
+        if let Err(e) = handler(self, opcode) {
+            if let Chip8Error::UnknownOpcode(opcode) = e {
+                logging::warn!("unknown opcode {:04x} at {:04x}", opcode, pc);
+            }
+            self.program_counter = pc;
+            return Err(e);
         }
+        Ok(())
     }
 
-    pub fn fetch_opcode(&self) -> u16 {
+    // Count the delay and sound timers down, once per 60 Hz frame
+    pub fn tick_timers(&mut self) {
+        self.delay_timer = self.delay_timer.saturating_sub(1);
+        self.sound_timer = self.sound_timer.saturating_sub(1);
+    }
+
+    pub fn fetch_opcode(&self) -> Result<u16, Chip8Error> {
+        if self.program_counter as usize + 1 >= MEMORY_SIZE {
+            return Err(Chip8Error::PcOutOfBounds(self.program_counter));
+        }
         let high_byte = self.memory[self.program_counter as usize] as u16;
         let low_byte = self.memory[(self.program_counter + 1) as usize] as u16;
-        (high_byte << 8) | low_byte
+        Ok((high_byte << 8) | low_byte)
     }
 
-    fn op_0xxx(&mut self, opcode: u16) {
+    // Memory range [I, I + len) used by FX33, FX55, FX65 and DXYN
+    fn check_index_range(&self, len: usize) -> Result<usize, Chip8Error> {
+        let start = self.index_register as usize;
+        if start + len > MEMORY_SIZE {
+            return Err(Chip8Error::MemoryOutOfBounds(self.index_register));
+        }
+        Ok(start)
+    }
+
+    fn op_0xxx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         match opcode & 0x00FF {
             0x00E0 => self.cls(),
             0x00EE => self.ret(),
-            _ => unimplemented!("opcode {:04x} not implemented", opcode),
+            _ => Err(Chip8Error::UnknownOpcode(opcode)),
         }
     }
 
-    fn op_8xxx(&mut self, opcode: u16) {
+    fn op_8xxx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         match opcode & 0x000F {
             0x0000 => self.ld_vx_vy(opcode),
             0x0001 => self.or_vx_vy(opcode),
@@ -133,19 +321,19 @@ impl Chip8 {
             0x0006 => self.shr_vx(opcode),
             0x0007 => self.subn_vx_vy(opcode),
             0x000E => self.shl_vx(opcode),
-            _ => unimplemented!("Opcode {:04x} not implemented", opcode),
+            _ => Err(Chip8Error::UnknownOpcode(opcode)),
         }
     }
 
-    fn op_exxx(&mut self, opcode: u16) {
+    fn op_exxx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         match opcode & 0x00FF {
             0x009E => self.skp_vx(opcode),
             0x00A1 => self.sknp_vx(opcode),
-            _ => unimplemented!("Opcode {:04x} not implemented", opcode),
+            _ => Err(Chip8Error::UnknownOpcode(opcode)),
         }
     }
 
-    fn op_fxxx(&mut self, opcode: u16) {
+    fn op_fxxx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         match opcode & 0x00FF {
             0x0007 => self.ld_vx_dt(opcode),
             0x000A => self.ld_vx_k(opcode),
@@ -156,149 +344,187 @@ impl Chip8 {
             0x0033 => self.ld_b_vx(opcode),
             0x0055 => self.ld_i_vx(opcode),
             0x0065 => self.ld_vx_i(opcode),
-            _ => unimplemented!("Opcode {:04x} not implemented", opcode),
+            _ => Err(Chip8Error::UnknownOpcode(opcode)),
         }
     }
 
     // instruction implementation===============================================
     // CLS - 00E0
     // Instruction: clear the display
-    fn cls(&mut self) {
-        self.screen = [0; SCREEN_WIDTH * SCREEN_HEIGHT]
+    fn cls(&mut self) -> Result<(), Chip8Error> {
+        self.screen = [0; SCREEN_WIDTH * SCREEN_HEIGHT];
+        Ok(())
     }
 
     // RET - 00EE
     // Instruction: return from a subroutine
-    fn ret(&mut self) {
+    fn ret(&mut self) -> Result<(), Chip8Error> {
+        if self.stack_pointer == 0 {
+            return Err(Chip8Error::StackUnderflow);
+        }
         self.stack_pointer -= 1;
         self.program_counter = self.return_stack[self.stack_pointer as usize];
+        logging::trace!(
+            "return to {:03x}, stack depth {}",
+            self.program_counter,
+            self.stack_pointer
+        );
+        Ok(())
     }
 
     // JP - 1NNN
     // Instruction: jump to address NNN
-    fn jp(&mut self, opcode: u16) {
+    fn jp(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let address = opcode & 0x0FFF;
         self.program_counter = address;
+        Ok(())
     }
 
     // CALL - 2NNN
     // Instruction: call subroutine at NNN
-    fn call(&mut self, opcode: u16) {
+    fn call(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let address = opcode & 0x0FFF;
+        if self.stack_pointer as usize >= STACK_SIZE {
+            return Err(Chip8Error::StackOverflow);
+        }
         self.return_stack[self.stack_pointer as usize] = self.program_counter;
         self.stack_pointer += 1;
         self.program_counter = address;
+        logging::trace!("call {:03x}, stack depth {}", address, self.stack_pointer);
+        Ok(())
     }
 
     // SE Vx, byte - 3XNN
     // Instruction: skip next instruction if Vx equals NN
-    fn se_vx_byte(&mut self, opcode: u16) {
+    fn se_vx_byte(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let byte = (opcode & 0x00FF) as u8;
         if self.registers[x] == byte {
             self.program_counter += 2;
         }
+        Ok(())
     }
 
     // SNE Vx, byte - 4XNN
     // Instruction: skip next instruction if Vx doesn't equal NN
-    fn sne_vx_byte(&mut self, opcode: u16) {
+    fn sne_vx_byte(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let byte = (opcode & 0x00FF) as u8;
         if self.registers[x] != byte {
             self.program_counter += 2;
         }
+        Ok(())
     }
 
     // SE Vx, Vy - 5XY0
     // Instruction: skip next instruction if Vx equals Vy
-    fn se_vx_vy(&mut self, opcode: u16) {
+    fn se_vx_vy(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let y = ((opcode & 0x00F0) >> 4) as usize;
         if self.registers[x] == self.registers[y] {
             self.program_counter += 2;
         }
+        Ok(())
     }
 
     // LD Vx, byte - 6XNN
     // Instruction: set Vx to NN
-    fn ld_vx_byte(&mut self, opcode: u16) {
+    fn ld_vx_byte(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let byte = (opcode & 0x00FF) as u8;
         self.registers[x] = byte;
+        Ok(())
     }
 
     // ADD Vx, byte - 7XNN
     // Instruction: add NN to Vx
-    fn add_vx_byte(&mut self, opcode: u16) {
+    fn add_vx_byte(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let byte = (opcode & 0x00FF) as u8;
         self.registers[x] = self.registers[x].wrapping_add(byte);
+        Ok(())
     }
 
     // LD Vx, Vy - 8XY0
     // Instruction: set Vx to the value of Vy
-    fn ld_vx_vy(&mut self, opcode: u16) {
+    fn ld_vx_vy(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let y = ((opcode & 0x00F0) >> 4) as usize;
         self.registers[x] = self.registers[y];
+        Ok(())
     }
 
     // OR Vx, Vy - 8XY1
     // Instruction: set Vx to Vx OR Vy
-    fn or_vx_vy(&mut self, opcode: u16) {
+    fn or_vx_vy(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let y = ((opcode & 0x00F0) >> 4) as usize;
         self.registers[x] |= self.registers[y];
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
+        Ok(())
     }
 
     // AND Vx, Vy - 8XY2
     // Instruction: set Vx to Vx AND Vy
-    fn and_vx_vy(&mut self, opcode: u16) {
+    fn and_vx_vy(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let y = ((opcode & 0x00F0) >> 4) as usize;
         self.registers[x] &= self.registers[y];
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
+        Ok(())
     }
 
     // XOR Vx, Vy - 8XY3
     // Instruction: set Vx to Vx XOR Vy
-    fn xor_vx_vy(&mut self, opcode: u16) {
+    fn xor_vx_vy(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let y = ((opcode & 0x00F0) >> 4) as usize;
         self.registers[x] ^= self.registers[y];
+        if self.quirks.vf_reset {
+            self.registers[0xF] = 0;
+        }
+        Ok(())
     }
 
     // ADD Vx, Vy - 8XY4
     // Instruction: Add Vy to Vx, set VF = carry
-    fn add_vx_vy(&mut self, opcode: u16) {
+    fn add_vx_vy(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let y = ((opcode & 0x00F0) >> 4) as usize;
         let (result, carry) = self.registers[x].overflowing_add(self.registers[y]);
         self.registers[x] = result;
         self.registers[0xF] = if carry { 1 } else { 0 };
+        Ok(())
     }
 
     // SUB Vx, Vy - 8XY5
     // Instruction: subtract Vy from Vx, set VF = NOT borrow
-    fn sub_vx_vy(&mut self, opcode: u16) {
+    fn sub_vx_vy(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let y = ((opcode & 0x00F0) >> 4) as usize;
         let (result, borrow) = self.registers[x].overflowing_sub(self.registers[y]);
         self.registers[x] = result;
         self.registers[0xF] = if borrow { 0 } else { 1 };
+        Ok(())
     }
 
     // SHR Vx - 8XY6
     // Instruction: set Vx = Vx SHR 1
-    fn shr_vx(&mut self, opcode: u16) {
+    fn shr_vx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
-        self.registers[0xF] = self.registers[x] & 0x1;
-        self.registers[x] >>= 1;
+        let value = self.shift_source(opcode);
+        self.registers[x] = value >> 1;
+        self.registers[0xF] = value & 0x1;
+        Ok(())
     }
 
     // SUBN Vx, Vy - 8XY7
     // Instruction: set Vx = Vy - Vx, set VF = NOT borrow
-    fn subn_vx_vy(&mut self, opcode: u16) {
+    fn subn_vx_vy(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let y = ((opcode & 0x00F0) >> 4) as usize;
         self.registers[0xF] = if self.registers[y] > self.registers[x] {
@@ -307,54 +533,77 @@ impl Chip8 {
             0
         };
         self.registers[x] = self.registers[y].wrapping_sub(self.registers[x]);
+        Ok(())
     }
 
     // SHL Vx - 8XYE
     // Instruction: set Vx = Vx SHL 1
-    fn shl_vx(&mut self, opcode: u16) {
+    fn shl_vx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
-        self.registers[0xF] = (self.registers[x] & 0x80) >> 7;
-        self.registers[x] <<= 1;
+        let value = self.shift_source(opcode);
+        self.registers[x] = value << 1;
+        self.registers[0xF] = (value & 0x80) >> 7;
+        Ok(())
+    }
+
+    // The register 8XY6/8XYE shift, Vy with the shift_vy quirk and Vx without
+    fn shift_source(&self, opcode: u16) -> u8 {
+        let x = ((opcode & 0x0F00) >> 8) as usize;
+        let y = ((opcode & 0x00F0) >> 4) as usize;
+        if self.quirks.shift_vy {
+            self.registers[y]
+        } else {
+            self.registers[x]
+        }
     }
 
     // SNE Vx, Vy - 9XY0
     // Instruction: skip the next instruction if Vx != Vy
-    fn sne_vx_vy(&mut self, opcode: u16) {
+    fn sne_vx_vy(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let y = ((opcode & 0x00F0) >> 4) as usize;
         if self.registers[x] != self.registers[y] {
             self.program_counter += 2;
         }
+        Ok(())
     }
 
     // LD I, addr - ANNN
     // Instruction: set I = NNN
-    fn ld_i_addr(&mut self, opcode: u16) {
+    fn ld_i_addr(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let address = opcode & 0x0FFF;
         self.index_register = address;
+        Ok(())
     }
 
     // JP V0, addr - BNNN
-    // Instruction: jump to location nnn + V0
-    fn jp_v0_addr(&mut self, opcode: u16) {
+    // Instruction: jump to location nnn + V0, or XNN + VX with the jump_vx quirk
+    fn jp_v0_addr(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let address = opcode & 0x0FFF;
-        self.program_counter = (self.registers[0] as u16) + address;
+        let offset = if self.quirks.jump_vx {
+            self.registers[((opcode & 0x0F00) >> 8) as usize]
+        } else {
+            self.registers[0]
+        };
+        self.program_counter = (offset as u16) + address;
+        Ok(())
     }
 
     // RND Vx, byte
     // Instruction: set Vx = random byte and passed in byte
     // random byte is just 0x01 for now
-    fn rnd_vx_byte(&mut self, opcode: u16) {
+    fn rnd_vx_byte(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let byte = (opcode & 0x00FF) as u8;
         let random_byte: u8 = 0x01; // Generate a random byte(just 0x01 for now ok)
         self.registers[x] = random_byte & byte;
+        Ok(())
     }
 
     // DRW Vx, Vy, nibble
     // Instruction: display n-byte sprite starting at memory location I at (Vx, Vy), set VF =
     // collision
-    fn drw_vx_vy_nibble(&mut self, opcode: u16) {
+    fn drw_vx_vy_nibble(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let y = ((opcode & 0x00F0) >> 4) as usize;
         let height = (opcode & 0x000F) as usize;
@@ -362,10 +611,12 @@ impl Chip8 {
         let vx = self.registers[x] as usize;
         let vy = self.registers[y] as usize;
 
+        let start = self.check_index_range(height)?;
+
         self.registers[0xF] = 0;
 
         for row in 0..height {
-            let sprite_byte = self.memory[self.index_register as usize + row];
+            let sprite_byte = self.memory[start + row];
             for col in 0..8 {
                 let sprite_pixel = sprite_byte & (0x80 >> col);
                 let screen_index = (vy + row) * SCREEN_WIDTH + (vx + col);
@@ -381,104 +632,127 @@ impl Chip8 {
                 }
             }
         }
+        Ok(())
     }
 
     // SKP Vx - EX9E
     // Instruction: skip the next instruction if the key with the value of Vx is pressed
-    fn skp_vx(&mut self, opcode: u16) {
+    fn skp_vx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
-        let key = self.registers[x];
+        // Only the low nibble names a key, like on the COSMAC VIP
+        let key = self.registers[x] & 0xF;
         if self.keys[key as usize] != 0 {
             self.program_counter += 2;
         }
+        Ok(())
     }
 
     // SKNP Vx - EXA1
     // Instruction: skip the next instruction if the key with the value of Vx is not pressed
-    fn sknp_vx(&mut self, opcode: u16) {
+    fn sknp_vx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
-        let key = self.registers[x];
+        // Only the low nibble names a key, like on the COSMAC VIP
+        let key = self.registers[x] & 0xF;
         if self.keys[key as usize] == 0 {
             self.program_counter += 2;
         }
+        Ok(())
     }
 
     // LD Vx, DT - FX07
     // Instruction: set Vx = delay timer value
-    fn ld_vx_dt(&mut self, opcode: u16) {
+    fn ld_vx_dt(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         self.registers[x] = self.delay_timer;
+        Ok(())
     }
 
     // LD Vx, K - FX0A
     // Instruction: wait for a key press, store the value of the key in Vx
-    fn ld_vx_k(&mut self, opcode: u16) {
+    fn ld_vx_k(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         for i in 0..self.keys.len() {
             if self.keys[i] != 0 {
                 self.registers[x] = i as u8;
-                return;
+                return Ok(());
             }
         }
         // If no key is pressed, decrement PC to repeat the instruction
         self.program_counter -= 2;
+        Ok(())
     }
 
     // LD DT, Vx - FX15
     // Instruction: set delay timer = Vx
-    fn ld_dt_vx(&mut self, opcode: u16) {
+    fn ld_dt_vx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         self.delay_timer = self.registers[x];
+        Ok(())
     }
 
     // LD ST, Vx - FX18
     // Instruction: set sound timer = Vx
-    fn ld_st_vx(&mut self, opcode: u16) {
+    fn ld_st_vx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         self.sound_timer = self.registers[x];
+        Ok(())
     }
 
     // ADD I, Vx - FX1E
     // Instruction: Set I = I + Vx
-    fn add_i_vx(&mut self, opcode: u16) {
+    fn add_i_vx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         self.index_register = self.index_register.wrapping_add(self.registers[x] as u16);
+        Ok(())
     }
 
     // LD F, Vx - FX29
     // Instruction: set I = location of sprite for digit Vx
-    fn ld_f_vx(&mut self, opcode: u16) {
+    fn ld_f_vx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let digit = self.registers[x] as u16;
         self.index_register = FONTSET_START_ADDRESS as u16 + digit * 5;
+        Ok(())
     }
 
     // LD B, Vx
     // Instruction: store BCD representation of Vx in memory locations I, I+1, and I+2
-    fn ld_b_vx(&mut self, opcode: u16) {
+    fn ld_b_vx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
         let value = self.registers[x];
+        let start = self.check_index_range(3)?;
 
-        self.memory[self.index_register as usize] = value / 100;
-        self.memory[(self.index_register + 1) as usize] = (value / 10) % 10;
-        self.memory[(self.index_register + 2) as usize] = value % 10;
+        self.memory[start] = value / 100;
+        self.memory[start + 1] = (value / 10) % 10;
+        self.memory[start + 2] = value % 10;
+        Ok(())
     }
 
     // LD [I], Vx
     // Instruction: store registers V0 through Vx in memory starting at location I
-    fn ld_i_vx(&mut self, opcode: u16) {
+    fn ld_i_vx(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
+        let start = self.check_index_range(x + 1)?;
         for i in 0..=x {
-            self.memory[self.index_register as usize + i] = self.registers[i];
+            self.memory[start + i] = self.registers[i];
+        }
+        if self.quirks.load_store_increment_i {
+            self.index_register += x as u16 + 1;
         }
+        Ok(())
     }
 
     // LD Vx, I
     // Instruction: read registers V0 through Vx from memory starting at location I
-    fn ld_vx_i(&mut self, opcode: u16) {
+    fn ld_vx_i(&mut self, opcode: u16) -> Result<(), Chip8Error> {
         let x = ((opcode & 0x0F00) >> 8) as usize;
+        let start = self.check_index_range(x + 1)?;
         for i in 0..=x {
-            self.registers[i] = self.memory[self.index_register as usize + i];
+            self.registers[i] = self.memory[start + i];
+        }
+        if self.quirks.load_store_increment_i {
+            self.index_register += x as u16 + 1;
         }
+        Ok(())
     }
 }
@@ -0,0 +1,18 @@
+// CRC-16/CCITT-FALSE, the check on ROM upload frames and on records in flash
+// storage. Start from CRC16_INIT and feed the bytes in as many pieces as
+// convenient.
+pub const CRC16_INIT: u16 = 0xFFFF;
+
+pub fn crc16_update(mut crc: u16, bytes: &[u8]) -> u16 {
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,409 @@
+// Remote debugger for the running game, spoken over the upload UART next to
+// ROM uploads. Modelled on the GDB remote protocol, the host sends packets
+//
+//   '$', payload, '#', checksum
+//
+// where the checksum is the sum of the payload bytes modulo 256 as two hex
+// digits. Every packet is answered with one packet; a packet with a bad
+// checksum is dropped and the host sends it again once it times out.
+// Numbers are hex without a prefix.
+//
+//   ?                      S<reason> while halted, R while running
+//   c                      resume
+//   s                      run one instruction and halt again
+//   g                      registers, see REGISTER_BYTES
+//   q                      the running ROM's quirks, see Quirks::to_bits
+//   G<registers>           write all registers, OK
+//   m<addr>,<len>          read memory
+//   M<addr>,<len>:<bytes>  write memory, OK
+//   Z0,<addr>              set a breakpoint, OK
+//   z0,<addr>              clear a breakpoint, OK
+//
+// `c` and `s` are answered once the game halts again, with S<reason>: 05 for
+// a breakpoint or a finished step, 02 when the host paused it by sending a
+// 0x03 byte outside a packet. A pause while halted is ignored. The other
+// commands work while the game runs too. Errors are E<code> with a
+// DebugError code, unknown commands get an empty packet like in GDB.
+// tools/chipdbg is the host end.
+use crate::chip8::Chip8;
+use heapless::Vec;
+
+pub const PACKET_START: u8 = b'$';
+const PACKET_END: u8 = b'#';
+pub const PAUSE: u8 = 0x03;
+pub const MAX_PAYLOAD: usize = 300;
+// Largest memory read or write, so either fits in a packet as hex
+pub const MAX_TRANSFER: usize = 128;
+pub const MAX_BREAKPOINTS: usize = 8;
+// V0-VF, I, PC, SP, DT, ST and the 16 stack entries. I, PC and the stack
+// are big endian, like words in Chip8 memory
+pub const REGISTER_BYTES: usize = 16 + 2 + 2 + 3 + 2 * 16;
+// Room for a reply and a stop packet
+const OUTPUT_BYTES: usize = 2 * (MAX_PAYLOAD + 4);
+
+pub const STOP_PAUSED: u8 = 0x02;
+pub const STOP_TRAP: u8 = 0x05;
+
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum DebugError {
+    Malformed = 1,
+    OutOfRange = 2,
+    TooManyBreakpoints = 3,
+}
+
+impl DebugError {
+    // The host side, for tools/chipdbg
+    #[allow(dead_code)]
+    pub fn from_code(code: u8) -> Option<DebugError> {
+        match code {
+            1 => Some(DebugError::Malformed),
+            2 => Some(DebugError::OutOfRange),
+            3 => Some(DebugError::TooManyBreakpoints),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for DebugError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            DebugError::Malformed => write!(f, "malformed command"),
+            DebugError::OutOfRange => write!(f, "address or value out of range"),
+            DebugError::TooManyBreakpoints => {
+                write!(f, "no more than {} breakpoints", MAX_BREAKPOINTS)
+            }
+        }
+    }
+}
+
+// The bytes around a payload: the start before it and the end and checksum
+// after
+pub fn frame(payload: &[u8]) -> (u8, [u8; 3]) {
+    let sum = payload.iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+    (
+        PACKET_START,
+        [PACKET_END, HEX[sum as usize >> 4], HEX[sum as usize & 0xF]],
+    )
+}
+
+fn hex_value(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
+
+// A number of up to 4 hex digits
+pub fn parse_hex(digits: &[u8]) -> Option<u16> {
+    if digits.is_empty() || digits.len() > 4 {
+        return None;
+    }
+    digits
+        .iter()
+        .try_fold(0u16, |value, &d| Some(value << 4 | hex_value(d)? as u16))
+}
+
+// Two hex digits per byte, `digits` has to fill `out` exactly
+pub fn decode_hex(digits: &[u8], out: &mut [u8]) -> Option<()> {
+    if digits.len() != out.len() * 2 {
+        return None;
+    }
+    for (byte, pair) in out.iter_mut().zip(digits.chunks(2)) {
+        *byte = hex_value(pair[0])? << 4 | hex_value(pair[1])?;
+    }
+    Some(())
+}
+
+fn push_hex<const N: usize>(out: &mut Vec<u8, N>, bytes: &[u8]) {
+    for &byte in bytes {
+        let _ = out.push(HEX[byte as usize >> 4]);
+        let _ = out.push(HEX[byte as usize & 0xF]);
+    }
+}
+
+pub fn registers(chip8: &Chip8) -> [u8; REGISTER_BYTES] {
+    let mut bytes = [0; REGISTER_BYTES];
+    bytes[..16].copy_from_slice(&chip8.registers);
+    bytes[16..18].copy_from_slice(&chip8.index_register.to_be_bytes());
+    bytes[18..20].copy_from_slice(&chip8.program_counter.to_be_bytes());
+    bytes[20] = chip8.stack_pointer;
+    bytes[21] = chip8.delay_timer;
+    bytes[22] = chip8.sound_timer;
+    for (entry, word) in bytes[23..].chunks_mut(2).zip(chip8.return_stack) {
+        entry.copy_from_slice(&word.to_be_bytes());
+    }
+    bytes
+}
+
+pub fn set_registers(chip8: &mut Chip8, bytes: &[u8; REGISTER_BYTES]) -> Result<(), DebugError> {
+    if bytes[20] as usize > chip8.return_stack.len() {
+        return Err(DebugError::OutOfRange);
+    }
+    chip8.registers.copy_from_slice(&bytes[..16]);
+    chip8.index_register = u16::from_be_bytes([bytes[16], bytes[17]]);
+    chip8.program_counter = u16::from_be_bytes([bytes[18], bytes[19]]);
+    chip8.stack_pointer = bytes[20];
+    chip8.delay_timer = bytes[21];
+    chip8.sound_timer = bytes[22];
+    for (word, entry) in chip8.return_stack.iter_mut().zip(bytes[23..].chunks(2)) {
+        *word = u16::from_be_bytes([entry[0], entry[1]]);
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Idle,
+    Payload,
+    // Checksum digits received so far
+    Checksum(usize),
+}
+
+// Picks packets out of a byte stream, for both ends
+pub struct PacketReceiver {
+    state: State,
+    payload: Vec<u8, MAX_PAYLOAD>,
+    checksum: [u8; 2],
+}
+
+impl PacketReceiver {
+    pub const fn new() -> PacketReceiver {
+        PacketReceiver {
+            state: State::Idle,
+            payload: Vec::new(),
+            checksum: [0; 2],
+        }
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.state == State::Idle
+    }
+
+    // Feed one received byte. Returns the payload once a whole packet with a
+    // good checksum is in, packets that are too long are dropped
+    pub fn feed(&mut self, byte: u8) -> Option<&[u8]> {
+        match self.state {
+            // A start in the middle of a packet starts over, the sender gave
+            // up on the one before
+            _ if byte == PACKET_START => {
+                self.state = State::Payload;
+                self.payload.clear();
+            }
+            State::Idle => {}
+            State::Payload if byte == PACKET_END => self.state = State::Checksum(0),
+            State::Payload => {
+                if self.payload.push(byte).is_err() {
+                    self.state = State::Idle;
+                }
+            }
+            State::Checksum(count) => {
+                self.checksum[count] = byte;
+                if count == 0 {
+                    self.state = State::Checksum(1);
+                    return None;
+                }
+                self.state = State::Idle;
+                let (_, end) = frame(&self.payload);
+                if self.checksum.eq_ignore_ascii_case(&end[1..]) {
+                    return Some(&self.payload);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum RunState {
+    Running,
+    // One more instruction, then halt
+    Stepping,
+    Halted(u8),
+}
+
+// The board's end. Bytes from the host go in through `feed`, the game loop
+// asks `may_run` before each instruction and calls `ran` after it, and
+// whatever is to be sent back collects in `output`.
+pub struct DebugServer {
+    packets: PacketReceiver,
+    state: RunState,
+    breakpoints: Vec<u16, MAX_BREAKPOINTS>,
+    // The game was resumed on this PC, a breakpoint there doesn't stop it
+    // again straight away
+    resumed_at: Option<u16>,
+    output: Vec<u8, OUTPUT_BYTES>,
+}
+
+impl DebugServer {
+    pub const fn new() -> DebugServer {
+        DebugServer {
+            packets: PacketReceiver::new(),
+            state: RunState::Running,
+            breakpoints: Vec::new(),
+            resumed_at: None,
+            output: Vec::new(),
+        }
+    }
+
+    // The game's timers and keys stand still while halted
+    pub fn is_halted(&self) -> bool {
+        self.state != RunState::Running
+    }
+
+    pub fn feed(&mut self, byte: u8, chip8: &mut Chip8) {
+        if byte == PAUSE && self.packets.is_idle() {
+            if !matches!(self.state, RunState::Halted(_)) {
+                self.stop(STOP_PAUSED);
+            }
+            return;
+        }
+        let Some(payload) = self.packets.feed(byte) else {
+            return;
+        };
+        // Copied out so the command can change the rest of the server
+        let payload: Vec<u8, MAX_PAYLOAD> = Vec::from_slice(payload).unwrap();
+        let mut reply: Vec<u8, MAX_PAYLOAD> = Vec::new();
+        match self.command(&payload, chip8, &mut reply) {
+            Ok(true) => self.send(&reply),
+            Ok(false) => {}
+            Err(e) => {
+                reply.clear();
+                let _ = reply.push(b'E');
+                push_hex(&mut reply, &[e as u8]);
+                self.send(&reply);
+            }
+        }
+    }
+
+    // Returns false for commands that are answered later
+    fn command(
+        &mut self,
+        payload: &[u8],
+        chip8: &mut Chip8,
+        reply: &mut Vec<u8, MAX_PAYLOAD>,
+    ) -> Result<bool, DebugError> {
+        let (&command, args) = payload.split_first().ok_or(DebugError::Malformed)?;
+        match command {
+            b'?' => match self.state {
+                RunState::Halted(reason) => {
+                    let _ = reply.push(b'S');
+                    push_hex(reply, &[reason]);
+                }
+                _ => {
+                    let _ = reply.push(b'R');
+                }
+            },
+            b'c' | b's' => {
+                self.state = match command {
+                    b'c' => RunState::Running,
+                    _ => RunState::Stepping,
+                };
+                self.resumed_at = Some(chip8.program_counter);
+                return Ok(false);
+            }
+            b'g' => push_hex(reply, &registers(chip8)),
+            b'G' => {
+                let mut bytes = [0; REGISTER_BYTES];
+                decode_hex(args, &mut bytes).ok_or(DebugError::Malformed)?;
+                set_registers(chip8, &bytes)?;
+                let _ = reply.extend_from_slice(b"OK");
+            }
+            b'q' => push_hex(reply, &[chip8.quirks.to_bits()]),
+            b'm' => {
+                let (address, len) = parse_range(args, chip8.memory.len())?;
+                push_hex(reply, &chip8.memory[address..address + len]);
+            }
+            b'M' => {
+                let colon = args.iter().position(|&b| b == b':');
+                let colon = colon.ok_or(DebugError::Malformed)?;
+                let (address, len) = parse_range(&args[..colon], chip8.memory.len())?;
+                let memory = &mut chip8.memory[address..address + len];
+                decode_hex(&args[colon + 1..], memory).ok_or(DebugError::Malformed)?;
+                let _ = reply.extend_from_slice(b"OK");
+            }
+            b'Z' | b'z' => {
+                let address = args.strip_prefix(b"0,").and_then(parse_hex);
+                let address = address.ok_or(DebugError::Malformed)?;
+                if command == b'z' {
+                    self.breakpoints.retain(|&b| b != address);
+                } else if !self.breakpoints.contains(&address) {
+                    self.breakpoints
+                        .push(address)
+                        .map_err(|_| DebugError::TooManyBreakpoints)?;
+                }
+                let _ = reply.extend_from_slice(b"OK");
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    // Asked before each instruction, false when it must not run: the game is
+    // halted or has just hit a breakpoint at `pc`
+    pub fn may_run(&mut self, pc: u16) -> bool {
+        match self.state {
+            RunState::Halted(_) => false,
+            RunState::Stepping => true,
+            RunState::Running => {
+                if self.resumed_at != Some(pc) && self.breakpoints.contains(&pc) {
+                    self.stop(STOP_TRAP);
+                    return false;
+                }
+                true
+            }
+        }
+    }
+
+    // After each instruction that `may_run` let through
+    pub fn ran(&mut self) {
+        self.resumed_at = None;
+        if self.state == RunState::Stepping {
+            self.stop(STOP_TRAP);
+        }
+    }
+
+    // Bytes for the host, to be sent and cleared by the caller
+    pub fn output(&self) -> &[u8] {
+        &self.output
+    }
+
+    pub fn clear_output(&mut self) {
+        self.output.clear();
+    }
+
+    fn stop(&mut self, reason: u8) {
+        self.state = RunState::Halted(reason);
+        let mut payload: Vec<u8, 3> = Vec::new();
+        let _ = payload.push(b'S');
+        push_hex(&mut payload, &[reason]);
+        self.send(&payload);
+    }
+
+    // A packet that doesn't fit is dropped, the host times out and asks again
+    fn send(&mut self, payload: &[u8]) {
+        if self.output.capacity() - self.output.len() < payload.len() + 4 {
+            return;
+        }
+        let (start, end) = frame(payload);
+        let _ = self.output.push(start);
+        let _ = self.output.extend_from_slice(payload);
+        let _ = self.output.extend_from_slice(&end);
+    }
+}
+
+// `<addr>,<len>` of a memory read or write
+fn parse_range(args: &[u8], memory_len: usize) -> Result<(usize, usize), DebugError> {
+    let comma = args.iter().position(|&b| b == b',');
+    let comma = comma.ok_or(DebugError::Malformed)?;
+    let address = parse_hex(&args[..comma]).ok_or(DebugError::Malformed)? as usize;
+    let len = parse_hex(&args[comma + 1..]).ok_or(DebugError::Malformed)? as usize;
+    if len > MAX_TRANSFER || address + len > memory_len {
+        return Err(DebugError::OutOfRange);
+    }
+    Ok((address, len))
+}
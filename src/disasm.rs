@@ -0,0 +1,60 @@
+// CHIP-8 mnemonics, in the style of the instruction comments in chip8.rs.
+// Shared with the host tools, which show them in traces. Opcodes the
+// emulator doesn't run come out as a data word. The shifts and BNNN read
+// different registers depending on the quirks, so an opcode is shown the way
+// the emulator would run it with the given ones.
+use crate::chip8::Quirks;
+use core::fmt;
+
+pub struct Disassembly(pub u16, pub Quirks);
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Disassembly(opcode, quirks) = *self;
+        let x = (opcode >> 8) & 0xF;
+        let y = (opcode >> 4) & 0xF;
+        let n = opcode & 0xF;
+        let nn = opcode & 0xFF;
+        let nnn = opcode & 0xFFF;
+        match (opcode >> 12, n, nn) {
+            (0x0, _, _) if opcode == 0x00E0 => write!(f, "CLS"),
+            (0x0, _, _) if opcode == 0x00EE => write!(f, "RET"),
+            (0x1, _, _) => write!(f, "JP {:03X}", nnn),
+            (0x2, _, _) => write!(f, "CALL {:03X}", nnn),
+            (0x3, _, _) => write!(f, "SE V{:X}, {:02X}", x, nn),
+            (0x4, _, _) => write!(f, "SNE V{:X}, {:02X}", x, nn),
+            (0x5, _, _) => write!(f, "SE V{:X}, V{:X}", x, y),
+            (0x6, _, _) => write!(f, "LD V{:X}, {:02X}", x, nn),
+            (0x7, _, _) => write!(f, "ADD V{:X}, {:02X}", x, nn),
+            (0x8, 0x0, _) => write!(f, "LD V{:X}, V{:X}", x, y),
+            (0x8, 0x1, _) => write!(f, "OR V{:X}, V{:X}", x, y),
+            (0x8, 0x2, _) => write!(f, "AND V{:X}, V{:X}", x, y),
+            (0x8, 0x3, _) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            (0x8, 0x4, _) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            (0x8, 0x5, _) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            (0x8, 0x6, _) if quirks.shift_vy => write!(f, "SHR V{:X}, V{:X}", x, y),
+            (0x8, 0x6, _) => write!(f, "SHR V{:X}", x),
+            (0x8, 0x7, _) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            (0x8, 0xE, _) if quirks.shift_vy => write!(f, "SHL V{:X}, V{:X}", x, y),
+            (0x8, 0xE, _) => write!(f, "SHL V{:X}", x),
+            (0x9, _, _) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            (0xA, _, _) => write!(f, "LD I, {:03X}", nnn),
+            (0xB, _, _) if quirks.jump_vx => write!(f, "JP V{:X}, {:03X}", x, nnn),
+            (0xB, _, _) => write!(f, "JP V0, {:03X}", nnn),
+            (0xC, _, _) => write!(f, "RND V{:X}, {:02X}", x, nn),
+            (0xD, _, _) => write!(f, "DRW V{:X}, V{:X}, {:X}", x, y, n),
+            (0xE, _, 0x9E) => write!(f, "SKP V{:X}", x),
+            (0xE, _, 0xA1) => write!(f, "SKNP V{:X}", x),
+            (0xF, _, 0x07) => write!(f, "LD V{:X}, DT", x),
+            (0xF, _, 0x0A) => write!(f, "LD V{:X}, K", x),
+            (0xF, _, 0x15) => write!(f, "LD DT, V{:X}", x),
+            (0xF, _, 0x18) => write!(f, "LD ST, V{:X}", x),
+            (0xF, _, 0x1E) => write!(f, "ADD I, V{:X}", x),
+            (0xF, _, 0x29) => write!(f, "LD F, V{:X}", x),
+            (0xF, _, 0x33) => write!(f, "LD B, V{:X}", x),
+            (0xF, _, 0x55) => write!(f, "LD [I], V{:X}", x),
+            (0xF, _, 0x65) => write!(f, "LD V{:X}, [I]", x),
+            _ => write!(f, "DW {:04X}", opcode),
+        }
+    }
+}
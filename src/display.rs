@@ -0,0 +1,242 @@
+use crate::chip8::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::panel::Panel;
+use core::convert::Infallible;
+use core::marker::PhantomData;
+use display_interface::{DataFormat, DisplayError, WriteOnlyDataCommand};
+use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
+
+// Largest panel the buffers are sized for
+pub const MAX_PANEL_WIDTH: usize = 128;
+pub const MAX_PAGES: usize = 8;
+// Each page is sent as one I2C write: the data control byte followed by the
+// page's columns, so the buffer handed to the transport carries both
+pub const PAGE_BYTES: usize = MAX_PANEL_WIDTH + 1;
+const DATA_CONTROL_BYTE: u8 = 0x40;
+// Commands go out over I2C behind this control byte, at most
+// I2C_COMMANDS_PER_WRITE of them in one write
+#[cfg_attr(feature = "spi", allow(dead_code))]
+pub const COMMAND_CONTROL_BYTE: u8 = 0x00;
+pub const I2C_COMMANDS_PER_WRITE: usize = 7;
+
+pub type PageBuffer = [u8; PAGE_BYTES];
+pub type FrameBuffer = [PageBuffer; MAX_PAGES];
+
+// Something that can push a page of a finished frame to the panel in the
+// background. On the board this is the I2C1 DMA stream or SPI1; on the host
+// it can be any fake that records the pages and lets the caller decide when
+// a transfer ends.
+pub trait FrameTransport {
+    type Error;
+
+    // Select `page` starting at `column` and start sending `data`, which
+    // begins with the data control byte. The transport may keep reading from
+    // `data` until `is_busy` returns false again.
+    fn start_page(&mut self, page: u8, column: u8, data: &'static [u8]) -> Result<(), Self::Error>;
+
+    fn is_busy(&mut self) -> bool;
+
+    // Send panel commands, only called while the transport is not busy.
+    // Returns once the commands have been sent.
+    fn send_commands(&mut self, commands: &[u8]) -> Result<(), Self::Error>;
+}
+
+// Select `page` starting at `column`, the commands every transport sends
+// before a page's data
+pub fn page_commands(page: u8, column: u8) -> [u8; 3] {
+    [0xB0 | page, column & 0x0F, 0x10 | (column >> 4)]
+}
+
+// Where the 64x32 Chip8 image sits on a panel, always 1:1: centered across,
+// and on 64 row panels at row 17 like it always has been on the 128x64
+// SSD1306. Panels only as tall as the image show it from the top row.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Layout {
+    pub x_offset: usize,
+    pub y_offset: usize,
+}
+
+impl Layout {
+    pub const fn for_panel(width: usize, height: usize) -> Layout {
+        let y_offset = if height > SCREEN_HEIGHT {
+            (height - SCREEN_HEIGHT) / 2 + 1
+        } else {
+            0
+        };
+        Layout {
+            x_offset: (width - SCREEN_WIDTH) / 2,
+            y_offset,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FlushState {
+    Idle,
+    Sending(u8),
+}
+
+// Double buffered flush: the emulator always renders into the back buffer
+// while the transport owns the front buffer. `present` swaps them once the
+// previous frame has completed, and `poll` moves the transfer on page by page.
+pub struct FrameFlusher<P: Panel, T: FrameTransport> {
+    transport: T,
+    buffers: &'static mut [FrameBuffer; 2],
+    back: usize,
+    state: FlushState,
+    panel: PhantomData<P>,
+}
+
+impl<P: Panel, T: FrameTransport> FrameFlusher<P, T> {
+    pub fn new(transport: T, buffers: &'static mut [FrameBuffer; 2]) -> FrameFlusher<P, T> {
+        for page in buffers.iter_mut().flatten() {
+            page.fill(0);
+            page[0] = DATA_CONTROL_BYTE;
+        }
+        FrameFlusher {
+            transport,
+            buffers,
+            back: 0,
+            state: FlushState::Idle,
+            panel: PhantomData,
+        }
+    }
+
+    // Start the next page once the transport has finished with the current one
+    pub fn poll(&mut self) -> Result<FlushState, T::Error> {
+        if let FlushState::Sending(page) = self.state {
+            if !self.transport.is_busy() {
+                let next = page + 1;
+                if (next as usize) < P::PAGES {
+                    self.send_page(next)?;
+                } else {
+                    self.state = FlushState::Idle;
+                }
+            }
+        }
+        Ok(self.state)
+    }
+
+    pub fn is_busy(&mut self) -> Result<bool, T::Error> {
+        Ok(self.poll()? != FlushState::Idle)
+    }
+
+    // For the transport's interrupts, which SPI display builds don't have
+    #[cfg_attr(feature = "spi", allow(dead_code))]
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    // Buffer that is not being transferred
+    pub fn back_buffer(&mut self) -> &mut FrameBuffer {
+        &mut self.buffers[self.back]
+    }
+
+    // Hand the back buffer to the transport. Returns Ok(false) without doing
+    // anything if the previous frame is still in flight, so the caller can
+    // keep running instructions and try again later.
+    pub fn present(&mut self) -> Result<bool, T::Error> {
+        if self.is_busy()? {
+            return Ok(false);
+        }
+        self.back ^= 1;
+        self.send_page(0)?;
+        Ok(true)
+    }
+
+    fn send_page(&mut self, page: u8) -> Result<(), T::Error> {
+        let front = &self.buffers[self.back ^ 1][page as usize][..P::WIDTH + 1];
+        // SAFETY: the front buffer is only read by the transport and is not
+        // handed out through `back_buffer` until the whole frame has been sent
+        let data: &'static [u8] = unsafe { &*(front as *const [u8]) };
+        self.state = FlushState::Sending(page);
+        self.transport.start_page(page, P::COLUMN_OFFSET, data)
+    }
+
+    // Wait for the frame in flight to finish, then send panel commands
+    pub fn send_commands(&mut self, commands: &[u8]) -> Result<(), T::Error> {
+        while self.is_busy()? {}
+        self.transport.send_commands(commands)
+    }
+}
+
+// Convert the Chip8 screen (one byte per pixel, row major) into the panel's
+// page layout
+pub fn render_frame<P: Panel>(
+    screen: &[u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    frame: &mut FrameBuffer,
+) {
+    let layout = Layout::for_panel(P::WIDTH, P::HEIGHT);
+    clear_frame(frame);
+    for (i, &pixel) in screen.iter().enumerate() {
+        if pixel == 1 {
+            let x = layout.x_offset + i % SCREEN_WIDTH;
+            let y = layout.y_offset + i / SCREEN_WIDTH;
+            frame[y / 8][1 + x] |= 1 << (y % 8);
+        }
+    }
+}
+
+pub fn clear_frame(frame: &mut FrameBuffer) {
+    for page in frame.iter_mut() {
+        page[1..].fill(0);
+    }
+}
+
+// Send a whole frame with blocking writes, for when the transports are not
+// usable (fault screen) or not set up yet
+pub fn send_frame_blocking<P: Panel, DI: WriteOnlyDataCommand>(
+    interface: &mut DI,
+    frame: &FrameBuffer,
+) -> Result<(), DisplayError> {
+    for (page, data) in frame.iter().take(P::PAGES).enumerate() {
+        let commands = page_commands(page as u8, P::COLUMN_OFFSET);
+        interface.send_commands(DataFormat::U8(&commands))?;
+        interface.send_data(DataFormat::U8(&data[1..P::WIDTH + 1]))?;
+    }
+    Ok(())
+}
+
+// embedded-graphics target drawing straight into a frame buffer, for screens
+// that are not the Chip8 image (fault screen, menus)
+pub struct Canvas<'a, P: Panel> {
+    frame: &'a mut FrameBuffer,
+    panel: PhantomData<P>,
+}
+
+impl<'a, P: Panel> Canvas<'a, P> {
+    pub fn new(frame: &'a mut FrameBuffer) -> Canvas<'a, P> {
+        Canvas {
+            frame,
+            panel: PhantomData,
+        }
+    }
+}
+
+impl<P: Panel> OriginDimensions for Canvas<'_, P> {
+    fn size(&self) -> Size {
+        Size::new(P::WIDTH as u32, P::HEIGHT as u32)
+    }
+}
+
+impl<P: Panel> DrawTarget for Canvas<'_, P> {
+    type Color = BinaryColor;
+    type Error = Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            let (x, y) = (point.x as usize, point.y as usize);
+            if point.x < 0 || point.y < 0 || x >= P::WIDTH || y >= P::HEIGHT {
+                continue;
+            }
+            let byte = &mut self.frame[y / 8][1 + x];
+            match color {
+                BinaryColor::On => *byte |= 1 << (y % 8),
+                BinaryColor::Off => *byte &= !(1 << (y % 8)),
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+// Fault screen drawn on the panel when the emulator stops with an error, the
+// firmware panics or a HardFault is taken. The display bus is set up again
+// from scratch with blocking writes, since whatever was driving it before may
+// be in any state. Pressing the button resets the board.
+use crate::board::Pins;
+use crate::chip8::{Chip8, Chip8Error};
+use crate::display::{self, Canvas, FrameBuffer, MAX_PAGES, PAGE_BYTES};
+use crate::panel::{ActivePanel, Panel};
+use core::fmt::Write;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::ExceptionFrame;
+use embedded_graphics::{
+    mono_font::{ascii::FONT_4X6, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Baseline, Text},
+};
+use heapless::String;
+use stm32f4xx_hal::{pac, prelude::*};
+
+const LINE_HEIGHT: i32 = 6;
+// 128 pixels of 4 pixel wide characters
+const LINE_CHARS: usize = 32;
+// Lines on the panel, the last one is kept for the reset prompt: 10 on 64
+// row panels, 5 on 32 row ones
+const LINES: i32 = ActivePanel::HEIGHT as i32 / LINE_HEIGHT;
+// Lines the emulator fault takes laid out in full, with a line for each
+// half of the V registers
+const EMULATOR_LINES: i32 = 6;
+
+static IN_FAULT: AtomicBool = AtomicBool::new(false);
+
+// HardFault is named after the exception
+#[allow(clippy::enum_variant_names)]
+pub enum Fault<'a> {
+    Emulator { error: Chip8Error, chip8: &'a Chip8 },
+    Panic(&'a PanicInfo<'a>),
+    HardFault(&'a ExceptionFrame),
+}
+
+struct Screen<'a> {
+    canvas: Canvas<'a, ActivePanel>,
+    line: i32,
+}
+
+impl Screen<'_> {
+    // Lines that don't fit above the prompt are left out
+    fn line(&mut self, args: core::fmt::Arguments) {
+        if self.line < LINES - 1 {
+            self.draw(self.line, args);
+            self.line += 1;
+        }
+    }
+
+    fn prompt(&mut self) {
+        self.draw(LINES - 1, format_args!("PRESS KEY TO RESET"));
+    }
+
+    fn draw(&mut self, line: i32, args: core::fmt::Arguments) {
+        let mut text: String<LINE_CHARS> = String::new();
+        // Anything past the end of the line is cut off
+        let _ = text.write_fmt(args);
+        let style = MonoTextStyle::new(&FONT_4X6, BinaryColor::On);
+        let _ = Text::with_baseline(
+            &text,
+            Point::new(0, line * LINE_HEIGHT),
+            style,
+            Baseline::Top,
+        )
+        .draw(&mut self.canvas);
+    }
+}
+
+pub fn show(fault: Fault) -> ! {
+    cortex_m::interrupt::disable();
+    // A fault while drawing the fault screen would recurse forever
+    if IN_FAULT.swap(true, Ordering::AcqRel) {
+        loop {}
+    }
+    crate::buzzer::shutdown();
+
+    let mut frame: FrameBuffer = [[0; PAGE_BYTES]; MAX_PAGES];
+    let mut screen = Screen {
+        canvas: Canvas::new(&mut frame),
+        line: 0,
+    };
+    match fault {
+        Fault::Emulator { error, chip8 } => {
+            defmt::error!(
+                "emulator fault: {} at {:04x}, stack depth {}",
+                error,
+                chip8.program_counter,
+                chip8.stack_pointer
+            );
+            let r = &chip8.registers;
+            // Short panels go without the title, and show the registers as
+            // one run of hex digits, V0 first
+            let full = LINES > EMULATOR_LINES;
+            if full {
+                screen.line(format_args!("EMULATOR FAULT"));
+            }
+            screen.line(format_args!("{}", error));
+            match chip8.fetch_opcode() {
+                Ok(opcode) => screen.line(format_args!(
+                    "PC {:04X} OP {:04X} I {:04X}",
+                    chip8.program_counter, opcode, chip8.index_register
+                )),
+                Err(_) => screen.line(format_args!(
+                    "PC {:04X} OP ---- I {:04X}",
+                    chip8.program_counter, chip8.index_register
+                )),
+            }
+            screen.line(format_args!(
+                "SP {:02X} DT {:02X} ST {:02X}",
+                chip8.stack_pointer, chip8.delay_timer, chip8.sound_timer
+            ));
+            if full {
+                screen.line(format_args!(
+                    "V0 {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X}",
+                    r[0], r[1], r[2], r[3], r[4], r[5], r[6], r[7]
+                ));
+                screen.line(format_args!(
+                    "V8 {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X} {:02X}",
+                    r[8], r[9], r[10], r[11], r[12], r[13], r[14], r[15]
+                ));
+            } else {
+                let mut hex: String<LINE_CHARS> = String::new();
+                for value in r {
+                    let _ = write!(hex, "{:02X}", value);
+                }
+                screen.line(format_args!("{}", hex));
+            }
+        }
+        Fault::Panic(info) => {
+            defmt::error!("{}", defmt::Display2Format(info));
+            screen.line(format_args!("PANIC"));
+            if let Some(location) = info.location() {
+                let file = location.file().rsplit('/').next().unwrap_or("");
+                screen.line(format_args!("{}:{}", file, location.line()));
+            }
+            // Wrap the message over the remaining lines
+            let mut message: String<{ LINE_CHARS * 5 }> = String::new();
+            let _ = write!(message, "{}", info.message());
+            for chunk in message.as_bytes().chunks(LINE_CHARS) {
+                screen.line(format_args!(
+                    "{}",
+                    core::str::from_utf8(chunk).unwrap_or("?")
+                ));
+            }
+        }
+        Fault::HardFault(ef) => {
+            defmt::error!("{}", defmt::Debug2Format(ef));
+            screen.line(format_args!("HARDFAULT"));
+            screen.line(format_args!("PC {:08X} LR {:08X}", ef.pc(), ef.lr()));
+            screen.line(format_args!("R0 {:08X} R1 {:08X}", ef.r0(), ef.r1()));
+            screen.line(format_args!("R2 {:08X} R3 {:08X}", ef.r2(), ef.r3()));
+            screen.line(format_args!("R12 {:08X} XPSR {:08X}", ef.r12(), ef.xpsr()));
+        }
+    }
+    screen.prompt();
+
+    // SAFETY: nothing else runs from here on, the peripherals are only
+    // reconfigured for the fault screen and the reset key
+    let dp = unsafe { pac::Peripherals::steal() };
+    let clocks = crate::board::clocks(dp.RCC);
+    let pins = Pins::new(dp.GPIOA.split(), dp.GPIOB.split());
+    let mut button_out = pins.button_out;
+    let button_in = pins.button_in;
+    button_out.set_high();
+
+    #[cfg(feature = "i2c")]
+    let mut interface = {
+        crate::i2c_dma::shutdown();
+        crate::i2c_dma::interface(dp.I2C1, pins.display, &clocks)
+    };
+    #[cfg(feature = "spi")]
+    let mut interface = {
+        let bus = crate::spi_bus::new(dp.SPI1, pins.spi1, &clocks);
+        let bus = cortex_m::singleton!(: crate::spi_bus::Bus = bus).unwrap();
+        crate::spi_display::interface(bus, pins.display, &clocks)
+    };
+    if ActivePanel::init(&mut interface).is_ok() {
+        let _ = display::send_frame_blocking::<ActivePanel, _>(&mut interface, &frame);
+    }
+
+    // Wait for a full press so a key held down during the fault is ignored
+    while button_in.is_high() {}
+    while button_in.is_low() {}
+    SCB::sys_reset();
+}
@@ -0,0 +1,61 @@
+// 60 Hz frame tick from TIM9. Its interrupt is the frame task in main.rs:
+// the CHIP-8 timers and keys are updated there and the frame's instructions
+// are handed to the CPU task. Nothing depends on how long the instructions
+// or a present take.
+use stm32f4xx_hal::pac::TIM9;
+use stm32f4xx_hal::timer::{CounterHz, Event, Flag};
+use stm32f4xx_hal::{prelude::*, rcc::Clocks};
+
+pub const FRAME_HZ: u32 = 60;
+
+pub struct FrameTick {
+    timer: CounterHz<TIM9>,
+}
+
+impl FrameTick {
+    pub fn new(tim9: TIM9, clocks: &Clocks) -> FrameTick {
+        let mut timer = tim9.counter_hz(clocks);
+        timer.listen(Event::Update);
+        timer.start(FRAME_HZ.Hz()).unwrap();
+        FrameTick { timer }
+    }
+
+    // The timer clock is divided by `divider`, see power::set_speed. It was
+    // set up for the full clock, so ask it for that many times the rate
+    pub fn set_divider(&mut self, divider: u32) {
+        self.timer.start((FRAME_HZ * divider).Hz()).unwrap();
+    }
+
+    // Called from the TIM1_BRK_TIM9 interrupt
+    pub fn clear(&mut self) {
+        self.timer.clear_flags(Flag::Update);
+    }
+}
+
+// Frames presented in the last second, for the RTT log
+pub struct FpsCounter {
+    frames: u32,
+    since_ms: u32,
+}
+
+impl FpsCounter {
+    pub fn new(now_ms: u32) -> FpsCounter {
+        FpsCounter {
+            frames: 0,
+            since_ms: now_ms,
+        }
+    }
+
+    // Count a presented frame, returns the rate once a second has passed
+    pub fn frame(&mut self, now_ms: u32) -> Option<u32> {
+        self.frames += 1;
+        let elapsed = now_ms.wrapping_sub(self.since_ms);
+        if elapsed < 1000 {
+            return None;
+        }
+        let fps = self.frames * 1000 / elapsed;
+        self.frames = 0;
+        self.since_ms = now_ms;
+        Some(fps)
+    }
+}
@@ -0,0 +1,170 @@
+// Panel over I2C1 with the frame pushed by DMA1 stream 7
+use crate::board::DisplayPins;
+use crate::display::{self, FrameTransport, COMMAND_CONTROL_BYTE, I2C_COMMANDS_PER_WRITE};
+use crate::panel::Panel;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, Ordering};
+use cortex_m::peripheral::NVIC;
+use ssd1306::{prelude::I2CInterface, I2CDisplayInterface};
+use stm32f4xx_hal::dma::{Stream7, StreamsTuple};
+use stm32f4xx_hal::i2c::dma::{
+    self as i2c_dma, I2CMasterDma, I2CMasterHandleIT, I2CMasterWriteDMA, NoDMA, TxDMA,
+};
+use stm32f4xx_hal::i2c::I2c;
+use stm32f4xx_hal::pac::{Interrupt, DMA1, I2C1};
+use stm32f4xx_hal::{self as hal, prelude::*, rcc::Clocks};
+
+// All of the supported I2C panels answer on the SSD1306 default address
+const SSD1306_ADDRESS: u8 = 0x3C;
+
+pub type I2cDisplayInterface = I2CInterface<I2c<I2C1>>;
+type I2c1Dma = I2CMasterDma<I2C1, TxDMA<I2C1, Stream7<DMA1>, 1>, NoDMA>;
+
+// Cleared by the completion callback, which the HAL calls without a way to
+// reach the transport
+static FLUSH_BUSY: AtomicBool = AtomicBool::new(false);
+
+// Page select and settings commands, preceded by the command control byte.
+// Only written while no transfer is in flight.
+static mut PAGE_COMMANDS: [u8; 4] = [COMMAND_CONTROL_BYTE; 4];
+static mut COMMANDS: [u8; I2C_COMMANDS_PER_WRITE + 1] =
+    [COMMAND_CONTROL_BYTE; I2C_COMMANDS_PER_WRITE + 1];
+
+// Blocking interface, used for panel init and the fault screen
+pub fn interface(i2c1: I2C1, pins: DisplayPins, clocks: &Clocks) -> I2cDisplayInterface {
+    let i2c = i2c1.i2c((pins.scl, pins.sda), 400.kHz(), clocks);
+    I2CDisplayInterface::new(i2c)
+}
+
+// Stop the interrupts driving the DMA state machine so the bus can be taken
+// over with blocking writes
+pub fn shutdown() {
+    NVIC::mask(Interrupt::I2C1_ER);
+    NVIC::mask(Interrupt::DMA1_STREAM7);
+}
+
+// The I2C1 error and DMA1 stream 7 interrupts drive the transfer state
+// machine inside the HAL, see the display tasks in main.rs. The address phase
+// is polled when a transfer starts, so there is no event interrupt
+pub struct I2cDmaTransport {
+    i2c: I2c1Dma,
+    // Page data waiting for its page select commands to finish
+    pending: Option<&'static [u8]>,
+}
+
+impl I2cDmaTransport {
+    // Initialisation is done with blocking writes, after that frames are
+    // streamed by DMA
+    pub fn new<P: Panel>(
+        i2c1: I2C1,
+        dma1: DMA1,
+        pins: DisplayPins,
+        clocks: &Clocks,
+    ) -> I2cDmaTransport {
+        let mut interface = interface(i2c1, pins, clocks);
+        P::init(&mut interface).unwrap();
+        let i2c = interface.release();
+
+        let streams = StreamsTuple::new(dma1);
+        I2cDmaTransport {
+            i2c: i2c.use_dma_tx(streams.7),
+            pending: None,
+        }
+    }
+
+    pub fn on_dma_interrupt(&mut self) {
+        self.i2c.handle_dma_interrupt();
+    }
+
+    pub fn on_error_interrupt(&mut self) {
+        self.i2c.handle_error_interrupt();
+    }
+
+    // Whether a transfer is still in flight. The interrupts are masked while
+    // a task with the flusher locked waits, so the flags are checked here too
+    fn in_flight(&mut self) -> bool {
+        if FLUSH_BUSY.load(Ordering::Acquire) {
+            self.on_dma_interrupt();
+            self.on_error_interrupt();
+        }
+        FLUSH_BUSY.load(Ordering::Acquire)
+    }
+
+    fn write(&mut self, bytes: &'static [u8]) -> Result<(), hal::i2c::Error> {
+        FLUSH_BUSY.store(true, Ordering::Release);
+        // SAFETY: `bytes` is 'static and is not written again until
+        // FLUSH_BUSY is cleared by the completion callback
+        let result = unsafe {
+            self.i2c
+                .write_dma(SSD1306_ADDRESS, bytes, Some(flush_complete))
+        };
+        if result.is_err() {
+            FLUSH_BUSY.store(false, Ordering::Release);
+        }
+        result.map_err(|e| match e {
+            hal::nb::Error::Other(e) => e,
+            hal::nb::Error::WouldBlock => hal::i2c::Error::Timeout,
+        })
+    }
+}
+
+impl FrameTransport for I2cDmaTransport {
+    type Error = hal::i2c::Error;
+
+    fn start_page(&mut self, page: u8, column: u8, data: &'static [u8]) -> Result<(), Self::Error> {
+        // SAFETY: the flusher only starts a page once the previous transfer
+        // has completed, so the DMA is not reading PAGE_COMMANDS
+        let commands = unsafe {
+            let commands = &mut *addr_of_mut!(PAGE_COMMANDS);
+            commands[1..].copy_from_slice(&display::page_commands(page, column));
+            &*commands
+        };
+        self.pending = Some(data);
+        self.write(commands)
+    }
+
+    fn is_busy(&mut self) -> bool {
+        if self.in_flight() {
+            return true;
+        }
+        match self.pending.take() {
+            Some(data) => match self.write(data) {
+                Ok(()) => true,
+                Err(e) => {
+                    defmt::error!("display flush failed: {}", defmt::Debug2Format(&e));
+                    false
+                }
+            },
+            None => false,
+        }
+    }
+
+    fn send_commands(&mut self, commands: &[u8]) -> Result<(), Self::Error> {
+        for chunk in commands.chunks(I2C_COMMANDS_PER_WRITE) {
+            // SAFETY: nothing is in flight, see FrameFlusher::send_commands,
+            // and this waits for the transfer to finish before returning
+            let bytes = unsafe {
+                let buffer = &mut *addr_of_mut!(COMMANDS);
+                buffer[1..=chunk.len()].copy_from_slice(chunk);
+                &buffer[..=chunk.len()]
+            };
+            self.write(bytes)?;
+            while self.in_flight() {
+                core::hint::spin_loop();
+            }
+        }
+        Ok(())
+    }
+}
+
+// Errors in the DMA transfer only come here, the frame is dropped
+fn flush_complete(result: Result<(), i2c_dma::Error>) {
+    match result {
+        Ok(()) => {}
+        Err(i2c_dma::Error::I2CError(e)) => {
+            defmt::error!("display flush failed: {}", defmt::Debug2Format(&e))
+        }
+        Err(_) => defmt::error!("display flush failed: DMA error"),
+    }
+    FLUSH_BUSY.store(false, Ordering::Release);
+}
@@ -0,0 +1,178 @@
+// Input handling that does not depend on the board: menu navigation events,
+// turning a single button into short and long presses, and button events.
+use crate::keymap::{button_bit, Button, ButtonMask, ButtonSource};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MenuInput {
+    Up,
+    Down,
+    Left,
+    Right,
+    Select,
+    Back,
+}
+
+// Menu navigation on the hex keypad: 2/8/4/6 as arrows around 5 to select,
+// 0 to go back
+pub fn menu_input_for_key(key: u8) -> Option<MenuInput> {
+    match key {
+        0x2 => Some(MenuInput::Up),
+        0x8 => Some(MenuInput::Down),
+        0x4 => Some(MenuInput::Left),
+        0x6 => Some(MenuInput::Right),
+        0x5 => Some(MenuInput::Select),
+        0x0 => Some(MenuInput::Back),
+        _ => None,
+    }
+}
+
+// Menu navigation on the d-pad, A to select and B to go back
+pub fn menu_input_for_button(button: Button) -> Option<MenuInput> {
+    match button {
+        Button::Up => Some(MenuInput::Up),
+        Button::Down => Some(MenuInput::Down),
+        Button::Left => Some(MenuInput::Left),
+        Button::Right => Some(MenuInput::Right),
+        Button::A => Some(MenuInput::Select),
+        Button::B => Some(MenuInput::Back),
+        Button::Menu => None,
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Gesture {
+    Short,
+    Long,
+}
+
+// Presses shorter than this are contact bounce
+const DEBOUNCE_MS: u32 = 20;
+const LONG_PRESS_MS: u32 = 600;
+
+pub struct ButtonGestures {
+    pressed_at: Option<u32>,
+    long_reported: bool,
+}
+
+impl ButtonGestures {
+    pub const fn new() -> ButtonGestures {
+        ButtonGestures {
+            pressed_at: None,
+            long_reported: false,
+        }
+    }
+
+    // Feed the current button level and time. A long press is reported once
+    // while the button is still held, a short press on release.
+    pub fn update(&mut self, pressed: bool, now_ms: u32) -> Option<Gesture> {
+        match (self.pressed_at, pressed) {
+            (None, true) => {
+                self.pressed_at = Some(now_ms);
+                self.long_reported = false;
+                None
+            }
+            (Some(start), true) => {
+                if !self.long_reported && now_ms.wrapping_sub(start) >= LONG_PRESS_MS {
+                    self.long_reported = true;
+                    Some(Gesture::Long)
+                } else {
+                    None
+                }
+            }
+            (Some(start), false) => {
+                self.pressed_at = None;
+                let held = now_ms.wrapping_sub(start);
+                if !self.long_reported && held >= DEBOUNCE_MS {
+                    Some(Gesture::Short)
+                } else {
+                    None
+                }
+            }
+            (None, false) => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ButtonEvent {
+    pub button: Button,
+    pub pressed: bool,
+}
+
+const ALL_BUTTONS: [Button; 7] = [
+    Button::Up,
+    Button::Down,
+    Button::Left,
+    Button::Right,
+    Button::A,
+    Button::B,
+    Button::Menu,
+];
+
+// Events for every button that changed between two samples
+pub fn button_events(
+    previous: ButtonMask,
+    current: ButtonMask,
+) -> impl Iterator<Item = ButtonEvent> {
+    ALL_BUTTONS.into_iter().filter_map(move |button| {
+        let bit = button_bit(button);
+        if (previous ^ current) & bit != 0 {
+            Some(ButtonEvent {
+                button,
+                pressed: current & bit != 0,
+            })
+        } else {
+            None
+        }
+    })
+}
+
+// Button state built from press/release events. A press stays visible until
+// the end of the next frame even if the release comes first, so a tap that
+// is shorter than a frame still reaches the game.
+pub struct ButtonLatch {
+    held: ButtonMask,
+    // Pressed since the last frame ended
+    pressed_this_frame: ButtonMask,
+    // Pressed during the previous frame
+    pressed_last_frame: ButtonMask,
+}
+
+impl ButtonLatch {
+    pub const fn new() -> ButtonLatch {
+        ButtonLatch {
+            held: 0,
+            pressed_this_frame: 0,
+            pressed_last_frame: 0,
+        }
+    }
+
+    pub fn apply(&mut self, event: ButtonEvent) {
+        let bit = button_bit(event.button);
+        if event.pressed {
+            self.held |= bit;
+            self.pressed_this_frame |= bit;
+        } else {
+            self.held &= !bit;
+        }
+    }
+
+    pub fn end_frame(&mut self) {
+        self.pressed_last_frame = self.pressed_this_frame;
+        self.pressed_this_frame = 0;
+    }
+
+    pub fn is_held(&self, button: Button) -> bool {
+        self.held & button_bit(button) != 0
+    }
+
+    pub fn buttons(&self) -> ButtonMask {
+        self.held | self.pressed_this_frame | self.pressed_last_frame
+    }
+}
+
+impl ButtonSource for ButtonLatch {
+    fn read_buttons(&mut self) -> ButtonMask {
+        self.buttons()
+    }
+}
@@ -0,0 +1,38 @@
+// The storage sectors of the STM32F411's own flash: sectors 6 and 7, 128K
+// each from 0x0804_0000, past the end of the FLASH region in memory.x.
+use crate::storage::Flash;
+use stm32f4xx_hal::flash::{Error, FlashExt};
+use stm32f4xx_hal::pac::FLASH;
+
+const FIRST_SECTOR: u8 = 6;
+const REGION_OFFSET: usize = 0x4_0000;
+
+pub struct InternalFlash {
+    flash: FLASH,
+}
+
+impl InternalFlash {
+    pub fn new(flash: FLASH) -> InternalFlash {
+        InternalFlash { flash }
+    }
+}
+
+impl Flash for InternalFlash {
+    type Error = Error;
+    const SECTOR_SIZE: usize = 128 * 1024;
+    const SECTOR_COUNT: usize = 2;
+
+    fn read(&self) -> &[u8] {
+        &self.flash.read()[REGION_OFFSET..REGION_OFFSET + Self::SECTOR_SIZE * Self::SECTOR_COUNT]
+    }
+
+    fn erase(&mut self, sector: usize) -> Result<(), Error> {
+        self.flash.unlocked().erase(FIRST_SECTOR + sector as u8)
+    }
+
+    fn program(&mut self, offset: usize, bytes: &[u8]) -> Result<(), Error> {
+        self.flash
+            .unlocked()
+            .program(REGION_OFFSET + offset, bytes.iter())
+    }
+}
@@ -0,0 +1,142 @@
+// Per-ROM key maps. Most games only use a handful of the 16 hex keys and
+// those are rarely in comfortable spots, so a ROM can name a key map that
+// lets a d-pad plus A/B buttons stand in for whichever keys it uses.
+use crate::chip8::{InputSource, KEY_COUNT};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Button {
+    Up,
+    Down,
+    Left,
+    Right,
+    A,
+    B,
+    // Opens the settings menu, never part of a key map
+    Menu,
+}
+
+// Buttons a key map can assign, Up to B
+pub const BUTTON_COUNT: usize = 6;
+
+// Bit (Button as u8) is set for every pressed button
+pub type ButtonMask = u8;
+
+pub fn button_bit(button: Button) -> ButtonMask {
+    1 << button as u8
+}
+
+// The CHIP-8 key each button presses, indexed by Button
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeyMap {
+    pub keys: [Option<u8>; BUTTON_COUNT],
+}
+
+impl KeyMap {
+    pub const fn new(up: u8, down: u8, left: u8, right: u8, a: u8, b: u8) -> KeyMap {
+        KeyMap {
+            keys: [
+                Some(up),
+                Some(down),
+                Some(left),
+                Some(right),
+                Some(a),
+                Some(b),
+            ],
+        }
+    }
+
+    pub fn apply(&self, buttons: ButtonMask, keys: &mut [u8; KEY_COUNT]) {
+        *keys = [0; KEY_COUNT];
+        for (i, key) in self.keys.iter().enumerate() {
+            if let Some(key) = key {
+                if buttons & (1 << i) != 0 {
+                    keys[*key as usize & 0xF] = 1;
+                }
+            }
+        }
+    }
+}
+
+// The hex keypad layout from buttons_from_hex, for buttons on ROMs that have
+// no key map of their own
+pub const HEX_KEYMAP: KeyMap = KeyMap::new(0x2, 0x8, 0x4, 0x6, 0x5, 0x0);
+
+// Key maps by ROM name, matched without case against the file name with the
+// extension and anything in brackets dropped. ROMs in roms/ can set theirs in
+// a .meta file instead.
+pub const ROM_KEYMAPS: &[(&str, KeyMap)] = &[
+    // Left paddle on 1/4, right paddle on C/D
+    ("pong", KeyMap::new(0x1, 0x4, 0x1, 0x4, 0xC, 0xD)),
+    // 4/6 move, 5 fires
+    ("space invaders", KeyMap::new(0x5, 0x5, 0x4, 0x6, 0x5, 0x5)),
+    ("brix", KeyMap::new(0x4, 0x6, 0x4, 0x6, 0x4, 0x6)),
+    ("breakout", KeyMap::new(0x4, 0x6, 0x4, 0x6, 0x4, 0x6)),
+    // 3/6 up and down, 7/8 left and right
+    ("blinky", KeyMap::new(0x3, 0x6, 0x7, 0x8, 0x3, 0x6)),
+    // 2/8/4/6 steer, 5 fires
+    ("tank", KeyMap::new(0x2, 0x8, 0x4, 0x6, 0x5, 0x5)),
+];
+
+pub fn keymap_for(rom_name: &str) -> Option<&'static KeyMap> {
+    let name = rom_name.rsplit('/').next().unwrap_or(rom_name);
+    let name = name.strip_suffix(".ch8").unwrap_or(name);
+    let name = name.split(['[', '(']).next().unwrap_or(name).trim();
+    ROM_KEYMAPS
+        .iter()
+        .find(|(rom, _)| rom.eq_ignore_ascii_case(name))
+        .map(|(_, map)| map)
+}
+
+// Buttons from the hex keypad: the 2/8/4/6 cluster is the d-pad, 5 is A and
+// 0 is B
+pub fn buttons_from_hex(keys: &[u8; KEY_COUNT]) -> ButtonMask {
+    const HEX_BUTTONS: [(u8, Button); BUTTON_COUNT] = [
+        (0x2, Button::Up),
+        (0x8, Button::Down),
+        (0x4, Button::Left),
+        (0x6, Button::Right),
+        (0x5, Button::A),
+        (0x0, Button::B),
+    ];
+    HEX_BUTTONS
+        .iter()
+        .filter(|(key, _)| keys[*key as usize] != 0)
+        .fold(0, |mask, (_, button)| mask | button_bit(*button))
+}
+
+// Something with a d-pad and A/B buttons
+pub trait ButtonSource {
+    fn read_buttons(&mut self) -> ButtonMask;
+}
+
+impl<S: ButtonSource + ?Sized> ButtonSource for &mut S {
+    fn read_buttons(&mut self) -> ButtonMask {
+        (**self).read_buttons()
+    }
+}
+
+// A button source seen through a key map
+pub struct MappedButtons<'a, S: ButtonSource> {
+    pub source: S,
+    pub map: &'a KeyMap,
+}
+
+impl<S: ButtonSource> InputSource for MappedButtons<'_, S> {
+    fn read_keys(&mut self, keys: &mut [u8; KEY_COUNT]) {
+        self.map.apply(self.source.read_buttons(), keys);
+    }
+}
+
+// Two input sources read as one, a key is pressed if either source has it
+pub struct EitherInput<A: InputSource, B: InputSource>(pub A, pub B);
+
+impl<A: InputSource, B: InputSource> InputSource for EitherInput<A, B> {
+    fn read_keys(&mut self, keys: &mut [u8; KEY_COUNT]) {
+        let mut other = [0; KEY_COUNT];
+        self.0.read_keys(keys);
+        self.1.read_keys(&mut other);
+        for (key, other) in keys.iter_mut().zip(other) {
+            *key = (*key != 0 || other != 0) as u8;
+        }
+    }
+}
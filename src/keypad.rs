@@ -0,0 +1,170 @@
+// 4x4 hex keypad matrix. Rows are driven high one at a time and the columns
+// (pulled down) are read back, giving one bit per key. The scan is generic
+// over embedded-hal pins so the debouncing and ghosting logic does not
+// depend on the board.
+use crate::chip8::{self, InputSource};
+use crate::keymap::{self, ButtonMask, ButtonSource};
+use embedded_hal::digital::{InputPin, OutputPin};
+
+pub const ROWS: usize = 4;
+pub const COLS: usize = 4;
+pub const KEY_COUNT: usize = ROWS * COLS;
+// One matrix key per hex key, write_keys fills every entry of Chip8::keys
+const _: () = assert!(KEY_COUNT == chip8::KEY_COUNT);
+
+// COSMAC VIP layout, indexed by [row][col]:
+//   1 2 3 C
+//   4 5 6 D
+//   7 8 9 E
+//   A 0 B F
+pub const COSMAC_LAYOUT: [[u8; COLS]; ROWS] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+// Hex key for a bit of a KeyMask
+pub fn key_at(bit: usize) -> u8 {
+    COSMAC_LAYOUT[bit / COLS][bit % COLS]
+}
+
+// The raw matrix has to read the same for this long before it is accepted
+const DEBOUNCE_MS: u32 = 10;
+
+// Bit (row * COLS + col) is set for every closed switch
+pub type KeyMask = u16;
+
+fn row_bits(mask: KeyMask, row: usize) -> u16 {
+    (mask >> (row * COLS)) & ((1 << COLS) - 1)
+}
+
+// Without a diode per switch, three keys on the corners of a rectangle also
+// close the fourth corner, so any two rows sharing two or more columns can
+// not be told apart from a phantom key press
+pub fn has_ghosting(mask: KeyMask) -> bool {
+    for a in 0..ROWS {
+        for b in (a + 1)..ROWS {
+            if (row_bits(mask, a) & row_bits(mask, b)).count_ones() >= 2 {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+// Debounces raw matrix samples into a stable key mask
+pub struct KeyMatrix {
+    stable: KeyMask,
+    candidate: KeyMask,
+    candidate_since: u32,
+}
+
+impl KeyMatrix {
+    pub const fn new() -> KeyMatrix {
+        KeyMatrix {
+            stable: 0,
+            candidate: 0,
+            candidate_since: 0,
+        }
+    }
+
+    // Feed a raw sample taken at `now_ms`. Samples with ghosting are dropped
+    // and the last stable state is kept.
+    pub fn update(&mut self, raw: KeyMask, now_ms: u32) {
+        if has_ghosting(raw) {
+            return;
+        }
+        if raw != self.candidate {
+            self.candidate = raw;
+            self.candidate_since = now_ms;
+        } else if now_ms.wrapping_sub(self.candidate_since) >= DEBOUNCE_MS {
+            self.stable = raw;
+        }
+    }
+
+    pub fn stable(&self) -> KeyMask {
+        self.stable
+    }
+
+    // Write the debounced state into Chip8::keys, one entry per hex key
+    pub fn write_keys(&self, keys: &mut [u8; chip8::KEY_COUNT]) {
+        for bit in 0..KEY_COUNT {
+            keys[key_at(bit) as usize] = ((self.stable >> bit) & 1) as u8;
+        }
+    }
+}
+
+pub struct Keypad<R, C> {
+    rows: [R; ROWS],
+    cols: [C; COLS],
+    matrix: KeyMatrix,
+}
+
+impl<R: OutputPin, C: InputPin> Keypad<R, C> {
+    pub fn new(mut rows: [R; ROWS], cols: [C; COLS]) -> Keypad<R, C> {
+        for row in rows.iter_mut() {
+            let _ = row.set_low();
+        }
+        Keypad {
+            rows,
+            cols,
+            matrix: KeyMatrix::new(),
+        }
+    }
+
+    // Drive each row in turn and read the columns. `settle` is called after
+    // a row goes high so the column lines have time to follow.
+    pub fn scan_raw(&mut self, mut settle: impl FnMut()) -> KeyMask {
+        let mut mask = 0;
+        for (r, row) in self.rows.iter_mut().enumerate() {
+            let _ = row.set_high();
+            settle();
+            for (c, col) in self.cols.iter_mut().enumerate() {
+                if col.is_high().unwrap_or(false) {
+                    mask |= 1 << (r * COLS + c);
+                }
+            }
+            let _ = row.set_low();
+        }
+        mask
+    }
+
+    pub fn scan(&mut self, now_ms: u32, settle: impl FnMut()) -> &KeyMatrix {
+        let raw = self.scan_raw(settle);
+        self.matrix.update(raw, now_ms);
+        &self.matrix
+    }
+
+    // Drive every row, so any key pulls its column high. For waking up on a
+    // key press, `release_rows` puts them back for scanning
+    pub fn drive_all_rows(&mut self) {
+        for row in self.rows.iter_mut() {
+            let _ = row.set_high();
+        }
+    }
+
+    pub fn release_rows(&mut self) {
+        for row in self.rows.iter_mut() {
+            let _ = row.set_low();
+        }
+    }
+
+    pub fn matrix(&self) -> &KeyMatrix {
+        &self.matrix
+    }
+}
+
+impl<R: OutputPin, C: InputPin> InputSource for Keypad<R, C> {
+    fn read_keys(&mut self, keys: &mut [u8; chip8::KEY_COUNT]) {
+        self.matrix.write_keys(keys);
+    }
+}
+
+impl<R: OutputPin, C: InputPin> ButtonSource for Keypad<R, C> {
+    fn read_buttons(&mut self) -> ButtonMask {
+        let mut keys = [0; chip8::KEY_COUNT];
+        self.matrix.write_keys(&mut keys);
+        keymap::buttons_from_hex(&keys)
+    }
+}
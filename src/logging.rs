@@ -0,0 +1,11 @@
+// Log statements for the code shared with the host tools. On the board they
+// are defmt over RTT, filtered at build time with DEFMT_LOG. On the host
+// they go through the log crate, and the tools that run the emulator print
+// them with env_logger, filtered at run time with RUST_LOG.
+//
+// Arguments have to work with both: defmt::Format on the board and
+// Display/Debug on the host.
+#[cfg(target_os = "none")]
+pub use defmt::{debug, trace, warn};
+#[cfg(not(target_os = "none"))]
+pub use log::{debug, trace, warn};
@@ -1,80 +1,936 @@
 #![allow(clippy::empty_loop)]
 #![no_std]
 #![no_main]
+mod audio;
+mod board;
+mod buttons;
+mod buzzer;
 mod chip8;
-use crate::chip8::SCREEN_HEIGHT;
-use crate::chip8::SCREEN_WIDTH;
-use chip8::Chip8;
+mod crc;
+mod debug;
+mod display;
+mod fault;
+mod frame_tick;
+#[cfg(feature = "i2c")]
+mod i2c_dma;
+mod input;
+mod internal_flash;
+mod keymap;
+mod keypad;
+mod logging;
+mod panel;
+mod power;
+mod rom_library;
+mod rom_menu;
+mod roms;
+mod sd_card;
+mod serial_keys;
+mod settings;
+mod spi_bus;
+#[cfg(feature = "spi")]
+mod spi_display;
+mod storage;
+mod time;
+// Records are only decoded by tools/tracedump
+#[cfg(feature = "trace")]
+#[allow(dead_code)]
+mod trace;
+mod uart_upload;
+mod upload;
+mod usb_keys;
+use audio::{AudioSettings, AudioSink, Sound};
+use board::{KeypadCol, KeypadRow, Pins};
+use chip8::{Chip8, Quirks};
+use core::panic::PanicInfo;
+use cortex_m_rt::exception;
 use cortex_m_rt::ExceptionFrame;
-use cortex_m_rt::{entry, exception};
-use embedded_graphics::{pixelcolor::BinaryColor, prelude::*};
-use panic_semihosting as _;
-use rtt_target::{rprintln, rtt_init_print};
-use ssd1306::mode::BufferedGraphicsMode;
-use ssd1306::{prelude::*, I2CDisplayInterface, Ssd1306};
-use stm32f4xx_hal::i2c::I2c;
-use stm32f4xx_hal::pac::I2C1;
-use stm32f4xx_hal::{self as hal, pac};
-
-use crate::hal::{
-    gpio::{gpiob::PB0, gpiob::PB1, Input, Output, PushPull},
-    pac::Interrupt,
-    prelude::*,
-};
-#[entry]
-fn main() -> ! {
-    rtt_init_print!();
-    let dp = pac::Peripherals::take().unwrap();
-    // Set up the system clock. 
-    let rcc = dp.RCC.constrain();
-    let clocks = rcc.cfgr.sysclk(100.MHz()).freeze();
-
-    // Set up I2C - SCL is PB6 and SDA is PB7; they are set to Alternate Function 4
-    let gpiob = dp.GPIOB.split();
-    let scl = gpiob.pb6.internal_pull_up(true);
-    let sda = gpiob.pb7.internal_pull_up(true);
-    // Configure PB0 as an output pin (connected to one side of the button)
-    let mut output_pin: PB0<Output<PushPull>> = gpiob.pb0.into_push_pull_output();
-    // Configure PB1 as an input pin as pull down input
-    let input_pin: PB1<Input> = gpiob.pb1.into_pull_down_input();
-
-    output_pin.set_high();
-    let i2c = dp.I2C1.i2c((scl, sda), 400.kHz(), &clocks);
-
-    // Set up the display
-    let interface = I2CDisplayInterface::new(i2c);
-    let mut disp = Ssd1306::new(interface, DisplaySize128x64, DisplayRotation::Rotate0)
-        .into_buffered_graphics_mode();
-    disp.init().unwrap();
-    disp.flush().unwrap();
-
-    let mut chip8 = Chip8::new();
-
-    // Load ROM ================================================================
-    //const CHIP8_ROM: &[u8] = include_bytes!("../test_opcode.ch8");
-    const CHIP8_ROM: &[u8] = include_bytes!("../Chip8 Picture.ch8");
-    //const CHIP8_ROM: &[u8] = include_bytes!("../Life [GV Samways, 1980].ch8");
-    // Load the program into the CHIP-8 emulator
-    chip8.load_program(&CHIP8_ROM);
-
-    loop {
-        // Emulate cycle:
-        chip8.emulate_cycle();
-        // Draw logic here =====================================================
-        disp.flush().unwrap();
-        for (i, &pixel) in chip8.screen.iter().enumerate() {
-            if pixel == 1 {
-                let x = 32 + ((i % SCREEN_WIDTH) as i32);
-                let y = 17 + (i / SCREEN_WIDTH) as i32;
-                Pixel(Point::new(x, y), BinaryColor::On)
-                    .draw(&mut disp)
-                    .unwrap();
+use display::{Canvas, FrameBuffer, FrameFlusher, FrameTransport, MAX_PAGES, PAGE_BYTES};
+use fault::Fault;
+use frame_tick::{FpsCounter, FrameTick};
+use heapless::spsc::{Consumer, Producer, Queue};
+use input::{ButtonGestures, ButtonLatch, Gesture, MenuInput};
+use internal_flash::InternalFlash;
+use keymap::{Button, EitherInput, KeyMap, MappedButtons};
+use keypad::{KeyMask, Keypad};
+use panel::{ActivePanel, Panel};
+use power::Speed;
+use rom_menu::{Rom, RomMenu};
+use rtic::mutex::prelude::*;
+use settings::{DisplaySettings, MenuAction, SettingsMenu};
+use stm32f4xx_hal as hal;
+use storage::{Flash, Key, Storage};
+
+use crate::hal::prelude::*;
+
+#[cfg(feature = "i2c")]
+type Transport = i2c_dma::I2cDmaTransport;
+#[cfg(feature = "spi")]
+type Transport = spi_display::SpiTransport;
+type Flusher = FrameFlusher<ActivePanel, Transport>;
+
+// What the input task saw, for the menus and the display sleep in the CPU
+// task. The game reads the keys itself on the frame tick
+#[derive(Clone, Copy)]
+enum UiEvent {
+    // A gamepad button other than Menu went down
+    Button(Button),
+    // Keypad keys that went down
+    Keys(KeyMask),
+    Gesture(Gesture),
+    // A key typed on a PC, see tools/keysend
+    UsbKey,
+}
+
+const UI_EVENTS: usize = 16;
+// Long enough for the keypad to debounce the press that woke the core
+const WAKE_KEY_MS: u32 = 100;
+
+// Read by the frame task to feed the game
+pub struct Game {
+    // Games with a key map are played with the 2/8/4/6 d-pad and 5/0 as A/B.
+    // Without one the gamepad buttons press those hex keys
+    keymap: Option<&'static KeyMap>,
+    // A menu is open, the game is paused and the keys drive the menu
+    paused: bool,
+    // Tone and volume of the beep, from the settings menu
+    audio: AudioSettings,
+}
+
+#[rtic::app(device = stm32f4xx_hal::pac, peripherals = true, dispatchers = [USART6])]
+mod app {
+    use super::*;
+
+    #[shared]
+    struct Shared {
+        chip8: Chip8,
+        flusher: Flusher,
+        game: Game,
+        // Slowed down along with the core in menus
+        frame_tick: FrameTick,
+        // Only touched by the button interrupts, which all run at the same
+        // priority
+        #[lock_free]
+        buttons: buttons::Buttons,
+        // The CPU task drives its rows while the core is stopped
+        keypad: Keypad<KeypadRow, KeypadCol>,
+        // Only touched at the frame task's priority, so none of these need
+        // a lock
+        // Button state as of the events drained so far, taps shorter than a
+        // frame are held until the frame after
+        #[lock_free]
+        latch: ButtonLatch,
+        // Keys typed on a PC, see tools/keysend
+        #[lock_free]
+        usb_keys: usb_keys::UsbKeys,
+        #[lock_free]
+        ui_events: Producer<'static, UiEvent, UI_EVENTS>,
+    }
+
+    #[local]
+    struct Local {
+        buzzer: buzzer::Buzzer,
+        button_events: buttons::ButtonEvents,
+        uart_receive: uart_upload::UartReceive,
+        gestures: ButtonGestures,
+        ui: Ui,
+    }
+
+    #[init(local = [
+        ui_events: Queue<UiEvent, UI_EVENTS> = Queue::new(),
+        button_queue: buttons::ButtonQueue = Queue::new(),
+        uart_bytes: uart_upload::ByteQueue = Queue::new(),
+    ])]
+    fn init(cx: init::Context) -> (Shared, Local) {
+        #[cfg(not(feature = "trace"))]
+        rtt_target::rtt_init_defmt!();
+        // Records are dropped rather than slowing the game down when the
+        // probe doesn't keep up
+        #[cfg(feature = "trace")]
+        let trace = {
+            let channels = rtt_target::rtt_init! {
+                up: {
+                    0: {
+                        size: 1024,
+                        mode: rtt_target::ChannelMode::NoBlockSkip,
+                        name: "defmt"
+                    }
+                    1: {
+                        size: 4096,
+                        mode: rtt_target::ChannelMode::NoBlockSkip,
+                        name: "trace"
+                    }
+                }
+            };
+            rtt_target::set_defmt_channel(channels.up.0);
+            channels.up.1
+        };
+        let dp = cx.device;
+        // Set up the system clock.
+        let clocks = board::clocks(dp.RCC);
+        time::init(cx.core.SYST, &clocks);
+        power::init(dp.PWR, dp.DBGMCU);
+
+        let pins = Pins::new(dp.GPIOA.split(), dp.GPIOB.split());
+        let mut button_out = pins.button_out;
+        button_out.set_high();
+        let mut syscfg = dp.SYSCFG.constrain();
+        let mut exti = dp.EXTI;
+        let (buttons, button_events) = buttons::Buttons::new(
+            pins.gamepad,
+            pins.button_in,
+            &mut syscfg,
+            &mut exti,
+            dp.TIM3,
+            &clocks,
+            cx.local.button_queue,
+        );
+        let keypad = Keypad::new(pins.keypad_rows, pins.keypad_cols);
+        let usb_keys = usb_keys::UsbKeys::new(
+            (dp.OTG_FS_GLOBAL, dp.OTG_FS_DEVICE, dp.OTG_FS_PWRCLK),
+            pins.usb,
+            &clocks,
+        );
+        // ROMs sent from a PC, see tools/romupload
+        let (uart_upload, uart_receive) =
+            uart_upload::UartUpload::new(dp.USART2, pins.uart, &clocks, cx.local.uart_bytes);
+        // SPI1 carries the SD card, and the display in SPI builds
+        let spi1 = spi_bus::new(dp.SPI1, pins.spi1, &clocks);
+        let spi1: &'static spi_bus::Bus = cortex_m::singleton!(: spi_bus::Bus = spi1).unwrap();
+        // ROMs on an SD card, listed after the bundled and uploaded ones
+        let card = sd_card::library(spi1, pins.sd, dp.TIM4, &clocks);
+        // ROMs and save states are read from the card through this buffer
+        let card_buffer: &'static mut [u8; chip8::STATE_BYTES] =
+            cortex_m::singleton!(: [u8; chip8::STATE_BYTES] = [0; chip8::STATE_BYTES]).unwrap();
+
+        let buzzer = buzzer::Buzzer::new(dp.TIM2, pins.buzzer, &clocks);
+
+        // Set up the display
+        #[cfg(feature = "i2c")]
+        let transport =
+            i2c_dma::I2cDmaTransport::new::<ActivePanel>(dp.I2C1, dp.DMA1, pins.display, &clocks);
+        #[cfg(feature = "spi")]
+        let transport = spi_display::SpiTransport::new::<ActivePanel>(spi1, pins.display, &clocks);
+
+        let buffers: &'static mut [FrameBuffer; 2] =
+            cortex_m::singleton!(: [FrameBuffer; 2] = [[[0; PAGE_BYTES]; MAX_PAGES]; 2]).unwrap();
+        let mut flusher: Flusher = FrameFlusher::new(transport, buffers);
+
+        // Display settings ====================================================
+        // Settings, uploaded ROMs and save states live in flash storage
+        let storage =
+            Storage::mount(InternalFlash::new(dp.FLASH)).expect("mounting flash storage failed");
+        let settings = storage
+            .read(Key::Settings)
+            .and_then(DisplaySettings::from_bytes)
+            .unwrap_or_default();
+        apply_settings(&mut flusher, &settings);
+        let audio = storage
+            .read(Key::AudioSettings)
+            .and_then(AudioSettings::from_bytes)
+            .unwrap_or_default();
+
+        let (producer, events) = cx.local.ui_events.split();
+        let mut ui = Ui {
+            events,
+            storage,
+            settings,
+            audio,
+            menu: None,
+            rom_menu: None,
+            last_input: time::millis(),
+            ipf: roms::DEFAULT_IPF,
+            speed: Speed::Full,
+            ignore_keys_until: 0,
+            fps: FpsCounter::new(time::millis()),
+            #[cfg(feature = "trace")]
+            trace,
+            uart_upload,
+            debugger: debug::DebugServer::new(),
+            card,
+            card_roms: heapless::Vec::new(),
+            card_buffer,
+            running_card_rom: None,
+        };
+        // Boot into the list of ROMs in roms/, uploaded ROMs and ROMs on the
+        // SD card, the chosen one is loaded into the emulator when it is
+        // picked
+        ui.open_rom_menu();
+
+        (
+            Shared {
+                chip8: Chip8::new(),
+                flusher,
+                buttons,
+                // Started last, so the first frame doesn't wait behind the
+                // setup above
+                frame_tick: FrameTick::new(dp.TIM9, &clocks),
+                game: Game {
+                    keymap: None,
+                    paused: true,
+                    audio,
+                },
+                keypad,
+                latch: ButtonLatch::new(),
+                usb_keys,
+                ui_events: producer,
+            },
+            Local {
+                buzzer,
+                button_events,
+                uart_receive,
+                // A long press opens the settings menu; in the menu a short
+                // press moves to the next item and a long press changes it
+                gestures: ButtonGestures::new(),
+                ui,
+            },
+        )
+    }
+
+    // Sleeps until the next interrupt, which once the frame's instructions
+    // have run is the next millisecond or frame tick
+    #[idle]
+    fn idle(_: idle::Context) -> ! {
+        loop {
+            cortex_m::asm::wfi();
+        }
+    }
+
+    // The game only moves on the 60 Hz tick: the timers count down and the
+    // keys are read here, the frame's instructions run in the CPU task.
+    // Paused while a menu is open
+    #[task(
+        binds = TIM1_BRK_TIM9,
+        priority = 2,
+        local = [buzzer],
+        shared = [chip8, game, frame_tick, keypad, latch, usb_keys]
+    )]
+    fn frame(mut cx: frame::Context) {
+        cx.shared.frame_tick.lock(|frame_tick| frame_tick.clear());
+        let latch = cx.shared.latch;
+        let usb_keys = cx.shared.usb_keys;
+        let (sound, audio) = (
+            &mut cx.shared.chip8,
+            &mut cx.shared.game,
+            &mut cx.shared.keypad,
+        )
+            .lock(|chip8, game, keypad| {
+                if game.paused {
+                    // Keys drive the menu instead of the game while it is open
+                    chip8.keys.fill(0);
+                    return (Sound::Silent, game.audio);
+                }
+                chip8.tick_timers();
+                let gamepad = MappedButtons {
+                    source: &mut *latch,
+                    map: game.keymap.unwrap_or(&keymap::HEX_KEYMAP),
+                };
+                // USB keys are sent as hex keys and skip the key map
+                match game.keymap {
+                    Some(map) => chip8.poll_input(&mut EitherInput(
+                        EitherInput(
+                            MappedButtons {
+                                source: &mut *keypad,
+                                map,
+                            },
+                            gamepad,
+                        ),
+                        &mut *usb_keys,
+                    )),
+                    None => chip8.poll_input(&mut EitherInput(
+                        EitherInput(&mut *keypad, gamepad),
+                        &mut *usb_keys,
+                    )),
+                }
+                // Beep while sound_timer runs, the menu pauses it along with the
+                // game
+                (audio::sound_for(chip8), game.audio)
+            });
+        latch.end_frame();
+        cx.local.buzzer.play(sound, &audio);
+        // Still busy with the last frame (a ROM load, a flash write), the
+        // timers keep real time and this frame's instructions are dropped
+        let _ = cpu::spawn();
+    }
+
+    // Everything that can take a while runs here, below the interrupts: the
+    // menus, storage, uploads, the frame's instructions and the present
+    #[task(
+        priority = 1,
+        local = [ui],
+        shared = [chip8, flusher, game, frame_tick, keypad]
+    )]
+    async fn cpu(cx: cpu::Context) {
+        let cpu::SharedResources {
+            mut chip8,
+            mut flusher,
+            mut game,
+            mut frame_tick,
+            mut keypad,
+            ..
+        } = cx.shared;
+        let ui = cx.local.ui;
+        let now = time::millis();
+        ui.handle_events(now, &mut chip8, &mut flusher, &mut game);
+        ui.poll_uart(now, &mut chip8, &mut game);
+        ui.sleep_when_idle(now, &mut flusher, &mut keypad);
+        let paused = ui.menu_open();
+        // Halted by the debugger the game stands still without a menu
+        let halted = ui.debugger.is_halted();
+        game.lock(|game| game.paused = paused || halted);
+        let speed = if paused { Speed::Half } else { Speed::Full };
+        if speed != ui.speed {
+            ui.speed = speed;
+            power::set_speed(speed);
+            frame_tick.lock(|frame_tick| frame_tick.set_divider(speed.divider()));
+        }
+        if !paused {
+            let ipf = ui.ipf;
+            #[cfg(feature = "trace")]
+            let trace = &mut ui.trace;
+            let debugger = &mut ui.debugger;
+            // Locked per instruction: the lock masks every task up to the
+            // frame task's priority, the millisecond tick and USB too, and
+            // they should wait one instruction at most rather than a frame
+            for _ in 0..ipf {
+                let ran = chip8.lock(|chip8| {
+                    if !debugger.may_run(chip8.program_counter) {
+                        return false;
+                    }
+                    #[cfg(not(feature = "trace"))]
+                    let result = chip8.emulate_cycle();
+                    #[cfg(feature = "trace")]
+                    let result = traced_cycle(chip8, trace);
+                    if let Err(error) = result {
+                        fault::show(Fault::Emulator { error, chip8 });
+                    }
+                    true
+                });
+                if !ran {
+                    break;
+                }
+                debugger.ran();
+            }
+        }
+        ui.draw(now, &mut chip8, &mut flusher);
+    }
+
+    // Moves the frame on to its next page as soon as a DMA transfer ends
+    #[cfg(feature = "i2c")]
+    #[task(binds = DMA1_STREAM7, priority = 3, shared = [flusher])]
+    fn display(mut cx: display::Context) {
+        cx.shared.flusher.lock(|flusher| {
+            flusher.transport().on_dma_interrupt();
+            if let Err(e) = flusher.poll() {
+                defmt::error!("display flush failed: {}", defmt::Debug2Format(&e));
+            }
+        });
+    }
+
+    #[cfg(feature = "i2c")]
+    #[task(binds = I2C1_ER, priority = 3, shared = [flusher])]
+    fn display_error(mut cx: display_error::Context) {
+        cx.shared.flusher.lock(|flusher| {
+            flusher.transport().on_error_interrupt();
+            if let Err(e) = flusher.poll() {
+                defmt::error!("display flush failed: {}", defmt::Debug2Format(&e));
+            }
+        });
+    }
+
+    // Button edges restart the debounce delay, which samples the buttons
+    // once it runs out
+    #[task(binds = EXTI1, priority = 3, shared = [buttons])]
+    fn button_edge_1(cx: button_edge_1::Context) {
+        cx.shared.buttons.on_edge();
+    }
+
+    #[task(binds = EXTI9_5, priority = 3, shared = [buttons])]
+    fn button_edge_9_5(cx: button_edge_9_5::Context) {
+        cx.shared.buttons.on_edge();
+    }
+
+    #[task(binds = EXTI15_10, priority = 3, shared = [buttons])]
+    fn button_edge_15_10(cx: button_edge_15_10::Context) {
+        cx.shared.buttons.on_edge();
+    }
+
+    #[task(binds = TIM3, priority = 3, shared = [buttons])]
+    fn button_debounce(cx: button_debounce::Context) {
+        cx.shared.buttons.on_timer();
+    }
+
+    // Above everything else: the receive register holds a single byte, and
+    // the next one arrives 87 us later at 115200 baud
+    #[task(binds = USART2, priority = 4, local = [uart_receive])]
+    fn uart_receive(cx: uart_receive::Context) {
+        cx.local.uart_receive.on_interrupt();
+    }
+
+    // The millisecond tick. Scans the keypad, often enough for the
+    // debouncing and well within a 60 Hz frame, and turns button edges into
+    // gestures
+    #[task(
+        binds = SysTick,
+        priority = 2,
+        local = [button_events, gestures],
+        shared = [keypad, latch, ui_events]
+    )]
+    fn input(mut cx: input::Context) {
+        time::tick();
+        let now = time::millis();
+        let latch = cx.shared.latch;
+        let ui_events = cx.shared.ui_events;
+        // A full queue means the CPU task is stuck in a long write, the
+        // presses are dropped like they would be in a menu
+        while let Some(event) = cx.local.button_events.dequeue() {
+            latch.apply(event);
+            if event.pressed && event.button != Button::Menu {
+                let _ = ui_events.enqueue(UiEvent::Button(event.button));
             }
         }
+        if let Some(gesture) = cx.local.gestures.update(latch.is_held(Button::Menu), now) {
+            let _ = ui_events.enqueue(UiEvent::Gesture(gesture));
+        }
+        let pressed = cx.shared.keypad.lock(|keypad| {
+            let previous = keypad.matrix().stable();
+            keypad.scan(now, || cortex_m::asm::delay(100)).stable() & !previous
+        });
+        if pressed != 0 {
+            let _ = ui_events.enqueue(UiEvent::Keys(pressed));
+        }
+    }
+
+    #[task(binds = OTG_FS, priority = 2, shared = [usb_keys, ui_events])]
+    fn usb(cx: usb::Context) {
+        if cx.shared.usb_keys.poll() {
+            let _ = cx.shared.ui_events.enqueue(UiEvent::UsbKey);
+        }
+    }
+}
+
+// State only the CPU task touches
+pub struct Ui {
+    events: Consumer<'static, UiEvent, UI_EVENTS>,
+    storage: Storage<InternalFlash>,
+    settings: DisplaySettings,
+    // The frame task's copy in Game is updated as the menu changes it
+    audio: AudioSettings,
+    menu: Option<SettingsMenu>,
+    rom_menu: Option<RomMenu>,
+    last_input: u32,
+    // Instructions per frame of the running ROM, changed in the settings menu
+    ipf: u16,
+    speed: Speed,
+    // Keypad presses before this are the one that woke the core up, see
+    // `sleep_when_idle`
+    ignore_keys_until: u32,
+    fps: FpsCounter,
+    #[cfg(feature = "trace")]
+    trace: rtt_target::UpChannel,
+    uart_upload: uart_upload::UartUpload,
+    // Speaks over the upload UART, see tools/chipdbg
+    debugger: debug::DebugServer,
+    card: sd_card::CardLibrary,
+    card_roms: heapless::Vec<rom_library::RomName, { rom_library::MAX_CARD_ROMS }>,
+    card_buffer: &'static mut [u8; chip8::STATE_BYTES],
+    // Index into card_roms of the running ROM, its save states go to the card
+    running_card_rom: Option<usize>,
+}
+
+impl Ui {
+    fn menu_open(&self) -> bool {
+        self.menu.is_some() || self.rom_menu.is_some()
+    }
+
+    // At boot and from Change ROM in the settings menu. The card is brought
+    // up and listed again each time, so one put in or swapped since the last
+    // listing shows up. Without a card the list is empty
+    fn open_rom_menu(&mut self) {
+        self.card.device().mark_card_uninit();
+        self.card_roms = self.card.list().unwrap_or_else(|e| {
+            defmt::info!("no ROMs from the SD card: {}", e);
+            heapless::Vec::new()
+        });
+        // The old index may point at another ROM in the new list
+        self.running_card_rom = None;
+        self.rom_menu = Some(RomMenu::new());
+    }
+
+    fn handle_events(
+        &mut self,
+        now: u32,
+        chip8: &mut impl Mutex<T = Chip8>,
+        flusher: &mut impl Mutex<T = Flusher>,
+        game: &mut impl Mutex<T = Game>,
+    ) {
+        let mut menu_input = None;
+        let menu_open = self.menu_open();
+        while let Some(event) = self.events.dequeue() {
+            let waking = (self.ignore_keys_until.wrapping_sub(now) as i32) > 0;
+            if waking && matches!(event, UiEvent::Keys(_)) {
+                continue;
+            }
+            self.last_input = now;
+            match (menu_open, event) {
+                (true, UiEvent::Button(button)) => {
+                    menu_input = menu_input.or(input::menu_input_for_button(button));
+                }
+                (true, UiEvent::Keys(pressed)) => {
+                    if let Some(bit) = (0..keypad::KEY_COUNT).find(|bit| pressed & (1 << bit) != 0)
+                    {
+                        menu_input = menu_input.or(input::menu_input_for_key(keypad::key_at(bit)));
+                    }
+                }
+                (true, UiEvent::Gesture(Gesture::Short)) => menu_input = Some(MenuInput::Down),
+                (true, UiEvent::Gesture(Gesture::Long)) => menu_input = Some(MenuInput::Select),
+                (false, UiEvent::Gesture(Gesture::Long)) => self.menu = Some(SettingsMenu::new()),
+                _ => {}
+            }
+        }
+        let Some(input) = menu_input else {
+            return;
+        };
+        if let Some(m) = self.menu.as_mut() {
+            let action = m.handle(&mut self.settings, &mut self.audio, &mut self.ipf, input);
+            match action {
+                MenuAction::None => {}
+                MenuAction::Changed => {
+                    flusher.lock(|flusher| apply_settings(flusher, &self.settings));
+                    game.lock(|game| game.audio = self.audio);
+                }
+                MenuAction::Close
+                | MenuAction::SaveState
+                | MenuAction::LoadState
+                | MenuAction::ChangeRom => {
+                    if let Err(e) = self.storage.write(Key::Settings, &self.settings.to_bytes()) {
+                        defmt::error!("saving settings failed: {}", defmt::Debug2Format(&e));
+                    }
+                    if let Err(e) = self
+                        .storage
+                        .write(Key::AudioSettings, &self.audio.to_bytes())
+                    {
+                        defmt::error!("saving audio settings failed: {}", defmt::Debug2Format(&e));
+                    }
+                    self.menu = None;
+                }
+            }
+            match action {
+                MenuAction::SaveState => self.save_state(chip8.lock(|chip8| chip8.save_state())),
+                MenuAction::LoadState => match self.load_state() {
+                    Some(saved) => chip8.lock(|chip8| *chip8 = saved),
+                    None => defmt::warn!("no saved state"),
+                },
+                MenuAction::ChangeRom => self.open_rom_menu(),
+                _ => {}
+            }
+        } else if let Some(m) = self.rom_menu.as_mut() {
+            let count =
+                roms::ROMS.len() + uploaded_slots(&self.storage).count() + self.card_roms.len();
+            if let Some(index) = m.handle(count, input) {
+                // The menu stays open when the ROM couldn't be read
+                if let Some(keymap) = chip8.lock(|chip8| self.load_rom(index, chip8)) {
+                    game.lock(|game| game.keymap = keymap);
+                    self.rom_menu = None;
+                }
+            }
+        }
+    }
+
+    fn save_state(&mut self, state: [u8; chip8::STATE_BYTES]) {
+        // A ROM from the SD card keeps its state next to it
+        if let Some(index) = self.running_card_rom {
+            if let Err(e) = self.card.save_state(&self.card_roms[index], &state) {
+                defmt::error!("saving state to the SD card failed: {}", e);
+            }
+            return;
+        }
+        if let Err(e) = self.storage.write(Key::SaveState(0), &state) {
+            defmt::error!("saving state failed: {}", defmt::Debug2Format(&e));
+        }
+    }
+
+    fn load_state(&mut self) -> Option<Chip8> {
+        if let Some(index) = self.running_card_rom {
+            return match self
+                .card
+                .load_state(&self.card_roms[index], self.card_buffer)
+            {
+                Ok(len) => Chip8::from_state(&self.card_buffer[..len]),
+                Err(e) => {
+                    defmt::error!("loading state from the SD card failed: {}", e);
+                    None
+                }
+            };
+        }
+        self.storage
+            .read(Key::SaveState(0))
+            .and_then(Chip8::from_state)
+    }
+
+    // Load the ROM at `index` in the ROM menu's list, returns its key map or
+    // None when it couldn't be read
+    fn load_rom(&mut self, index: usize, chip8: &mut Chip8) -> Option<Option<&'static KeyMap>> {
+        let (loaded, keymap) = match rom_entry(index, &self.storage) {
+            RomEntry::Bundled(rom) => {
+                defmt::info!("loading {}, {} instructions per frame", rom.name, rom.ipf);
+                defmt::debug!("sha1 {=[u8]:02x}", rom.sha1);
+                chip8.quirks = rom.quirks;
+                self.ipf = rom.ipf;
+                self.running_card_rom = None;
+                let keymap = rom.keymap.as_ref().or(keymap::keymap_for(rom.name));
+                (chip8.load_program(rom.bytes), keymap)
+            }
+            RomEntry::Uploaded(slot) => {
+                let image = self.storage.read(Key::Rom(slot)).unwrap();
+                defmt::info!(
+                    "loading {}, {} bytes",
+                    UPLOAD_NAMES[slot as usize],
+                    image.len()
+                );
+                chip8.quirks = Quirks::default();
+                self.ipf = roms::DEFAULT_IPF;
+                self.running_card_rom = None;
+                (chip8.load_program(image), None)
+            }
+            // Nothing is known about ROMs from the card either, they run
+            // like uploaded ones
+            RomEntry::Card(card_index) => {
+                let name = &self.card_roms[card_index];
+                let image = &mut self.card_buffer[..upload::MAX_IMAGE_BYTES];
+                match self.card.load(name, image) {
+                    Ok(len) => {
+                        defmt::info!("loading {} from the SD card, {} bytes", name.as_str(), len);
+                        chip8.quirks = Quirks::default();
+                        self.ipf = roms::DEFAULT_IPF;
+                        self.running_card_rom = Some(card_index);
+                        (chip8.load_program(&image[..len]), None)
+                    }
+                    Err(e) => {
+                        defmt::error!("loading {} failed: {}", name.as_str(), e);
+                        return None;
+                    }
+                }
+            }
+        };
+        if let Err(error) = loaded {
+            fault::show(Fault::Emulator { error, chip8 });
+        }
+        Some(keymap)
+    }
+
+    // An uploaded ROM replaces whatever was running, with a fresh machine and
+    // no key map since nothing is known about it. The debugger keeps its
+    // breakpoints and whether the game is halted across uploads
+    fn poll_uart(
+        &mut self,
+        now: u32,
+        chip8: &mut impl Mutex<T = Chip8>,
+        game: &mut impl Mutex<T = Game>,
+    ) {
+        let debugger = &mut self.debugger;
+        let mut debugging = false;
+        let upload = self.uart_upload.poll(now, |byte| {
+            debugging = true;
+            chip8.lock(|chip8| debugger.feed(byte, chip8));
+        });
+        if let Some(image) = upload {
+            defmt::info!("running uploaded ROM, {} bytes", image.len());
+            // Kept so it can be picked from the ROM menu after a reset
+            if let Err(e) = store_upload(&mut self.storage, image) {
+                defmt::error!("storing uploaded ROM failed: {}", defmt::Debug2Format(&e));
+            }
+            chip8.lock(|chip8| {
+                *chip8 = Chip8::new();
+                if let Err(error) = chip8.load_program(image) {
+                    fault::show(Fault::Emulator { error, chip8 });
+                }
+            });
+            game.lock(|game| game.keymap = None);
+            self.ipf = roms::DEFAULT_IPF;
+            self.running_card_rom = None;
+            self.rom_menu = None;
+        }
+        // Replies, and stops from the instructions of the last frame
+        self.uart_upload.write(self.debugger.output());
+        self.debugger.clear_output();
+        // Someone at the debugger is using the board too
+        if debugging {
+            self.last_input = now;
+        }
+    }
+
+    // Put the display to sleep and stop the core once there has been no
+    // input for a while. Only a keypad press wakes it up again, and that
+    // press doesn't reach the menus
+    fn sleep_when_idle(
+        &mut self,
+        now: u32,
+        flusher: &mut impl Mutex<T = Flusher>,
+        keypad: &mut impl Mutex<T = Keypad<KeypadRow, KeypadCol>>,
+    ) {
+        let sleep_after_ms = self.settings.sleep_after_s as u32 * 1000;
+        if sleep_after_ms == 0 || now.wrapping_sub(self.last_input) < sleep_after_ms {
+            return;
+        }
+        flusher.lock(|flusher| set_display_on(flusher, false));
+        keypad.lock(|keypad| {
+            keypad.drive_all_rows();
+            power::stop_until_key();
+            keypad.release_rows();
+        });
+        flusher.lock(|flusher| set_display_on(flusher, true));
+        // The millisecond tick stood still while stopped
+        let now = time::millis();
+        self.last_input = now;
+        self.ignore_keys_until = now.wrapping_add(WAKE_KEY_MS);
+    }
+
+    // Render the frame once the previous one has left the back buffer. A
+    // tick that comes while it is in flight has its frame dropped
+    fn draw(
+        &mut self,
+        now: u32,
+        chip8: &mut impl Mutex<T = Chip8>,
+        flusher: &mut impl Mutex<T = Flusher>,
+    ) {
+        let presented = (chip8, flusher).lock(|chip8, flusher| {
+            match flusher.is_busy() {
+                Ok(false) => {}
+                Ok(true) => return false,
+                Err(e) => {
+                    defmt::error!("display flush failed: {}", defmt::Debug2Format(&e));
+                    return false;
+                }
+            }
+            match (&self.menu, &self.rom_menu) {
+                (Some(m), _) => {
+                    let frame = flusher.back_buffer();
+                    display::clear_frame(frame);
+                    m.draw(
+                        &self.settings,
+                        &self.audio,
+                        self.ipf,
+                        &mut Canvas::<ActivePanel>::new(frame),
+                    );
+                }
+                (None, Some(m)) => {
+                    let frame = flusher.back_buffer();
+                    display::clear_frame(frame);
+                    let names = roms::ROMS
+                        .iter()
+                        .map(|rom| rom.name)
+                        .chain(
+                            uploaded_slots(&self.storage).map(|slot| UPLOAD_NAMES[slot as usize]),
+                        )
+                        .chain(self.card_roms.iter().map(|name| name.as_str()));
+                    m.draw(names, &mut Canvas::<ActivePanel>::new(frame));
+                }
+                (None, None) => {
+                    display::render_frame::<ActivePanel>(&chip8.screen, flusher.back_buffer())
+                }
+            }
+            match flusher.present() {
+                Ok(presented) => presented,
+                Err(e) => {
+                    defmt::error!("display flush failed: {}", defmt::Debug2Format(&e));
+                    false
+                }
+            }
+        });
+        if presented {
+            if let Some(rate) = self.fps.frame(now).filter(|_| !self.menu_open()) {
+                defmt::info!("{} fps, {} instructions per frame", rate, self.ipf);
+            }
+        }
+    }
+}
+
+// Storage slots for uploaded ROMs, listed after the bundled ROMs
+const UPLOAD_NAMES: [&str; 4] = ["Uploaded 1", "Uploaded 2", "Uploaded 3", "Uploaded 4"];
+
+fn uploaded_slots<F: Flash>(storage: &Storage<F>) -> impl Iterator<Item = u8> + '_ {
+    (0..UPLOAD_NAMES.len() as u8).filter(|&slot| storage.read(Key::Rom(slot)).is_some())
+}
+
+// Where an index into the ROM menu's list points
+enum RomEntry {
+    Bundled(&'static Rom),
+    Uploaded(u8),
+    // Index into the ROMs listed from the SD card
+    Card(usize),
+}
+
+fn rom_entry<F: Flash>(index: usize, storage: &Storage<F>) -> RomEntry {
+    if let Some(rom) = roms::ROMS.get(index) {
+        return RomEntry::Bundled(rom);
+    }
+    let index = index - roms::ROMS.len();
+    match uploaded_slots(storage).nth(index) {
+        Some(slot) => RomEntry::Uploaded(slot),
+        None => RomEntry::Card(index - uploaded_slots(storage).count()),
+    }
+}
+
+// A ROM uploaded before keeps its slot, a new one takes the first free slot
+// or replaces the first ROM once they are all taken
+fn store_upload<F: Flash>(
+    storage: &mut Storage<F>,
+    image: &[u8],
+) -> Result<(), storage::StorageError<F::Error>> {
+    let slots = 0..UPLOAD_NAMES.len() as u8;
+    let slot = slots
+        .clone()
+        .find(|&slot| storage.read(Key::Rom(slot)) == Some(image))
+        .or_else(|| {
+            slots
+                .clone()
+                .find(|&slot| storage.read(Key::Rom(slot)).is_none())
+        })
+        .unwrap_or(0);
+    storage.write(Key::Rom(slot), image)
+}
+
+#[cfg(feature = "trace")]
+fn traced_cycle(
+    chip8: &mut Chip8,
+    channel: &mut rtt_target::UpChannel,
+) -> Result<(), chip8::Chip8Error> {
+    let record = trace::step(chip8)?;
+    let mut bytes = [0; trace::MAX_RECORD_BYTES];
+    let len = record.encode(&mut bytes);
+    channel.write(&bytes[..len]);
+    Ok(())
+}
+
+fn apply_settings<T: FrameTransport>(
+    flusher: &mut FrameFlusher<ActivePanel, T>,
+    settings: &DisplaySettings,
+) where
+    T::Error: core::fmt::Debug,
+{
+    if let Err(e) = flusher.send_commands(&settings.commands::<ActivePanel>()) {
+        defmt::error!(
+            "applying display settings failed: {}",
+            defmt::Debug2Format(&e)
+        );
+    }
+}
+
+fn set_display_on<T: FrameTransport>(flusher: &mut FrameFlusher<ActivePanel, T>, on: bool)
+where
+    T::Error: core::fmt::Debug,
+{
+    if let Err(e) = flusher.send_commands(&[ActivePanel::display_on_command(on)]) {
+        defmt::error!("display sleep/wake failed: {}", defmt::Debug2Format(&e));
     }
 }
 
 #[exception]
 unsafe fn HardFault(ef: &ExceptionFrame) -> ! {
-    panic!("{:#?}", ef);
+    fault::show(Fault::HardFault(ef));
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    fault::show(Fault::Panic(info));
 }
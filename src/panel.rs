@@ -0,0 +1,212 @@
+// Monochrome panel controllers supported by the firmware. All of them share
+// the page/column addressing commands (0xB0 | page, 0x00 | low nibble,
+// 0x10 | high nibble), so frames are always sent one page at a time and only
+// the geometry and the init sequence differ between controllers.
+use crate::display::I2C_COMMANDS_PER_WRITE;
+use display_interface::{DataFormat, DisplayError, WriteOnlyDataCommand};
+
+pub trait Panel {
+    const WIDTH: usize;
+    const HEIGHT: usize;
+    const PAGES: usize = Self::HEIGHT / 8;
+    // First visible column in the controller's RAM
+    const COLUMN_OFFSET: u8 = 0;
+    // Sent once after reset, ending with display on
+    const INIT_COMMANDS: &'static [u8];
+
+    // The ssd1306 crate's I2C interface takes a few commands per write only
+    fn init<DI: WriteOnlyDataCommand>(interface: &mut DI) -> Result<(), DisplayError> {
+        for chunk in Self::INIT_COMMANDS.chunks(I2C_COMMANDS_PER_WRITE) {
+            interface.send_commands(DataFormat::U8(chunk))?;
+        }
+        Ok(())
+    }
+
+    // Contrast from 0 (dimmest) to 255
+    fn contrast_commands(level: u8) -> [u8; 2] {
+        [0x81, level]
+    }
+
+    // Segment remap and COM scan direction, rotated means turned by 180°
+    fn rotation_commands(rotated: bool) -> [u8; 2] {
+        if rotated {
+            [0xA0, 0xC0]
+        } else {
+            [0xA1, 0xC8]
+        }
+    }
+
+    fn invert_command(inverted: bool) -> u8 {
+        if inverted {
+            0xA7
+        } else {
+            0xA6
+        }
+    }
+
+    fn display_on_command(on: bool) -> u8 {
+        if on {
+            0xAF
+        } else {
+            0xAE
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "panel-ssd1306"), allow(dead_code))]
+pub struct Ssd1306x64;
+
+impl Panel for Ssd1306x64 {
+    const WIDTH: usize = 128;
+    const HEIGHT: usize = 64;
+    const INIT_COMMANDS: &'static [u8] = &[
+        0xAE, // display off
+        0xD5, 0x80, // clock divide ratio / oscillator frequency
+        0xA8, 0x3F, // multiplex ratio 64
+        0xD3, 0x00, // display offset
+        0x40, // start line 0
+        0x8D, 0x14, // charge pump on
+        0x20, 0x02, // page addressing mode
+        0xA1, // segment remap
+        0xC8, // COM scan direction remapped
+        0xDA, 0x12, // COM pins alternative configuration
+        0x81, 0xCF, // contrast
+        0xD9, 0xF1, // pre-charge period
+        0xDB, 0x40, // VCOMH deselect level
+        0xA4, // display follows RAM
+        0xA6, // normal, not inverted
+        0xAF, // display on
+    ];
+}
+
+#[cfg_attr(not(feature = "panel-ssd1306-128x32"), allow(dead_code))]
+pub struct Ssd1306x32;
+
+impl Panel for Ssd1306x32 {
+    const WIDTH: usize = 128;
+    const HEIGHT: usize = 32;
+    const INIT_COMMANDS: &'static [u8] = &[
+        0xAE, // display off
+        0xD5, 0x80, // clock divide ratio / oscillator frequency
+        0xA8, 0x1F, // multiplex ratio 32
+        0xD3, 0x00, // display offset
+        0x40, // start line 0
+        0x8D, 0x14, // charge pump on
+        0x20, 0x02, // page addressing mode
+        0xA1, // segment remap
+        0xC8, // COM scan direction remapped
+        0xDA, 0x02, // COM pins sequential configuration
+        0x81, 0x8F, // contrast
+        0xD9, 0xF1, // pre-charge period
+        0xDB, 0x40, // VCOMH deselect level
+        0xA4, // display follows RAM
+        0xA6, // normal, not inverted
+        0xAF, // display on
+    ];
+}
+
+// SSD1309 is command compatible with the SSD1306 but has no charge pump and
+// wants different timing values
+#[cfg_attr(not(feature = "panel-ssd1309"), allow(dead_code))]
+pub struct Ssd1309;
+
+impl Panel for Ssd1309 {
+    const WIDTH: usize = 128;
+    const HEIGHT: usize = 64;
+    const INIT_COMMANDS: &'static [u8] = &[
+        0xAE, // display off
+        0xD5, 0xA0, // clock divide ratio / oscillator frequency
+        0xA8, 0x3F, // multiplex ratio 64
+        0xD3, 0x00, // display offset
+        0x40, // start line 0
+        0x20, 0x02, // page addressing mode
+        0xA1, // segment remap
+        0xC8, // COM scan direction remapped
+        0xDA, 0x12, // COM pins alternative configuration
+        0x81, 0x6F, // contrast
+        0xD9, 0xD3, // pre-charge period
+        0xDB, 0x20, // VCOMH deselect level
+        0xA4, // display follows RAM
+        0xA6, // normal, not inverted
+        0xAF, // display on
+    ];
+}
+
+// SH1106 has 132 columns of RAM with the 128 visible ones starting at column
+// 2, and only supports page addressing
+#[cfg_attr(not(feature = "panel-sh1106"), allow(dead_code))]
+pub struct Sh1106;
+
+impl Panel for Sh1106 {
+    const WIDTH: usize = 128;
+    const HEIGHT: usize = 64;
+    const COLUMN_OFFSET: u8 = 2;
+    const INIT_COMMANDS: &'static [u8] = &[
+        0xAE, // display off
+        0xD5, 0x80, // clock divide ratio / oscillator frequency
+        0xA8, 0x3F, // multiplex ratio 64
+        0xD3, 0x00, // display offset
+        0x40, // start line 0
+        0xAD, 0x8B, // DC-DC converter on
+        0xA1, // segment remap
+        0xC8, // COM scan direction remapped
+        0xDA, 0x12, // COM pins alternative configuration
+        0x81, 0x80, // contrast
+        0xD9, 0x22, // pre-charge period
+        0xDB, 0x35, // VCOM deselect level
+        0xA4, // display follows RAM
+        0xA6, // normal, not inverted
+        0xAF, // display on
+    ];
+}
+
+// ST7565 LCD controller, SPI only
+#[cfg_attr(not(feature = "panel-st7565"), allow(dead_code))]
+pub struct St7565;
+
+impl Panel for St7565 {
+    const WIDTH: usize = 128;
+    const HEIGHT: usize = 64;
+    const INIT_COMMANDS: &'static [u8] = &[
+        0xE2, // internal reset
+        0xA2, // LCD bias 1/9
+        0xA0, // segment direction normal
+        0xC8, // COM scan direction reversed
+        0x25, // regulator resistor ratio
+        0x81, 0x20, // contrast
+        0x2F, // booster, regulator and follower on
+        0x40, // start line 0
+        0xA4, // display follows RAM
+        0xA6, // normal, not inverted
+        0xAF, // display on
+    ];
+
+    // The electronic volume register is only 6 bits wide
+    fn contrast_commands(level: u8) -> [u8; 2] {
+        [0x81, level >> 2]
+    }
+
+    // Normal orientation here has the segments unmapped, see INIT_COMMANDS
+    fn rotation_commands(rotated: bool) -> [u8; 2] {
+        if rotated {
+            [0xA1, 0xC0]
+        } else {
+            [0xA0, 0xC8]
+        }
+    }
+}
+
+// The panel fitted to the board, selected with the `panel-*` cargo features
+#[cfg(feature = "panel-ssd1306")]
+pub type ActivePanel = Ssd1306x64;
+#[cfg(feature = "panel-ssd1306-128x32")]
+pub type ActivePanel = Ssd1306x32;
+#[cfg(feature = "panel-ssd1309")]
+pub type ActivePanel = Ssd1309;
+#[cfg(feature = "panel-sh1106")]
+pub type ActivePanel = Sh1106;
+#[cfg(feature = "panel-st7565")]
+pub type ActivePanel = St7565;
+
+#[cfg(all(feature = "panel-st7565", feature = "i2c"))]
+compile_error!("the ST7565 panel only has an SPI interface, build with the \"spi\" feature");
@@ -0,0 +1,155 @@
+// Power saving for running off a battery. The idle task sleeps between
+// interrupts, a menu runs the core at half speed, and when the display goes
+// to sleep the core is stopped until a keypad key is pressed.
+use crate::board::KEYPAD_COL_LINES;
+use crate::time;
+use cortex_m::peripheral::NVIC;
+use stm32f4xx_hal::pac::{self, Interrupt, DBGMCU, PWR};
+
+// The keypad wake up only handles columns on EXTICR3 and EXTICR4
+const _: () = {
+    let mut i = 0;
+    while i < KEYPAD_COL_LINES.len() {
+        assert!(KEYPAD_COL_LINES[i] >= 8 && KEYPAD_COL_LINES[i] < 16);
+        i += 1;
+    }
+};
+
+const SYST_CSR_TICKINT: u32 = 1 << 1;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Speed {
+    Full,
+    // The menus only redraw, half the core clock is plenty
+    Half,
+}
+
+impl Speed {
+    // What the core and the timer clocks are divided by
+    pub fn divider(self) -> u32 {
+        match self {
+            Speed::Full => 1,
+            Speed::Half => 2,
+        }
+    }
+}
+
+pub fn init(pwr: PWR, dbgmcu: DBGMCU) {
+    // SAFETY: only the PWR enable bit is touched, nothing else changes RCC
+    // after the clocks are frozen
+    let rcc = unsafe { &*pac::RCC::ptr() };
+    rcc.apb1enr.modify(|_, w| w.pwren().set_bit());
+    // STOP rather than STANDBY, with the regulator in low power mode
+    pwr.cr.modify(|_, w| w.pdds().clear_bit().lpds().set_bit());
+    // Debug builds keep the clocks running for the probe while the core
+    // sleeps, at the cost of most of the saving
+    #[cfg(debug_assertions)]
+    dbgmcu
+        .cr
+        .modify(|_, w| w.dbg_sleep().set_bit().dbg_stop().set_bit());
+    #[cfg(not(debug_assertions))]
+    let _ = dbgmcu;
+}
+
+// Change the AHB prescaler, which the core and both APB buses hang off.
+// APB1 goes from /2 to /1 at the same time so I2C1 and USART2 keep their
+// 48 MHz, see board::clocks. The timers and SPI1 run at `speed`, so the
+// frame tick and SysTick have to be told, see FrameTick::set_divider
+pub fn set_speed(speed: Speed) {
+    // SAFETY: the prescalers are only changed here
+    let rcc = unsafe { &*pac::RCC::ptr() };
+    match speed {
+        Speed::Full => {
+            rcc.cfgr.modify(|_, w| w.ppre1().div2());
+            rcc.cfgr.modify(|_, w| w.hpre().div1());
+        }
+        // AHB first, APB1 is never above its 50 MHz limit
+        Speed::Half => {
+            rcc.cfgr.modify(|_, w| w.hpre().div2());
+            rcc.cfgr.modify(|_, w| w.ppre1().div1());
+        }
+    }
+    time::set_divider(speed.divider());
+}
+
+// Stop every clock until a keypad column goes high, with all keypad rows
+// driven (see Keypad::drive_all_rows). Nothing else wakes it: every other
+// interrupt is disabled and the columns' EXTI lines are taken from the
+// gamepad buttons sharing them. All of that, and the PLL, is put back before
+// this returns
+pub fn stop_until_key() {
+    cortex_m::interrupt::free(|_| {
+        // SAFETY: interrupts are off and every register changed here is put
+        // back before they are turned on again
+        let (dp, mut cp) = unsafe { (pac::Peripherals::steal(), cortex_m::Peripherals::steal()) };
+        let lines = KEYPAD_COL_LINES
+            .iter()
+            .fold(0u32, |mask, &line| mask | 1 << line);
+        // Port A is 0 in the EXTICR fields
+        let (mut cr3_mask, mut cr4_mask) = (0u32, 0u32);
+        for &line in KEYPAD_COL_LINES.iter() {
+            let field = 0xF << ((line % 4) * 4);
+            if line < 12 {
+                cr3_mask |= field;
+            } else {
+                cr4_mask |= field;
+            }
+        }
+
+        let enabled = [
+            cp.NVIC.iser[0].read(),
+            cp.NVIC.iser[1].read(),
+            cp.NVIC.iser[2].read(),
+        ];
+        let exticr3 = dp.SYSCFG.exticr3.read().bits();
+        let exticr4 = dp.SYSCFG.exticr4.read().bits();
+        let imr = dp.EXTI.imr.read().bits();
+        let rtsr = dp.EXTI.rtsr.read().bits();
+        let ftsr = dp.EXTI.ftsr.read().bits();
+        let syst_csr = cp.SYST.csr.read();
+        unsafe {
+            for icer in cp.NVIC.icer.iter().take(enabled.len()) {
+                icer.write(!0);
+            }
+            cp.SYST.csr.write(syst_csr & !SYST_CSR_TICKINT);
+            dp.SYSCFG.exticr3.write(|w| w.bits(exticr3 & !cr3_mask));
+            dp.SYSCFG.exticr4.write(|w| w.bits(exticr4 & !cr4_mask));
+            dp.EXTI.rtsr.write(|w| w.bits(rtsr | lines));
+            dp.EXTI.ftsr.write(|w| w.bits(ftsr & !lines));
+            dp.EXTI.imr.write(|w| w.bits(lines));
+            dp.EXTI.pr.write(|w| w.bits(lines));
+            NVIC::unpend(Interrupt::EXTI9_5);
+            NVIC::unpend(Interrupt::EXTI15_10);
+            NVIC::unmask(Interrupt::EXTI9_5);
+            NVIC::unmask(Interrupt::EXTI15_10);
+        }
+
+        cp.SCB.set_sleepdeep();
+        cortex_m::asm::dsb();
+        cortex_m::asm::wfi();
+        cp.SCB.clear_sleepdeep();
+
+        // The core comes back on HSI, the PLL settings are kept
+        dp.RCC.cr.modify(|_, w| w.hseon().set_bit());
+        while dp.RCC.cr.read().hserdy().bit_is_clear() {}
+        dp.RCC.cr.modify(|_, w| w.pllon().set_bit());
+        while dp.RCC.cr.read().pllrdy().bit_is_clear() {}
+        dp.RCC.cfgr.modify(|_, w| w.sw().pll());
+        while !dp.RCC.cfgr.read().sws().is_pll() {}
+
+        unsafe {
+            dp.EXTI.pr.write(|w| w.bits(lines));
+            dp.EXTI.imr.write(|w| w.bits(imr));
+            dp.EXTI.rtsr.write(|w| w.bits(rtsr));
+            dp.EXTI.ftsr.write(|w| w.bits(ftsr));
+            dp.SYSCFG.exticr3.write(|w| w.bits(exticr3));
+            dp.SYSCFG.exticr4.write(|w| w.bits(exticr4));
+            NVIC::unpend(Interrupt::EXTI9_5);
+            NVIC::unpend(Interrupt::EXTI15_10);
+            cp.SYST.csr.write(syst_csr);
+            for (iser, &bits) in cp.NVIC.iser.iter().zip(enabled.iter()) {
+                iser.write(bits);
+            }
+        }
+    });
+}
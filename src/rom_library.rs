@@ -0,0 +1,135 @@
+// ROMs on a FAT formatted SD card: the *.CH8 files in the root directory of
+// the first partition. A save state for a ROM goes next to it, with the
+// extension swapped for SAV. Generic over the block device, so the same
+// code runs against the card on the board and a disk image on a PC.
+use core::fmt::Write;
+use embedded_sdmmc::{
+    BlockDevice, Mode, ShortFileName, TimeSource, Timestamp, VolumeIdx, VolumeManager,
+};
+use heapless::{String, Vec};
+
+pub const MAX_CARD_ROMS: usize = 32;
+// 8.3 file name
+pub type RomName = String<12>;
+
+// The values are only read through Debug or defmt::Format, for the log
+#[allow(dead_code)]
+#[derive(Debug)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum LibraryError<E: core::fmt::Debug> {
+    Card(embedded_sdmmc::Error<E>),
+    // The file doesn't fit the buffer it is read into
+    TooLarge(u32),
+}
+
+impl<E: core::fmt::Debug> From<embedded_sdmmc::Error<E>> for LibraryError<E> {
+    fn from(error: embedded_sdmmc::Error<E>) -> LibraryError<E> {
+        LibraryError::Card(error)
+    }
+}
+
+// There is no real-time clock, so files are stamped 2024-01-01 00:00
+pub struct NoClock;
+
+impl TimeSource for NoClock {
+    fn get_timestamp(&self) -> Timestamp {
+        Timestamp {
+            year_since_1970: 54,
+            zero_indexed_month: 0,
+            zero_indexed_day: 0,
+            hours: 0,
+            minutes: 0,
+            seconds: 0,
+        }
+    }
+}
+
+pub struct RomLibrary<D: BlockDevice, T: TimeSource> {
+    volumes: VolumeManager<D, T>,
+}
+
+impl<D: BlockDevice, T: TimeSource> RomLibrary<D, T> {
+    pub fn new(device: D, time: T) -> RomLibrary<D, T> {
+        RomLibrary {
+            volumes: VolumeManager::new(device, time),
+        }
+    }
+
+    pub fn device(&mut self) -> &mut D {
+        self.volumes.device()
+    }
+
+    // Names of the ROMs on the card, sorted. Past MAX_CARD_ROMS the rest are
+    // left out
+    pub fn list(&mut self) -> Result<Vec<RomName, MAX_CARD_ROMS>, LibraryError<D::Error>> {
+        let mut volume = self.volumes.open_volume(VolumeIdx(0))?;
+        let mut root = volume.open_root_dir()?;
+        let mut names: Vec<RomName, MAX_CARD_ROMS> = Vec::new();
+        root.iterate_dir(|entry| {
+            let attributes = entry.attributes;
+            if !attributes.is_directory()
+                && !attributes.is_volume()
+                && entry.name.extension() == b"CH8"
+            {
+                let _ = names.push(rom_name(&entry.name));
+            }
+        })?;
+        names.sort_unstable();
+        Ok(names)
+    }
+
+    // Read a ROM into `buffer`, returns its length
+    pub fn load(&mut self, name: &str, buffer: &mut [u8]) -> Result<usize, LibraryError<D::Error>> {
+        self.read_file(name, buffer)
+    }
+
+    pub fn save_state(&mut self, rom: &str, state: &[u8]) -> Result<(), LibraryError<D::Error>> {
+        let name = state_name(rom);
+        let mut volume = self.volumes.open_volume(VolumeIdx(0))?;
+        let mut root = volume.open_root_dir()?;
+        let mut file = root.open_file_in_dir(name.as_str(), Mode::ReadWriteCreateOrTruncate)?;
+        file.write(state)?;
+        file.close()?;
+        Ok(())
+    }
+
+    // Read the save state for `rom` into `buffer`, returns its length
+    pub fn load_state(
+        &mut self,
+        rom: &str,
+        buffer: &mut [u8],
+    ) -> Result<usize, LibraryError<D::Error>> {
+        self.read_file(&state_name(rom), buffer)
+    }
+
+    fn read_file(
+        &mut self,
+        name: &str,
+        buffer: &mut [u8],
+    ) -> Result<usize, LibraryError<D::Error>> {
+        let mut volume = self.volumes.open_volume(VolumeIdx(0))?;
+        let mut root = volume.open_root_dir()?;
+        let mut file = root.open_file_in_dir(name, Mode::ReadOnly)?;
+        if file.length() as usize > buffer.len() {
+            return Err(LibraryError::TooLarge(file.length()));
+        }
+        let mut len = 0;
+        while !file.is_eof() {
+            len += file.read(&mut buffer[len..])?;
+        }
+        Ok(len)
+    }
+}
+
+fn rom_name(name: &ShortFileName) -> RomName {
+    let mut text = RomName::new();
+    let _ = write!(text, "{}", name);
+    text
+}
+
+fn state_name(rom: &str) -> RomName {
+    let base = rom.split('.').next().unwrap_or(rom);
+    let mut name = RomName::new();
+    let _ = write!(name, "{}.SAV", base);
+    name
+}
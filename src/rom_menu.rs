@@ -0,0 +1,82 @@
+// Menu listing the bundled and stored ROMs, shown at boot to pick the game
+// to run.
+use crate::chip8::Quirks;
+use crate::display::Canvas;
+use crate::input::MenuInput;
+use crate::keymap::KeyMap;
+use crate::panel::Panel;
+use embedded_graphics::{
+    mono_font::{ascii::FONT_5X8, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Baseline, Text},
+};
+
+// A bundled ROM with the metadata build.rs read from its .meta file
+pub struct Rom {
+    // File name without the .ch8 extension
+    pub name: &'static str,
+    pub bytes: &'static [u8],
+    // SHA-1 of `bytes`, the key ROM databases use
+    pub sha1: [u8; 20],
+    pub quirks: Quirks,
+    // Overrides the key map looked up by name
+    pub keymap: Option<KeyMap>,
+    // Instructions to run per 60 Hz frame
+    pub ipf: u16,
+}
+
+// 128 pixels of 5 pixel wide characters, less the selection marker
+const NAME_CHARS: usize = 24;
+
+pub struct RomMenu {
+    selected: usize,
+}
+
+impl RomMenu {
+    pub const fn new() -> RomMenu {
+        RomMenu { selected: 0 }
+    }
+
+    // Returns the index of the ROM to launch once one is picked, out of
+    // `count` listed
+    pub fn handle(&mut self, count: usize, input: MenuInput) -> Option<usize> {
+        if count == 0 {
+            return None;
+        }
+        match input {
+            MenuInput::Up => self.selected = (self.selected + count - 1) % count,
+            MenuInput::Down => self.selected = (self.selected + 1) % count,
+            MenuInput::Select | MenuInput::Right => return Some(self.selected),
+            MenuInput::Left | MenuInput::Back => {}
+        }
+        None
+    }
+
+    pub fn draw<'a, P: Panel>(&self, names: impl Iterator<Item = &'a str>, canvas: &mut Canvas<P>) {
+        const LINE_HEIGHT: usize = 8;
+        let style = MonoTextStyle::new(&FONT_5X8, BinaryColor::On);
+        let _ = Text::with_baseline("ROMS", Point::zero(), style, Baseline::Top).draw(canvas);
+
+        // Keep the selected ROM in view
+        let visible = P::HEIGHT / LINE_HEIGHT - 1;
+        let first = self.selected.saturating_sub(visible - 1);
+        for (row, (index, name)) in names.enumerate().skip(first).take(visible).enumerate() {
+            let y = ((row + 1) * LINE_HEIGHT) as i32;
+            if index == self.selected {
+                let _ =
+                    Text::with_baseline(">", Point::new(0, y), style, Baseline::Top).draw(canvas);
+            }
+            let name = truncate(name, NAME_CHARS);
+            let _ = Text::with_baseline(name, Point::new(5, y), style, Baseline::Top).draw(canvas);
+        }
+    }
+}
+
+// At most `chars` characters of `name`, cut on a character boundary
+fn truncate(name: &str, chars: usize) -> &str {
+    match name.char_indices().nth(chars) {
+        Some((end, _)) => &name[..end],
+        None => name,
+    }
+}
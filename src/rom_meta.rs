@@ -0,0 +1,107 @@
+// The .meta files next to ROMs, read on the host: by build.rs for the ROMs
+// bundled from roms/, and by the host tools that run a ROM like the board
+// would. Not part of the firmware.
+use std::fs;
+use std::path::Path;
+
+// Instructions per frame for ROMs without a .meta file, or without an ipf
+// in it
+pub const DEFAULT_IPF: u16 = 10;
+// In the order of Quirks::to_bits
+pub const QUIRK_NAMES: [&str; 4] = ["shift_vy", "load_store_increment_i", "jump_vx", "vf_reset"];
+
+// Settings for one ROM, read from the optional <name>.meta file next to it:
+//
+//   # comment
+//   ipf = 15
+//   quirks = shift_vy, vf_reset
+//   keymap = 4 7 5 6 4 -       (up down left right a b, - for none)
+pub struct RomMeta {
+    pub ipf: u16,
+    pub quirks: Vec<&'static str>,
+    pub keymap: Option<[Option<u8>; 6]>,
+}
+
+impl RomMeta {
+    // The quirks as Quirks::to_bits has them
+    pub fn quirk_bits(&self) -> u8 {
+        QUIRK_NAMES
+            .iter()
+            .enumerate()
+            .filter(|(_, name)| self.quirks.contains(name))
+            .fold(0, |bits, (bit, _)| bits | 1 << bit)
+    }
+}
+
+// A missing file gives the defaults
+pub fn parse_meta(path: &Path) -> Result<RomMeta, String> {
+    let mut meta = RomMeta {
+        ipf: DEFAULT_IPF,
+        quirks: Vec::new(),
+        keymap: None,
+    };
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(meta),
+        Err(e) => return Err(e.to_string()),
+    };
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let at = |message: String| format!("line {}: {}", number + 1, message);
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| at(format!("expected `key = value`, got `{}`", line)))?;
+        let value = value.trim();
+        match key.trim() {
+            "ipf" => {
+                meta.ipf = match value.parse() {
+                    Ok(ipf) if ipf > 0 => ipf,
+                    _ => {
+                        return Err(at(format!(
+                            "ipf must be a positive number, got `{}`",
+                            value
+                        )))
+                    }
+                }
+            }
+            "quirks" => {
+                for quirk in value.split([',', ' ']).filter(|q| !q.is_empty()) {
+                    let known =
+                        QUIRK_NAMES
+                            .iter()
+                            .find(|&&name| name == quirk)
+                            .ok_or_else(|| {
+                                at(format!(
+                                    "unknown quirk `{}`, expected one of {}",
+                                    quirk,
+                                    QUIRK_NAMES.join(", ")
+                                ))
+                            })?;
+                    meta.quirks.push(known);
+                }
+            }
+            "keymap" => {
+                let keys: Vec<&str> = value.split_whitespace().collect();
+                if keys.len() != 6 {
+                    return Err(at(format!("keymap needs 6 keys, got {}", keys.len())));
+                }
+                let mut keymap = [None; 6];
+                for (slot, key) in keymap.iter_mut().zip(keys) {
+                    if key != "-" {
+                        let key = u8::from_str_radix(key, 16)
+                            .ok()
+                            .filter(|&k| k < 16)
+                            .ok_or_else(|| at(format!("`{}` is not a hex key", key)))?;
+                        *slot = Some(key);
+                    }
+                }
+                meta.keymap = Some(keymap);
+            }
+            other => return Err(at(format!("unknown setting `{}`", other))),
+        }
+    }
+    Ok(meta)
+}
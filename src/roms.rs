@@ -0,0 +1,6 @@
+// ROMs from the roms/ directory, bundled into flash by build.rs
+use crate::chip8::Quirks;
+use crate::keymap::KeyMap;
+use crate::rom_menu::Rom;
+
+include!(concat!(env!("OUT_DIR"), "/roms.rs"));
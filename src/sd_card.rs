@@ -0,0 +1,22 @@
+// microSD card on SPI1, read through the ROM library. Nothing is sent to the
+// card until the ROM menu opens and lists it, so a missing card shows up as
+// an error from that listing rather than at boot. The card is brought up
+// again each time the ROM menu opens, so it can be swapped without a reset.
+use crate::board::SdPins;
+use crate::rom_library::{NoClock, RomLibrary};
+use crate::spi_bus::{self, Bus};
+use embedded_sdmmc::SdCard;
+use stm32f4xx_hal::pac::TIM4;
+use stm32f4xx_hal::timer::DelayUs;
+use stm32f4xx_hal::{prelude::*, rcc::Clocks};
+
+// Cards have to be brought up at 400 kHz or less. The clock stays there, a
+// ROM still loads in about 100 ms
+const SPI_FREQ_KHZ: u32 = 400;
+
+pub type CardLibrary = RomLibrary<SdCard<spi_bus::Device, DelayUs<TIM4>>, NoClock>;
+
+pub fn library(bus: &'static Bus, pins: SdPins, tim4: TIM4, clocks: &Clocks) -> CardLibrary {
+    let device = spi_bus::device(bus, pins.cs.erase(), SPI_FREQ_KHZ.kHz(), clocks);
+    RomLibrary::new(SdCard::new(device, tim4.delay_us(clocks)), NoClock)
+}
@@ -0,0 +1,85 @@
+// Key events over a byte stream, as sent to the USB serial port. An event is
+// two ASCII bytes: '+' for key down or '-' for key up, then the key as a hex
+// digit. Anything else between events is skipped, so the stream can also be
+// typed by hand into a terminal.
+use crate::chip8::{InputSource, KEY_COUNT};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeyEvent {
+    pub key: u8,
+    pub pressed: bool,
+}
+
+// The sending side, for tools/keysend
+#[allow(dead_code)]
+pub fn encode(event: KeyEvent) -> [u8; 2] {
+    const HEX: &[u8; 16] = b"0123456789ABCDEF";
+    let sign = if event.pressed { b'+' } else { b'-' };
+    [sign, HEX[event.key as usize & 0xF]]
+}
+
+pub struct KeyEventParser {
+    // Direction of the event being parsed, once its sign has been seen
+    pressed: Option<bool>,
+}
+
+impl KeyEventParser {
+    pub const fn new() -> KeyEventParser {
+        KeyEventParser { pressed: None }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        match byte {
+            b'+' => self.pressed = Some(true),
+            b'-' => self.pressed = Some(false),
+            _ => {
+                let pressed = self.pressed.take()?;
+                let key = (byte as char).to_digit(16)?;
+                return Some(KeyEvent {
+                    key: key as u8,
+                    pressed,
+                });
+            }
+        }
+        None
+    }
+}
+
+// The keys held down on the far end of a stream of key events
+pub struct RemoteKeys {
+    parser: KeyEventParser,
+    held: [u8; KEY_COUNT],
+}
+
+impl RemoteKeys {
+    pub const fn new() -> RemoteKeys {
+        RemoteKeys {
+            parser: KeyEventParser::new(),
+            held: [0; KEY_COUNT],
+        }
+    }
+
+    // Take in received bytes, returns true if any key went down
+    pub fn feed(&mut self, bytes: &[u8]) -> bool {
+        let mut pressed = false;
+        for &byte in bytes {
+            if let Some(event) = self.parser.feed(byte) {
+                self.held[event.key as usize] = event.pressed as u8;
+                pressed |= event.pressed;
+            }
+        }
+        pressed
+    }
+
+    // For when the sender goes away, its key up events will never come
+    pub fn release_all(&mut self) {
+        self.parser = KeyEventParser::new();
+        self.held = [0; KEY_COUNT];
+    }
+}
+
+impl InputSource for RemoteKeys {
+    fn read_keys(&mut self, keys: &mut [u8; KEY_COUNT]) {
+        *keys = self.held;
+    }
+}
@@ -0,0 +1,278 @@
+// User adjustable display settings and the menu that edits them, along
+// with the buzzer and the speed of the running game.
+use crate::audio::AudioSettings;
+use crate::display::Canvas;
+use crate::input::MenuInput;
+use crate::panel::Panel;
+use core::fmt::Write;
+use embedded_graphics::{
+    mono_font::{ascii::FONT_5X8, MonoTextStyle},
+    pixelcolor::BinaryColor,
+    prelude::*,
+    text::{Baseline, Text},
+};
+use heapless::String;
+
+// Stored form: magic, contrast, flags, sleep timeout (LE), checksum
+pub const SETTINGS_BYTES: usize = 6;
+const SETTINGS_MAGIC: u8 = 0xD5;
+const FLAG_INVERTED: u8 = 0x01;
+const FLAG_ROTATED: u8 = 0x02;
+
+const CONTRAST_STEP: u8 = 0x20;
+// Choices for the sleep timeout in seconds, 0 means never
+const SLEEP_CHOICES: [u16; 6] = [0, 15, 30, 60, 120, 300];
+// Choices for the beep in Hz
+const TONE_CHOICES: [u16; 7] = [220, 330, 440, 554, 660, 880, 1320];
+const VOLUME_STEP: u8 = 10;
+// Choices for the instructions per frame. A ROM's own speed may fall between
+// them, stepping moves to the next choice either way
+const IPF_CHOICES: [u16; 12] = [1, 2, 5, 8, 10, 12, 15, 20, 30, 50, 100, 200];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DisplaySettings {
+    pub contrast: u8,
+    pub inverted: bool,
+    // Turned by 180°, for panels mounted upside down
+    pub rotated: bool,
+    // Seconds without input before the panel is put to sleep, 0 means never
+    pub sleep_after_s: u16,
+}
+
+impl Default for DisplaySettings {
+    fn default() -> DisplaySettings {
+        DisplaySettings {
+            contrast: 0x7F,
+            inverted: false,
+            rotated: false,
+            sleep_after_s: 60,
+        }
+    }
+}
+
+impl DisplaySettings {
+    pub fn to_bytes(self) -> [u8; SETTINGS_BYTES] {
+        let mut flags = 0;
+        if self.inverted {
+            flags |= FLAG_INVERTED;
+        }
+        if self.rotated {
+            flags |= FLAG_ROTATED;
+        }
+        let sleep = self.sleep_after_s.to_le_bytes();
+        let mut bytes = [SETTINGS_MAGIC, self.contrast, flags, sleep[0], sleep[1], 0];
+        bytes[SETTINGS_BYTES - 1] = checksum(&bytes[..SETTINGS_BYTES - 1]);
+        bytes
+    }
+
+    // None if the bytes are not valid stored settings (erased flash, other data)
+    pub fn from_bytes(bytes: &[u8]) -> Option<DisplaySettings> {
+        if bytes.len() < SETTINGS_BYTES || bytes[0] != SETTINGS_MAGIC {
+            return None;
+        }
+        if checksum(&bytes[..SETTINGS_BYTES - 1]) != bytes[SETTINGS_BYTES - 1] {
+            return None;
+        }
+        Some(DisplaySettings {
+            contrast: bytes[1],
+            inverted: bytes[2] & FLAG_INVERTED != 0,
+            rotated: bytes[2] & FLAG_ROTATED != 0,
+            sleep_after_s: u16::from_le_bytes([bytes[3], bytes[4]]),
+        })
+    }
+
+    // Panel commands that put these settings into effect
+    pub fn commands<P: Panel>(&self) -> [u8; 5] {
+        let contrast = P::contrast_commands(self.contrast);
+        let rotation = P::rotation_commands(self.rotated);
+        [
+            contrast[0],
+            contrast[1],
+            P::invert_command(self.inverted),
+            rotation[0],
+            rotation[1],
+        ]
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) ^ 0xFF
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum MenuAction {
+    None,
+    // The settings changed and should be applied to the panel
+    Changed,
+    // Menu closed, the settings should be saved
+    Close,
+    // As Close, and the running game should be saved or restored
+    SaveState,
+    LoadState,
+    // As Close, and the ROM menu should be opened to pick another game
+    ChangeRom,
+}
+
+const ITEMS: [&str; 11] = [
+    "Contrast",
+    "Invert",
+    "Rotate 180",
+    "Sleep",
+    "Tone",
+    "Volume",
+    "Speed",
+    "Save state",
+    "Load state",
+    "Change ROM",
+    "Exit",
+];
+const ITEM_TONE: usize = 4;
+const ITEM_VOLUME: usize = 5;
+const ITEM_SPEED: usize = 6;
+const ITEM_SAVE_STATE: usize = 7;
+const ITEM_LOAD_STATE: usize = 8;
+const ITEM_CHANGE_ROM: usize = 9;
+const ITEM_EXIT: usize = ITEMS.len() - 1;
+
+pub struct SettingsMenu {
+    selected: usize,
+}
+
+impl SettingsMenu {
+    pub const fn new() -> SettingsMenu {
+        SettingsMenu { selected: 0 }
+    }
+
+    // `ipf` is the running game's instructions per frame
+    pub fn handle(
+        &mut self,
+        settings: &mut DisplaySettings,
+        audio: &mut AudioSettings,
+        ipf: &mut u16,
+        input: MenuInput,
+    ) -> MenuAction {
+        match input {
+            MenuInput::Up => {
+                self.selected = (self.selected + ITEMS.len() - 1) % ITEMS.len();
+                MenuAction::None
+            }
+            MenuInput::Down => {
+                self.selected = (self.selected + 1) % ITEMS.len();
+                MenuAction::None
+            }
+            MenuInput::Back => MenuAction::Close,
+            MenuInput::Select if self.selected == ITEM_EXIT => MenuAction::Close,
+            MenuInput::Select if self.selected == ITEM_SAVE_STATE => MenuAction::SaveState,
+            MenuInput::Select if self.selected == ITEM_LOAD_STATE => MenuAction::LoadState,
+            MenuInput::Select if self.selected == ITEM_CHANGE_ROM => MenuAction::ChangeRom,
+            // Takes effect from the next frame, nothing to apply
+            MenuInput::Select | MenuInput::Right if self.selected == ITEM_SPEED => {
+                *ipf = step_choice(&IPF_CHOICES, *ipf, true);
+                MenuAction::None
+            }
+            MenuInput::Left if self.selected == ITEM_SPEED => {
+                *ipf = step_choice(&IPF_CHOICES, *ipf, false);
+                MenuAction::None
+            }
+            // Select steps the value forward so the menu works with one button
+            MenuInput::Select | MenuInput::Right => self.adjust(settings, audio, true),
+            MenuInput::Left => self.adjust(settings, audio, false),
+        }
+    }
+
+    fn adjust(
+        &mut self,
+        settings: &mut DisplaySettings,
+        audio: &mut AudioSettings,
+        up: bool,
+    ) -> MenuAction {
+        match self.selected {
+            0 => {
+                settings.contrast = match (up, settings.contrast) {
+                    (true, 0xFF) => 0x00,
+                    (true, c) => c.saturating_add(CONTRAST_STEP),
+                    (false, c) => c.saturating_sub(CONTRAST_STEP),
+                };
+            }
+            1 => settings.inverted = !settings.inverted,
+            2 => settings.rotated = !settings.rotated,
+            3 => {
+                let current = SLEEP_CHOICES
+                    .iter()
+                    .position(|&s| s == settings.sleep_after_s)
+                    .unwrap_or(0);
+                let next = if up {
+                    (current + 1) % SLEEP_CHOICES.len()
+                } else {
+                    (current + SLEEP_CHOICES.len() - 1) % SLEEP_CHOICES.len()
+                };
+                settings.sleep_after_s = SLEEP_CHOICES[next];
+            }
+            ITEM_TONE => audio.tone_hz = step_choice(&TONE_CHOICES, audio.tone_hz, up),
+            ITEM_VOLUME => {
+                audio.volume = match (up, audio.volume) {
+                    (true, 100..) => 0,
+                    (true, v) => (v + VOLUME_STEP).min(100),
+                    (false, v) => v.saturating_sub(VOLUME_STEP),
+                };
+            }
+            _ => return MenuAction::None,
+        }
+        MenuAction::Changed
+    }
+
+    pub fn draw<P: Panel>(
+        &self,
+        settings: &DisplaySettings,
+        audio: &AudioSettings,
+        ipf: u16,
+        canvas: &mut Canvas<P>,
+    ) {
+        const LINE_HEIGHT: usize = 8;
+        let style = MonoTextStyle::new(&FONT_5X8, BinaryColor::On);
+        let _ = Text::with_baseline("SETTINGS", Point::zero(), style, Baseline::Top).draw(canvas);
+
+        // Keep the selected item in view on short panels
+        let visible = P::HEIGHT / LINE_HEIGHT - 1;
+        let first = self.selected.saturating_sub(visible - 1);
+        for (row, item) in (first..ITEMS.len()).take(visible).enumerate() {
+            let mut value: String<8> = String::new();
+            let _ = match item {
+                0 => write!(value, "{}", settings.contrast),
+                1 => write!(value, "{}", on_off(settings.inverted)),
+                2 => write!(value, "{}", on_off(settings.rotated)),
+                3 if settings.sleep_after_s == 0 => write!(value, "never"),
+                3 => write!(value, "{}s", settings.sleep_after_s),
+                ITEM_TONE => write!(value, "{}Hz", audio.tone_hz),
+                ITEM_VOLUME => write!(value, "{}%", audio.volume),
+                ITEM_SPEED => write!(value, "{}/f", ipf),
+                _ => Ok(()),
+            };
+            let marker = if item == self.selected { '>' } else { ' ' };
+            let mut line: String<24> = String::new();
+            let _ = write!(line, "{}{:<11}{}", marker, ITEMS[item], value);
+            let y = ((row + 1) * LINE_HEIGHT) as i32;
+            let _ = Text::with_baseline(&line, Point::new(0, y), style, Baseline::Top).draw(canvas);
+        }
+    }
+}
+
+// Wraps around at either end, like the other settings. A value between two
+// choices moves to the next one either way
+fn step_choice(choices: &[u16], value: u16, up: bool) -> u16 {
+    if up {
+        let next = choices.iter().find(|&&choice| choice > value);
+        *next.unwrap_or(&choices[0])
+    } else {
+        let next = choices.iter().rev().find(|&&choice| choice < value);
+        *next.unwrap_or(&choices[choices.len() - 1])
+    }
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "on"
+    } else {
+        "off"
+    }
+}
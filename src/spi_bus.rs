@@ -0,0 +1,75 @@
+// SPI1, shared by the microSD card and the SPI display. Both are driven from
+// the cpu task, so the bus is never contended and a busy bus is an error.
+// Each device runs at its own clock, which is set as its CS line goes low
+use crate::board::Spi1Pins;
+use core::convert::Infallible;
+use embedded_hal::digital::{ErrorType, OutputPin};
+use embedded_hal_bus::spi::{AtomicDevice, NoDelay};
+use embedded_hal_bus::util::AtomicCell;
+use stm32f4xx_hal::gpio::{ErasedPin, Output, PushPull};
+use stm32f4xx_hal::pac::SPI1;
+use stm32f4xx_hal::spi::{Mode, Phase, Polarity, Spi};
+use stm32f4xx_hal::time::Hertz;
+use stm32f4xx_hal::{prelude::*, rcc::Clocks};
+
+// Both the card and the panels use SPI mode 0
+pub const MODE: Mode = Mode {
+    polarity: Polarity::IdleLow,
+    phase: Phase::CaptureOnFirstTransition,
+};
+
+pub type Bus = AtomicCell<Spi<SPI1>>;
+pub type Device = AtomicDevice<'static, Spi<SPI1>, ChipSelect, NoDelay>;
+
+pub fn new(spi1: SPI1, pins: Spi1Pins, clocks: &Clocks) -> Bus {
+    AtomicCell::new(spi1.spi((pins.sck, pins.miso, pins.mosi), MODE, 400.kHz(), clocks))
+}
+
+pub fn device(
+    bus: &'static Bus,
+    cs: ErasedPin<Output<PushPull>>,
+    freq: Hertz,
+    clocks: &Clocks,
+) -> Device {
+    let cs = ChipSelect {
+        pin: cs,
+        baud_rate: baud_rate_bits(clocks.pclk2(), freq),
+    };
+    AtomicDevice::new_no_delay(bus, cs).unwrap()
+}
+
+// The CR1 BR divider for the fastest clock at or below `freq`, the same
+// rounding as the HAL uses when the bus is set up
+fn baud_rate_bits(pclk: Hertz, freq: Hertz) -> u8 {
+    (0..7)
+        .find(|&bits| pclk.raw() >> (bits + 1) <= freq.raw())
+        .unwrap_or(7)
+}
+
+pub struct ChipSelect {
+    pin: ErasedPin<Output<PushPull>>,
+    baud_rate: u8,
+}
+
+impl ErrorType for ChipSelect {
+    type Error = Infallible;
+}
+
+impl OutputPin for ChipSelect {
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        // SAFETY: AtomicDevice only selects a device while it holds the bus,
+        // after the previous transaction has been flushed, so nothing else
+        // touches SPI1 and no transfer is running
+        let spi = unsafe { &*SPI1::ptr() };
+        spi.cr1.modify(|_, w| w.spe().clear_bit());
+        spi.cr1
+            .modify(|_, w| w.br().bits(self.baud_rate).spe().set_bit());
+        self.pin.set_low();
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.pin.set_high();
+        Ok(())
+    }
+}
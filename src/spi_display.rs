@@ -0,0 +1,60 @@
+// Panel on SPI1 with DC/CS/RST lines, sharing the bus with the SD card. At
+// 10 MHz a whole frame takes well under a millisecond, so frames are written
+// blocking
+use crate::board::DisplayPins;
+use crate::display::{self, FrameTransport};
+use crate::panel::Panel;
+use crate::spi_bus::{self, Bus};
+use display_interface::{DataFormat, DisplayError, WriteOnlyDataCommand};
+use ssd1306::prelude::SPIInterface;
+use stm32f4xx_hal::gpio::{gpiob, Output, PushPull};
+use stm32f4xx_hal::{prelude::*, rcc::Clocks};
+
+pub type SpiDisplayInterface = SPIInterface<spi_bus::Device, gpiob::PB6<Output<PushPull>>>;
+
+pub struct SpiTransport {
+    interface: SpiDisplayInterface,
+}
+
+// Blocking interface with the panel reset, used for panel init and the fault
+// screen
+pub fn interface(bus: &'static Bus, pins: DisplayPins, clocks: &Clocks) -> SpiDisplayInterface {
+    let device = spi_bus::device(bus, pins.cs.erase(), 10.MHz(), clocks);
+
+    // Hardware reset pulse before the init sequence is sent. SysTick is taken
+    // by the millisecond tick, so this busy-waits on core cycles
+    let cycles_per_ms = clocks.sysclk().raw() / 1000;
+    let mut rst = pins.rst;
+    rst.set_low();
+    cortex_m::asm::delay(cycles_per_ms);
+    rst.set_high();
+    cortex_m::asm::delay(10 * cycles_per_ms);
+    SPIInterface::new(device, pins.dc)
+}
+
+impl SpiTransport {
+    pub fn new<P: Panel>(bus: &'static Bus, pins: DisplayPins, clocks: &Clocks) -> SpiTransport {
+        let mut interface = interface(bus, pins, clocks);
+        P::init(&mut interface).unwrap();
+        SpiTransport { interface }
+    }
+}
+
+impl FrameTransport for SpiTransport {
+    type Error = DisplayError;
+
+    fn start_page(&mut self, page: u8, column: u8, data: &'static [u8]) -> Result<(), Self::Error> {
+        let commands = display::page_commands(page, column);
+        self.interface.send_commands(DataFormat::U8(&commands))?;
+        // The DC line replaces the I2C data control byte at the start of the page
+        self.interface.send_data(DataFormat::U8(&data[1..]))
+    }
+
+    fn is_busy(&mut self) -> bool {
+        false
+    }
+
+    fn send_commands(&mut self, commands: &[u8]) -> Result<(), Self::Error> {
+        self.interface.send_commands(DataFormat::U8(commands))
+    }
+}
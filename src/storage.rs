@@ -0,0 +1,334 @@
+// Settings, uploaded ROMs and save states kept in a few flash sectors.
+// Records are appended to the active sector and the last record for a key
+// wins. Once the active sector is full the live records are copied to the
+// next sector along, so the erases go round all of the sectors in turn.
+//
+// Losing power part way through leaves the previous contents in place: a
+// record's data is programmed before its header, whose CRC covers both, and
+// a copied sector only counts once its header is marked committed.
+use crate::crc::{crc16_update, CRC16_INIT};
+use heapless::Vec;
+
+// SECTOR_COUNT sectors of SECTOR_SIZE bytes, at least two. Programming can
+// only turn 1 bits into 0 bits, erasing a sector sets every byte to 0xFF.
+pub trait Flash {
+    type Error;
+    const SECTOR_SIZE: usize;
+    const SECTOR_COUNT: usize;
+
+    // All of the sectors, back to back
+    fn read(&self) -> &[u8];
+    fn erase(&mut self, sector: usize) -> Result<(), Self::Error>;
+    // `offset` counts from the start of the first sector
+    fn program(&mut self, offset: usize, bytes: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Key {
+    Settings,
+    Rom(u8),
+    SaveState(u8),
+    AudioSettings,
+}
+
+impl Key {
+    fn to_bytes(self) -> [u8; 2] {
+        match self {
+            Key::Settings => [1, 0],
+            Key::Rom(slot) => [2, slot],
+            Key::SaveState(slot) => [3, slot],
+            Key::AudioSettings => [4, 0],
+        }
+    }
+
+    fn from_bytes(bytes: [u8; 2]) -> Option<Key> {
+        match bytes {
+            [1, 0] => Some(Key::Settings),
+            [2, slot] => Some(Key::Rom(slot)),
+            [3, slot] => Some(Key::SaveState(slot)),
+            [4, 0] => Some(Key::AudioSettings),
+            _ => None,
+        }
+    }
+}
+
+// The values are only read through Debug, for the log
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum StorageError<E> {
+    Flash(E),
+    // Records hold 1 to MAX_RECORD_BYTES bytes
+    BadLength(usize),
+    // The live records and the new one don't fit into one sector
+    Full,
+    // DIRECTORY_LEN different keys are stored already
+    DirectoryFull,
+}
+
+pub const MAX_RECORD_BYTES: usize = u16::MAX as usize;
+pub const DIRECTORY_LEN: usize = 32;
+
+// Sector header: magic, sequence number (LE), then a word programmed to 0
+// once the sector holds a complete set of records
+const SECTOR_MAGIC: [u8; 4] = *b"C8FS";
+const SECTOR_HEADER_BYTES: usize = 12;
+const COMMIT_OFFSET: usize = 8;
+// Record header: key, data length (LE), CRC over the key, length and data
+// (LE) and two spare bytes. Records start on a word boundary
+const RECORD_HEADER_BYTES: usize = 8;
+const ALIGN: usize = 4;
+const ERASED: u8 = 0xFF;
+
+fn record_bytes(len: usize) -> usize {
+    RECORD_HEADER_BYTES + len.div_ceil(ALIGN) * ALIGN
+}
+
+fn record_header(key: Key, data: &[u8]) -> [u8; RECORD_HEADER_BYTES] {
+    let key = key.to_bytes();
+    let len = (data.len() as u16).to_le_bytes();
+    let fields = [key[0], key[1], len[0], len[1]];
+    let crc = crc16_update(crc16_update(CRC16_INIT, &fields), data).to_le_bytes();
+    [
+        key[0], key[1], len[0], len[1], crc[0], crc[1], ERASED, ERASED,
+    ]
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    key: Key,
+    // Start of the record's data, from the start of the first sector
+    offset: usize,
+    len: usize,
+}
+
+pub struct Storage<F: Flash> {
+    flash: F,
+    active: usize,
+    sequence: u32,
+    // Where the next record goes in the active sector
+    free: usize,
+    // The active sector ends in a torn record, nothing can be appended to
+    // it until the next copy
+    dirty: bool,
+    directory: Vec<Entry, DIRECTORY_LEN>,
+}
+
+impl<F: Flash> Storage<F> {
+    // Blank or unreadable flash is formatted, which loses anything on it
+    pub fn mount(flash: F) -> Result<Storage<F>, StorageError<F::Error>> {
+        let mut storage = Storage {
+            flash,
+            active: 0,
+            sequence: 0,
+            free: SECTOR_HEADER_BYTES,
+            dirty: false,
+            directory: Vec::new(),
+        };
+        let newest = (0..F::SECTOR_COUNT)
+            .filter_map(|sector| Some((storage.committed_sequence(sector)?, sector)))
+            .max();
+        match newest {
+            Some((sequence, sector)) => {
+                storage.active = sector;
+                storage.sequence = sequence;
+                storage.scan();
+            }
+            None => {
+                storage.flash.erase(0).map_err(StorageError::Flash)?;
+                storage.program_sector_header(0, 0, true)?;
+            }
+        }
+        Ok(storage)
+    }
+
+    pub fn read(&self, key: Key) -> Option<&[u8]> {
+        let entry = self.directory.iter().find(|entry| entry.key == key)?;
+        Some(&self.flash.read()[entry.offset..entry.offset + entry.len])
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = Key> + '_ {
+        self.directory.iter().map(|entry| entry.key)
+    }
+
+    // Writing what is stored already leaves the flash alone
+    pub fn write(&mut self, key: Key, data: &[u8]) -> Result<(), StorageError<F::Error>> {
+        if data.is_empty() || data.len() > MAX_RECORD_BYTES {
+            return Err(StorageError::BadLength(data.len()));
+        }
+        if self.read(key) == Some(data) {
+            return Ok(());
+        }
+        if self.directory.is_full() && !self.keys().any(|k| k == key) {
+            return Err(StorageError::DirectoryFull);
+        }
+        if self.dirty || self.free + record_bytes(data.len()) > F::SECTOR_SIZE {
+            return self.copy_to_next_sector(key, data);
+        }
+        let offset = self.active * F::SECTOR_SIZE + self.free;
+        // Until the record is complete the sector can't take another one
+        self.dirty = true;
+        self.program_record(offset, key, data)?;
+        self.dirty = false;
+        self.free += record_bytes(data.len());
+        self.directory.retain(|entry| entry.key != key);
+        let _ = self.directory.push(Entry {
+            key,
+            offset: offset + RECORD_HEADER_BYTES,
+            len: data.len(),
+        });
+        Ok(())
+    }
+
+    fn committed_sequence(&self, sector: usize) -> Option<u32> {
+        let base = sector * F::SECTOR_SIZE;
+        let header = &self.flash.read()[base..base + SECTOR_HEADER_BYTES];
+        if header[..4] != SECTOR_MAGIC || header[COMMIT_OFFSET..] != [0; 4] {
+            return None;
+        }
+        Some(u32::from_le_bytes([
+            header[4], header[5], header[6], header[7],
+        ]))
+    }
+
+    // Build the directory from the active sector's records
+    fn scan(&mut self) {
+        let base = self.active * F::SECTOR_SIZE;
+        let sector = &self.flash.read()[base..base + F::SECTOR_SIZE];
+        let mut at = SECTOR_HEADER_BYTES;
+        while at + RECORD_HEADER_BYTES <= F::SECTOR_SIZE {
+            let header = &sector[at..at + RECORD_HEADER_BYTES];
+            if header.iter().all(|&b| b == ERASED) {
+                break;
+            }
+            let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+            let data_at = at + RECORD_HEADER_BYTES;
+            let record = Key::from_bytes([header[0], header[1]]).filter(|&key| {
+                data_at + len <= F::SECTOR_SIZE
+                    && record_header(key, &sector[data_at..data_at + len]) == header
+            });
+            let Some(key) = record else {
+                // Only the last record can be torn, the rest of the sector
+                // is written off
+                self.dirty = true;
+                break;
+            };
+            self.directory.retain(|entry| entry.key != key);
+            let _ = self.directory.push(Entry {
+                key,
+                offset: base + data_at,
+                len,
+            });
+            at += record_bytes(len);
+        }
+        self.free = at;
+        // Data programmed without its header
+        if sector[at.min(F::SECTOR_SIZE)..]
+            .iter()
+            .any(|&b| b != ERASED)
+        {
+            self.dirty = true;
+        }
+    }
+
+    // Copy every live record but the one for `key` to the next sector,
+    // followed by the new record, and make that the active sector
+    fn copy_to_next_sector(&mut self, key: Key, data: &[u8]) -> Result<(), StorageError<F::Error>> {
+        let kept = self.directory.iter().filter(|entry| entry.key != key);
+        let needed = SECTOR_HEADER_BYTES
+            + kept.map(|entry| record_bytes(entry.len)).sum::<usize>()
+            + record_bytes(data.len());
+        if needed > F::SECTOR_SIZE {
+            return Err(StorageError::Full);
+        }
+
+        let target = (self.active + 1) % F::SECTOR_COUNT;
+        let sequence = self.sequence.wrapping_add(1);
+        self.flash.erase(target).map_err(StorageError::Flash)?;
+        self.program_sector_header(target, sequence, false)?;
+        let base = target * F::SECTOR_SIZE;
+        let mut at = base + SECTOR_HEADER_BYTES;
+        let mut directory = Vec::new();
+        for entry in self
+            .directory
+            .clone()
+            .iter()
+            .filter(|entry| entry.key != key)
+        {
+            // Headers don't depend on where the record is, copy it whole
+            self.copy(
+                entry.offset - RECORD_HEADER_BYTES,
+                at,
+                RECORD_HEADER_BYTES + entry.len,
+            )?;
+            let _ = directory.push(Entry {
+                offset: at + RECORD_HEADER_BYTES,
+                ..*entry
+            });
+            at += record_bytes(entry.len);
+        }
+        self.program_record(at, key, data)?;
+        let _ = directory.push(Entry {
+            key,
+            offset: at + RECORD_HEADER_BYTES,
+            len: data.len(),
+        });
+        at += record_bytes(data.len());
+        self.flash
+            .program(base + COMMIT_OFFSET, &[0; 4])
+            .map_err(StorageError::Flash)?;
+
+        self.active = target;
+        self.sequence = sequence;
+        self.free = at - base;
+        self.dirty = false;
+        self.directory = directory;
+        Ok(())
+    }
+
+    fn program_sector_header(
+        &mut self,
+        sector: usize,
+        sequence: u32,
+        committed: bool,
+    ) -> Result<(), StorageError<F::Error>> {
+        let mut header = [ERASED; SECTOR_HEADER_BYTES];
+        header[..4].copy_from_slice(&SECTOR_MAGIC);
+        header[4..COMMIT_OFFSET].copy_from_slice(&sequence.to_le_bytes());
+        if committed {
+            header[COMMIT_OFFSET..].copy_from_slice(&[0; 4]);
+        }
+        self.flash
+            .program(sector * F::SECTOR_SIZE, &header)
+            .map_err(StorageError::Flash)
+    }
+
+    fn program_record(
+        &mut self,
+        offset: usize,
+        key: Key,
+        data: &[u8],
+    ) -> Result<(), StorageError<F::Error>> {
+        self.flash
+            .program(offset + RECORD_HEADER_BYTES, data)
+            .map_err(StorageError::Flash)?;
+        self.flash
+            .program(offset, &record_header(key, data))
+            .map_err(StorageError::Flash)
+    }
+
+    // Flash to flash through a small buffer, the source can't stay borrowed
+    // while programming
+    fn copy(&mut self, from: usize, to: usize, len: usize) -> Result<(), StorageError<F::Error>> {
+        let mut buffer = [0; 64];
+        let mut done = 0;
+        while done < len {
+            let chunk = (len - done).min(buffer.len());
+            buffer[..chunk].copy_from_slice(&self.flash.read()[from + done..from + done + chunk]);
+            self.flash
+                .program(to + done, &buffer[..chunk])
+                .map_err(StorageError::Flash)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,33 @@
+// Millisecond tick driven by SysTick. The interrupt is the input task in
+// main.rs, which calls `tick` before scanning the inputs
+use core::sync::atomic::{AtomicU32, Ordering};
+use cortex_m::peripheral::{syst::SystClkSource, SYST};
+use stm32f4xx_hal::rcc::Clocks;
+
+static MILLIS: AtomicU32 = AtomicU32::new(0);
+static SYSCLK_HZ: AtomicU32 = AtomicU32::new(0);
+
+pub fn init(mut syst: SYST, clocks: &Clocks) {
+    SYSCLK_HZ.store(clocks.sysclk().raw(), Ordering::Relaxed);
+    syst.set_clock_source(SystClkSource::Core);
+    syst.set_reload(clocks.sysclk().raw() / 1000 - 1);
+    syst.clear_current();
+    syst.enable_interrupt();
+    syst.enable_counter();
+}
+
+// Keep a millisecond tick while the core clock is divided, see power::set_speed
+pub fn set_divider(divider: u32) {
+    // SAFETY: the reload value is only written here and in `init`
+    let mut syst = unsafe { cortex_m::Peripherals::steal() }.SYST;
+    syst.set_reload(SYSCLK_HZ.load(Ordering::Relaxed) / divider / 1000 - 1);
+}
+
+// Milliseconds since `init`, wraps after about 49 days
+pub fn millis() -> u32 {
+    MILLIS.load(Ordering::Relaxed)
+}
+
+pub fn tick() {
+    MILLIS.fetch_add(1, Ordering::Relaxed);
+}
@@ -0,0 +1,164 @@
+// Instruction trace: one record per instruction run, with the registers it
+// changed. Built with the `trace` feature the firmware streams the records
+// over their own RTT up-channel, and tools/tracedump turns them back into a
+// disassembled listing.
+//
+// A record is little endian:
+//   u16  PC of the instruction
+//   u16  opcode
+//   u16  V registers that changed, bit n for Vn
+//   u8   other registers that changed: I (bit 0), SP (1), DT (2), ST (3)
+// followed by the new value of each changed register in that order: the V
+// registers a byte each, I as u16, SP, DT and ST a byte each.
+use crate::chip8::{Chip8, Chip8Error};
+
+const HEADER_BYTES: usize = 7;
+pub const MAX_RECORD_BYTES: usize = HEADER_BYTES + 16 + 2 + 3;
+
+const CHANGED_I: u8 = 1 << 0;
+const CHANGED_SP: u8 = 1 << 1;
+const CHANGED_DT: u8 = 1 << 2;
+const CHANGED_ST: u8 = 1 << 3;
+
+// The registers a record can show
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Registers {
+    pub v: [u8; 16],
+    pub i: u16,
+    pub sp: u8,
+    pub dt: u8,
+    pub st: u8,
+}
+
+impl Registers {
+    pub fn of(chip8: &Chip8) -> Registers {
+        Registers {
+            v: chip8.registers,
+            i: chip8.index_register,
+            sp: chip8.stack_pointer,
+            dt: chip8.delay_timer,
+            st: chip8.sound_timer,
+        }
+    }
+}
+
+// Registers that didn't change are left at 0 in `after`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TraceRecord {
+    pub pc: u16,
+    pub opcode: u16,
+    pub changed_v: u16,
+    pub changed: u8,
+    pub after: Registers,
+}
+
+// Run one instruction like Chip8::emulate_cycle and record it. The hook is
+// here rather than in the core, so only instructions run through this are
+// traced: in the firmware that is every instruction, the cpu task's loop is
+// the only caller of emulate_cycle. What changes the machine between
+// instructions has no record, the timers counting down, keys, the
+// debugger's writes and loaded states, so the registers in a record are
+// what the instruction left, not a full picture
+pub fn step(chip8: &mut Chip8) -> Result<TraceRecord, Chip8Error> {
+    let pc = chip8.program_counter;
+    let opcode = chip8.fetch_opcode()?;
+    let before = Registers::of(chip8);
+    chip8.emulate_cycle()?;
+    Ok(TraceRecord::new(pc, opcode, &before, &Registers::of(chip8)))
+}
+
+impl TraceRecord {
+    pub fn new(pc: u16, opcode: u16, before: &Registers, after: &Registers) -> TraceRecord {
+        let mut changed_v = 0;
+        for (n, (a, b)) in before.v.iter().zip(after.v.iter()).enumerate() {
+            if a != b {
+                changed_v |= 1 << n;
+            }
+        }
+        let mut changed = 0;
+        for (bit, differs) in [
+            (CHANGED_I, before.i != after.i),
+            (CHANGED_SP, before.sp != after.sp),
+            (CHANGED_DT, before.dt != after.dt),
+            (CHANGED_ST, before.st != after.st),
+        ] {
+            if differs {
+                changed |= bit;
+            }
+        }
+        TraceRecord {
+            pc,
+            opcode,
+            changed_v,
+            changed,
+            after: *after,
+        }
+    }
+
+    // Returns the record's length in `out`
+    pub fn encode(&self, out: &mut [u8; MAX_RECORD_BYTES]) -> usize {
+        out[0..2].copy_from_slice(&self.pc.to_le_bytes());
+        out[2..4].copy_from_slice(&self.opcode.to_le_bytes());
+        out[4..6].copy_from_slice(&self.changed_v.to_le_bytes());
+        out[6] = self.changed;
+        let mut len = HEADER_BYTES;
+        for n in 0..16 {
+            if self.changed_v & (1 << n) != 0 {
+                out[len] = self.after.v[n];
+                len += 1;
+            }
+        }
+        if self.changed & CHANGED_I != 0 {
+            out[len..len + 2].copy_from_slice(&self.after.i.to_le_bytes());
+            len += 2;
+        }
+        for (bit, value) in [
+            (CHANGED_SP, self.after.sp),
+            (CHANGED_DT, self.after.dt),
+            (CHANGED_ST, self.after.st),
+        ] {
+            if self.changed & bit != 0 {
+                out[len] = value;
+                len += 1;
+            }
+        }
+        len
+    }
+
+    // The record at the start of `bytes` and its length, None if `bytes`
+    // ends before the record does
+    pub fn decode(bytes: &[u8]) -> Option<(TraceRecord, usize)> {
+        let header = bytes.get(..HEADER_BYTES)?;
+        let mut record = TraceRecord {
+            pc: u16::from_le_bytes([header[0], header[1]]),
+            opcode: u16::from_le_bytes([header[2], header[3]]),
+            changed_v: u16::from_le_bytes([header[4], header[5]]),
+            changed: header[6],
+            after: Registers::default(),
+        };
+        let mut at = HEADER_BYTES;
+        let mut next = || {
+            let byte = bytes.get(at).copied();
+            at += 1;
+            byte
+        };
+        for n in 0..16 {
+            if record.changed_v & (1 << n) != 0 {
+                record.after.v[n] = next()?;
+            }
+        }
+        if record.changed & CHANGED_I != 0 {
+            record.after.i = u16::from_le_bytes([next()?, next()?]);
+        }
+        if record.changed & CHANGED_SP != 0 {
+            record.after.sp = next()?;
+        }
+        if record.changed & CHANGED_DT != 0 {
+            record.after.dt = next()?;
+        }
+        if record.changed & CHANGED_ST != 0 {
+            record.after.st = next()?;
+        }
+        Some((record, at))
+    }
+}
@@ -0,0 +1,101 @@
+// ROMs sent from a PC over USART2, in the upload module's framing. The RX
+// interrupt, bound by the app to `UartReceive::on_interrupt`, only moves
+// bytes into a lock-free queue; the CPU task feeds them
+// to the frame receiver and answers each frame. tools/romupload is the
+// sending end. Bytes between frames are the debugger's, see the debug module.
+use crate::board::UartPins;
+use crate::upload::{self, UploadReceiver};
+use heapless::spsc::{Consumer, Producer, Queue};
+use stm32f4xx_hal::pac::USART2;
+use stm32f4xx_hal::serial::{Config, Event, Rx, Tx};
+use stm32f4xx_hal::{block, prelude::*, rcc::Clocks};
+
+pub const BAUD_RATE: u32 = 115_200;
+// About 45 ms of bytes at full speed, enough to ride out a slow frame of the
+// CPU task. Bytes that don't fit are dropped, the frame then fails its CRC
+// and the host sends it again
+const QUEUE_LEN: usize = 512;
+pub type ByteQueue = Queue<u8, QUEUE_LEN>;
+
+pub struct UartReceive {
+    rx: Rx<USART2>,
+    bytes: Producer<'static, u8, QUEUE_LEN>,
+}
+
+impl UartReceive {
+    pub fn on_interrupt(&mut self) {
+        // Reading clears the interrupt, an overrun error is cleared by the
+        // failed read and the lost byte shows up as a bad CRC
+        while let Ok(byte) = self.rx.read() {
+            let _ = self.bytes.enqueue(byte);
+        }
+    }
+}
+
+pub struct UartUpload {
+    tx: Tx<USART2>,
+    bytes: Consumer<'static, u8, QUEUE_LEN>,
+    receiver: &'static mut UploadReceiver,
+}
+
+impl UartUpload {
+    pub fn new(
+        usart2: USART2,
+        pins: UartPins,
+        clocks: &Clocks,
+        queue: &'static mut ByteQueue,
+    ) -> (UartUpload, UartReceive) {
+        let mut serial = usart2
+            .serial(
+                (pins.tx, pins.rx),
+                Config::default().baudrate(BAUD_RATE.bps()),
+                clocks,
+            )
+            .unwrap();
+        serial.listen(Event::RxNotEmpty);
+        let (tx, rx) = serial.split();
+
+        let (producer, consumer) = queue.split();
+        // Kept out of main's stack, the image buffer is 3.5 KiB
+        let receiver = cortex_m::singleton!(: UploadReceiver = UploadReceiver::new()).unwrap();
+        (
+            UartUpload {
+                tx,
+                bytes: consumer,
+                receiver,
+            },
+            UartReceive {
+                rx,
+                bytes: producer,
+            },
+        )
+    }
+
+    // Work through the bytes received so far, answering every complete
+    // frame and handing bytes outside frames to `other`. Returns the image
+    // once one has been accepted; any bytes after it wait for the next call.
+    pub fn poll(&mut self, now_ms: u32, mut other: impl FnMut(u8)) -> Option<&[u8]> {
+        while let Some(byte) = self.bytes.dequeue() {
+            if byte != upload::FRAME_START && !self.receiver.in_frame(now_ms) {
+                other(byte);
+                continue;
+            }
+            let result = match self.receiver.feed(byte, now_ms) {
+                Some(result) => result.map(|_| ()),
+                None => continue,
+            };
+            self.write(upload::reply(result));
+            match result {
+                Ok(()) => return Some(self.receiver.image()),
+                Err(e) => defmt::warn!("ROM upload rejected: {}", e),
+            }
+        }
+        None
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            let _ = block!(self.tx.write(byte));
+        }
+    }
+}
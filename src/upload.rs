@@ -0,0 +1,173 @@
+// ROM upload protocol, spoken over the upload UART. The host sends one frame
+// per ROM:
+//
+//   0x7E, length (u16 LE), image (length bytes), CRC (u16 LE)
+//
+// The CRC is CRC-16/CCITT-FALSE over the length bytes and the image. The
+// board answers ACK, or NAK followed by an UploadError code. Bytes outside a
+// frame are ignored, and a frame that stalls for FRAME_TIMEOUT_MS is dropped
+// so the next one is picked up cleanly.
+use crate::crc::{crc16_update, CRC16_INIT};
+
+pub const FRAME_START: u8 = 0x7E;
+pub const ACK: u8 = 0x06;
+pub const NAK: u8 = 0x15;
+// Program memory runs from 0x200 to the end of the 4 KiB
+pub const MAX_IMAGE_BYTES: usize = 4096 - 0x200;
+pub const FRAME_TIMEOUT_MS: u32 = 500;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(target_os = "none", derive(defmt::Format))]
+pub enum UploadError {
+    Empty = 1,
+    TooLarge = 2,
+    BadCrc = 3,
+}
+
+impl UploadError {
+    // The sending side, for tools/romupload
+    #[allow(dead_code)]
+    pub fn from_code(code: u8) -> Option<UploadError> {
+        match code {
+            1 => Some(UploadError::Empty),
+            2 => Some(UploadError::TooLarge),
+            3 => Some(UploadError::BadCrc),
+            _ => None,
+        }
+    }
+}
+
+impl core::fmt::Display for UploadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            UploadError::Empty => write!(f, "empty image"),
+            UploadError::TooLarge => write!(f, "image larger than {} bytes", MAX_IMAGE_BYTES),
+            UploadError::BadCrc => write!(f, "CRC mismatch"),
+        }
+    }
+}
+
+// The bytes around an image: the header to send before it and the CRC after
+pub fn frame(image: &[u8]) -> ([u8; 3], [u8; 2]) {
+    let length = (image.len() as u16).to_le_bytes();
+    let crc = crc16_update(crc16_update(CRC16_INIT, &length), image);
+    ([FRAME_START, length[0], length[1]], crc.to_le_bytes())
+}
+
+// The reply to send for the outcome of a frame
+pub fn reply(result: Result<(), UploadError>) -> &'static [u8] {
+    match result {
+        Ok(()) => &[ACK],
+        Err(UploadError::Empty) => &[NAK, UploadError::Empty as u8],
+        Err(UploadError::TooLarge) => &[NAK, UploadError::TooLarge as u8],
+        Err(UploadError::BadCrc) => &[NAK, UploadError::BadCrc as u8],
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State {
+    Idle,
+    Length,
+    Image,
+    Crc,
+    // Swallowing the rest of a rejected frame, `count` bytes to go
+    Skip,
+}
+
+pub struct UploadReceiver {
+    state: State,
+    // Bytes of the current field received so far
+    count: usize,
+    field: [u8; 2],
+    length: usize,
+    image: [u8; MAX_IMAGE_BYTES],
+    last_byte_ms: u32,
+}
+
+impl UploadReceiver {
+    pub const fn new() -> UploadReceiver {
+        UploadReceiver {
+            state: State::Idle,
+            count: 0,
+            field: [0; 2],
+            length: 0,
+            image: [0; MAX_IMAGE_BYTES],
+            last_byte_ms: 0,
+        }
+    }
+
+    // Whether bytes arriving at `now_ms` belong to a frame, as opposed to
+    // the debugger's packets between frames
+    pub fn in_frame(&self, now_ms: u32) -> bool {
+        self.state != State::Idle && now_ms.wrapping_sub(self.last_byte_ms) <= FRAME_TIMEOUT_MS
+    }
+
+    // Feed one received byte. Returns the image once a whole frame has been
+    // checked, or why the frame was rejected.
+    pub fn feed(&mut self, byte: u8, now_ms: u32) -> Option<Result<&[u8], UploadError>> {
+        if self.state != State::Idle && now_ms.wrapping_sub(self.last_byte_ms) > FRAME_TIMEOUT_MS {
+            self.state = State::Idle;
+        }
+        self.last_byte_ms = now_ms;
+        match self.state {
+            State::Idle => {
+                if byte == FRAME_START {
+                    self.state = State::Length;
+                    self.count = 0;
+                }
+            }
+            State::Length => {
+                self.field[self.count] = byte;
+                self.count += 1;
+                if self.count == 2 {
+                    self.length = u16::from_le_bytes(self.field) as usize;
+                    self.count = 0;
+                    let error = match self.length {
+                        0 => UploadError::Empty,
+                        length if length > MAX_IMAGE_BYTES => UploadError::TooLarge,
+                        _ => {
+                            self.state = State::Image;
+                            return None;
+                        }
+                    };
+                    self.state = State::Skip;
+                    self.count = self.length + 2;
+                    return Some(Err(error));
+                }
+            }
+            State::Image => {
+                self.image[self.count] = byte;
+                self.count += 1;
+                if self.count == self.length {
+                    self.count = 0;
+                    self.state = State::Crc;
+                }
+            }
+            State::Crc => {
+                self.field[self.count] = byte;
+                self.count += 1;
+                if self.count == 2 {
+                    self.state = State::Idle;
+                    let image = &self.image[..self.length];
+                    let (_, crc) = frame(image);
+                    if crc != self.field {
+                        return Some(Err(UploadError::BadCrc));
+                    }
+                    return Some(Ok(image));
+                }
+            }
+            State::Skip => {
+                self.count -= 1;
+                if self.count == 0 {
+                    self.state = State::Idle;
+                }
+            }
+        }
+        None
+    }
+
+    // The image of the last frame that `feed` accepted
+    pub fn image(&self) -> &[u8] {
+        &self.image[..self.length]
+    }
+}
@@ -0,0 +1,79 @@
+// CHIP-8 keys from a PC over USB. The OTG FS peripheral shows up on the host
+// as a CDC-ACM serial port, and the bytes written to it are key events in the
+// serial_keys format. tools/keysend turns a keyboard into such a stream.
+use crate::board::UsbPins;
+use crate::chip8::{InputSource, KEY_COUNT};
+use crate::serial_keys::RemoteKeys;
+use core::ptr::addr_of_mut;
+use stm32f4xx_hal::otg_fs::{UsbBus, USB};
+use stm32f4xx_hal::pac::{OTG_FS_DEVICE, OTG_FS_GLOBAL, OTG_FS_PWRCLK};
+use stm32f4xx_hal::rcc::Clocks;
+use usb_device::bus::UsbBusAllocator;
+use usb_device::prelude::*;
+use usbd_serial::{SerialPort, USB_CLASS_CDC};
+
+// pid.codes test VID/PID, fine for a device that never leaves the bench
+const VID_PID: UsbVidPid = UsbVidPid(0x1209, 0x0001);
+
+static mut EP_MEMORY: [u32; 1024] = [0; 1024];
+
+pub struct UsbKeys {
+    device: UsbDevice<'static, UsbBus<USB>>,
+    serial: SerialPort<'static, UsbBus<USB>>,
+    keys: RemoteKeys,
+}
+
+impl UsbKeys {
+    // The clocks must have been frozen with the 48 MHz PLL output enabled
+    pub fn new(
+        otg_fs: (OTG_FS_GLOBAL, OTG_FS_DEVICE, OTG_FS_PWRCLK),
+        pins: UsbPins,
+        clocks: &Clocks,
+    ) -> UsbKeys {
+        let usb = USB::new(otg_fs, (pins.dm, pins.dp), clocks);
+        // SAFETY: EP_MEMORY is only ever borrowed here, and the singleton
+        // makes sure that happens once
+        let bus: &'static UsbBusAllocator<UsbBus<USB>> = cortex_m::singleton!(
+            : UsbBusAllocator<UsbBus<USB>> =
+                UsbBus::new(usb, unsafe { &mut *addr_of_mut!(EP_MEMORY) })
+        )
+        .unwrap();
+
+        let serial = SerialPort::new(bus);
+        let device = UsbDeviceBuilder::new(bus, VID_PID)
+            .device_class(USB_CLASS_CDC)
+            .strings(&[StringDescriptors::default()
+                .manufacturer("ssd1306_test")
+                .product("CHIP-8 keys")
+                .serial_number("0001")])
+            .unwrap()
+            .build();
+        UsbKeys {
+            device,
+            serial,
+            keys: RemoteKeys::new(),
+        }
+    }
+
+    // Has to be called every few milliseconds to keep the host happy.
+    // Returns true if a key went down.
+    pub fn poll(&mut self) -> bool {
+        if !self.device.poll(&mut [&mut self.serial]) {
+            if self.device.state() != UsbDeviceState::Configured {
+                self.keys.release_all();
+            }
+            return false;
+        }
+        let mut buf = [0; 64];
+        match self.serial.read(&mut buf) {
+            Ok(count) => self.keys.feed(&buf[..count]),
+            Err(_) => false,
+        }
+    }
+}
+
+impl InputSource for UsbKeys {
+    fn read_keys(&mut self, keys: &mut [u8; KEY_COUNT]) {
+        self.keys.read_keys(keys);
+    }
+}
@@ -0,0 +1,659 @@
+// Debugger for the game running on the board, over the upload UART in the
+// debug module's protocol:
+//
+//   $ chipdbg /dev/ttyUSB0
+//   (chipdbg) break 2a4
+//   (chipdbg) continue
+//
+// --local runs a ROM in the host emulator instead, in a second chipdbg
+// process (--serve) at the other end of a pipe. It starts out halted before
+// the first instruction. Commands come from stdin, so a session can be
+// scripted; `help` lists them. Addresses and values are hex.
+#[allow(dead_code)]
+#[path = "../../../src/chip8.rs"]
+mod chip8;
+#[allow(dead_code)]
+#[path = "../../../src/debug.rs"]
+mod debug;
+#[path = "../../../src/disasm.rs"]
+mod disasm;
+#[path = "../../../src/logging.rs"]
+mod logging;
+
+use chip8::{Chip8, Quirks};
+use debug::{DebugError, DebugServer, PacketReceiver, MAX_TRANSFER, REGISTER_BYTES};
+use disasm::Disassembly;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::time::{Duration, Instant};
+
+const BAUD_RATE: u32 = 115_200;
+// The board answers within a frame, unless it is busy writing flash
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+const ATTEMPTS: u32 = 3;
+// The firmware's speed for ROMs without a .meta file
+const INSTRUCTIONS_PER_FRAME: u32 = 10;
+const FRAME: Duration = Duration::from_micros(16_667);
+
+const HELP: &str = "\
+status                 running or where the game is halted
+pause                  halt the game
+continue               resume it
+step [count]           run instructions one at a time
+wait                   until the game halts, at a breakpoint for one
+regs                   show the registers and the stack
+set <reg> <value>      change V0-VF, I, PC, SP, DT or ST
+mem <addr> [len]       show memory
+write <addr> <byte>..  change memory
+dis [addr] [count]     disassemble, from PC without an address
+break <addr>           set a breakpoint
+delete <addr>          clear a breakpoint
+quit";
+
+fn usage() -> ! {
+    eprintln!("usage: chipdbg <serial port>");
+    eprintln!("       chipdbg --local <rom.ch8>");
+    eprintln!("       chipdbg --serve <rom.ch8>");
+    std::process::exit(2);
+}
+
+// Bytes from `reader` as they come in, the channel closes with it
+fn spawn_reader(mut reader: impl Read + Send + 'static) -> Receiver<u8> {
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut buf = [0u8; 256];
+        loop {
+            let count = match reader.read(&mut buf) {
+                Ok(0) => return,
+                Ok(count) => count,
+                // Serial ports time out when nothing arrives
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(_) => return,
+            };
+            for &byte in &buf[..count] {
+                if sender.send(byte).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+struct Connection {
+    writer: Box<dyn Write + Send>,
+    bytes: Receiver<u8>,
+    packets: PacketReceiver,
+    // Between a `c` or `s` and the stop packet that answers it
+    running: bool,
+    // Why the game last halted, and whether that has been shown yet
+    stop: u8,
+    unreported: bool,
+}
+
+impl Connection {
+    fn new(reader: impl Read + Send + 'static, writer: Box<dyn Write + Send>) -> Connection {
+        Connection {
+            writer,
+            bytes: spawn_reader(reader),
+            packets: PacketReceiver::new(),
+            running: false,
+            stop: 0,
+            unreported: false,
+        }
+    }
+
+    // The board may be running or halted when we connect
+    fn attach(&mut self) -> Result<(), String> {
+        let reply = self.request("?")?;
+        match stop_reason(&reply) {
+            Some(reason) => self.stopped(reason),
+            None if reply == "R" => self.running = true,
+            None => return Err(format!("unexpected reply {}", reply)),
+        }
+        Ok(())
+    }
+
+    fn stopped(&mut self, reason: u8) {
+        self.running = false;
+        self.stop = reason;
+        self.unreported = true;
+    }
+
+    fn send(&mut self, payload: &str) -> Result<(), String> {
+        let (start, end) = debug::frame(payload.as_bytes());
+        let mut bytes = vec![start];
+        bytes.extend_from_slice(payload.as_bytes());
+        bytes.extend_from_slice(&end);
+        self.write(&bytes)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.writer
+            .write_all(bytes)
+            .and_then(|_| self.writer.flush())
+            .map_err(|e| e.to_string())
+    }
+
+    // The next packet, or None once `deadline` has passed
+    fn next_packet(&mut self, deadline: Option<Instant>) -> Result<Option<String>, String> {
+        loop {
+            let byte = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match self.bytes.recv_timeout(timeout) {
+                        Ok(byte) => byte,
+                        Err(RecvTimeoutError::Timeout) => return Ok(None),
+                        Err(RecvTimeoutError::Disconnected) => return Err(closed()),
+                    }
+                }
+                None => self.bytes.recv().map_err(|_| closed())?,
+            };
+            if let Some(payload) = self.packets.feed(byte) {
+                return Ok(Some(String::from_utf8_lossy(payload).into_owned()));
+            }
+        }
+    }
+
+    // Send a command and wait for its reply. While the game runs the stop
+    // packet answering `c` or `s` can come first
+    fn request(&mut self, payload: &str) -> Result<String, String> {
+        for attempt in 1..=ATTEMPTS {
+            self.send(payload)?;
+            let deadline = Instant::now() + REPLY_TIMEOUT;
+            while let Some(reply) = self.next_packet(Some(deadline))? {
+                match stop_reason(&reply) {
+                    Some(reason) if self.running => self.stopped(reason),
+                    _ => return check_error(reply),
+                }
+            }
+            if attempt < ATTEMPTS {
+                eprintln!("no reply, retrying");
+            }
+        }
+        Err("no reply from the board".into())
+    }
+
+    // `c` or `s`, answered once the game halts
+    fn resume(&mut self, payload: &str) -> Result<(), String> {
+        if self.running {
+            return Err("the game is running, pause it first".into());
+        }
+        self.send(payload)?;
+        self.running = true;
+        Ok(())
+    }
+
+    fn pause(&mut self) -> Result<(), String> {
+        if !self.running {
+            return Ok(());
+        }
+        self.write(&[debug::PAUSE])?;
+        let deadline = Instant::now() + REPLY_TIMEOUT;
+        while self.running {
+            match self.next_packet(Some(deadline))? {
+                Some(reply) => {
+                    if let Some(reason) = stop_reason(&reply) {
+                        self.stopped(reason);
+                    }
+                }
+                None => return Err("no reply from the board".into()),
+            }
+        }
+        Ok(())
+    }
+
+    fn wait_stop(&mut self) -> Result<(), String> {
+        while self.running {
+            if let Some(reason) = self.next_packet(None)?.as_deref().and_then(stop_reason) {
+                self.stopped(reason);
+            }
+        }
+        Ok(())
+    }
+
+    // Pick up a stop that arrived since the last command, without waiting
+    fn poll_stop(&mut self) -> Result<(), String> {
+        loop {
+            match self.bytes.try_recv() {
+                Ok(byte) => {
+                    if let Some(reason) = self.packets.feed(byte).and_then(stop_reason) {
+                        self.stopped(reason);
+                    }
+                }
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => return Err(closed()),
+            }
+        }
+    }
+}
+
+fn closed() -> String {
+    "the connection closed".into()
+}
+
+fn stop_reason(payload: impl AsRef<[u8]>) -> Option<u8> {
+    match payload.as_ref() {
+        [b'S', digits @ ..] if digits.len() == 2 => debug::parse_hex(digits).map(|r| r as u8),
+        _ => None,
+    }
+}
+
+fn check_error(reply: String) -> Result<String, String> {
+    match reply.as_bytes() {
+        [b'E', digits @ ..] if digits.len() == 2 => {
+            let code = debug::parse_hex(digits).unwrap_or(0) as u8;
+            Err(match DebugError::from_code(code) {
+                Some(e) => e.to_string(),
+                None => format!("error {}", code),
+            })
+        }
+        _ => Ok(reply),
+    }
+}
+
+fn number(word: &str) -> Result<u16, String> {
+    debug::parse_hex(word.as_bytes()).ok_or_else(|| format!("not a hex number: {}", word))
+}
+
+// Only the registers and the stack of the returned machine are the board's
+fn read_registers(conn: &mut Connection) -> Result<Chip8, String> {
+    let reply = conn.request("g")?;
+    let mut bytes = [0; REGISTER_BYTES];
+    debug::decode_hex(reply.as_bytes(), &mut bytes).ok_or("malformed registers")?;
+    let mut chip8 = Chip8::new();
+    debug::set_registers(&mut chip8, &bytes).map_err(|e| e.to_string())?;
+    Ok(chip8)
+}
+
+fn read_memory(conn: &mut Connection, address: u16, len: usize) -> Result<Vec<u8>, String> {
+    let mut memory = Vec::with_capacity(len);
+    while memory.len() < len {
+        let at = address as usize + memory.len();
+        let count = (len - memory.len()).min(MAX_TRANSFER);
+        let reply = conn.request(&format!("m{:x},{:x}", at, count))?;
+        let mut bytes = vec![0; count];
+        debug::decode_hex(reply.as_bytes(), &mut bytes).ok_or("malformed memory read")?;
+        memory.extend_from_slice(&bytes);
+    }
+    Ok(memory)
+}
+
+// Firmware from before the `q` command answers it with an empty packet, its
+// ROMs are shown as if they had no quirks
+fn read_quirks(conn: &mut Connection) -> Result<Quirks, String> {
+    let reply = conn.request("q")?;
+    if reply.is_empty() {
+        return Ok(Quirks::default());
+    }
+    let mut bits = [0];
+    debug::decode_hex(reply.as_bytes(), &mut bits).ok_or("malformed quirks")?;
+    Ok(Quirks::from_bits(bits[0]))
+}
+
+fn disassemble(conn: &mut Connection, address: u16, count: usize) -> Result<(), String> {
+    let quirks = read_quirks(conn)?;
+    let memory = read_memory(conn, address, count * 2)?;
+    for (n, word) in memory.chunks(2).enumerate() {
+        let opcode = u16::from_be_bytes([word[0], word[1]]);
+        let at = address as usize + n * 2;
+        println!(
+            "{:03X}  {:04X}  {}",
+            at,
+            opcode,
+            Disassembly(opcode, quirks)
+        );
+    }
+    Ok(())
+}
+
+fn report_stop(conn: &mut Connection) -> Result<(), String> {
+    conn.unreported = false;
+    let why = match conn.stop {
+        debug::STOP_PAUSED => "paused",
+        debug::STOP_TRAP => "halted",
+        _ => "stopped",
+    };
+    let pc = read_registers(conn)?.program_counter;
+    print!("{} at ", why);
+    disassemble(conn, pc, 1)
+}
+
+fn show_registers(chip8: &Chip8) {
+    let v: Vec<String> = (0..16)
+        .map(|n| format!("V{:X}={:02X}", n, chip8.registers[n]))
+        .collect();
+    println!("{}", v[..8].join(" "));
+    println!("{}", v[8..].join(" "));
+    println!(
+        "I={:03X} PC={:03X} SP={:X} DT={:02X} ST={:02X}",
+        chip8.index_register,
+        chip8.program_counter,
+        chip8.stack_pointer,
+        chip8.delay_timer,
+        chip8.sound_timer
+    );
+    let stack: Vec<String> = chip8.return_stack[..chip8.stack_pointer as usize]
+        .iter()
+        .map(|entry| format!("{:03X}", entry))
+        .collect();
+    println!("stack: {}", stack.join(" "));
+}
+
+fn set_register(conn: &mut Connection, name: &str, value: u16) -> Result<(), String> {
+    let mut chip8 = read_registers(conn)?;
+    let name = name.to_ascii_uppercase();
+    let byte = || u8::try_from(value).map_err(|_| format!("{} takes a byte", name));
+    match name.as_str() {
+        "I" => chip8.index_register = value,
+        "PC" => chip8.program_counter = value,
+        "SP" => chip8.stack_pointer = byte()?,
+        "DT" => chip8.delay_timer = byte()?,
+        "ST" => chip8.sound_timer = byte()?,
+        _ => match name
+            .strip_prefix('V')
+            .and_then(|n| debug::parse_hex(n.as_bytes()))
+        {
+            Some(n) if name.len() == 2 => chip8.registers[n as usize] = byte()?,
+            _ => return Err(format!("no register {}", name)),
+        },
+    }
+    let bytes = debug::registers(&chip8);
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    conn.request(&format!("G{}", hex)).map(|_| ())
+}
+
+fn write_memory(conn: &mut Connection, address: u16, bytes: &[u8]) -> Result<(), String> {
+    for (n, chunk) in bytes.chunks(MAX_TRANSFER).enumerate() {
+        let at = address as usize + n * MAX_TRANSFER;
+        let hex: String = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        conn.request(&format!("M{:x},{:x}:{}", at, chunk.len(), hex))?;
+    }
+    Ok(())
+}
+
+// Returns false for quit
+fn command(conn: &mut Connection, words: &[&str]) -> Result<bool, String> {
+    match words {
+        [] => {}
+        ["help"] => println!("{}", HELP),
+        ["quit"] => return Ok(false),
+        ["status"] if conn.running => println!("running"),
+        ["status"] => report_stop(conn)?,
+        ["pause"] => {
+            conn.pause()?;
+            report_stop(conn)?;
+        }
+        ["continue" | "c"] => {
+            conn.resume("c")?;
+            println!("running");
+        }
+        ["step" | "s", count @ ..] => {
+            let count = match count {
+                [] => 1,
+                [count] => number(count)?,
+                _ => return Err("usage: step [count]".into()),
+            };
+            for _ in 0..count {
+                conn.resume("s")?;
+                conn.wait_stop()?;
+                report_stop(conn)?;
+            }
+        }
+        ["wait"] => {
+            conn.wait_stop()?;
+            report_stop(conn)?;
+        }
+        ["regs"] => show_registers(&read_registers(conn)?),
+        ["set", name, value] => set_register(conn, name, number(value)?)?,
+        ["mem", address, len @ ..] => {
+            let address = number(address)?;
+            let len = match len {
+                [] => 0x40,
+                [len] => number(len)? as usize,
+                _ => return Err("usage: mem <addr> [len]".into()),
+            };
+            let memory = read_memory(conn, address, len)?;
+            for (n, line) in memory.chunks(16).enumerate() {
+                let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+                println!("{:03X}  {}", address as usize + n * 16, hex.join(" "));
+            }
+        }
+        ["write", address, bytes @ ..] if !bytes.is_empty() => {
+            let bytes = bytes
+                .iter()
+                .map(|word| {
+                    u8::from_str_radix(word, 16).map_err(|_| format!("not a byte: {}", word))
+                })
+                .collect::<Result<Vec<u8>, String>>()?;
+            write_memory(conn, number(address)?, &bytes)?;
+        }
+        ["dis", args @ ..] => {
+            let (address, count) = match args {
+                [] => (read_registers(conn)?.program_counter, 8),
+                [address] => (number(address)?, 8),
+                [address, count] => (number(address)?, number(count)? as usize),
+                _ => return Err("usage: dis [addr] [count]".into()),
+            };
+            disassemble(conn, address, count)?;
+        }
+        ["break", address] => {
+            conn.request(&format!("Z0,{:x}", number(address)?))?;
+        }
+        ["delete", address] => {
+            conn.request(&format!("z0,{:x}", number(address)?))?;
+        }
+        _ => return Err(format!("unknown command, try help: {}", words.join(" "))),
+    }
+    Ok(true)
+}
+
+fn session(conn: &mut Connection) -> Result<(), String> {
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut lines = stdin.lock().lines();
+    conn.attach()?;
+    loop {
+        conn.poll_stop()?;
+        if conn.unreported {
+            report_stop(conn)?;
+        }
+        if interactive {
+            print!("(chipdbg) ");
+            let _ = io::stdout().flush();
+        }
+        let Some(line) = lines.next() else {
+            return Ok(());
+        };
+        let line = line.map_err(|e| e.to_string())?;
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match command(conn, &words) {
+            Ok(true) => {}
+            Ok(false) => return Ok(()),
+            // A script stops at the first failure, a person can carry on
+            Err(e) if interactive => eprintln!("{}", e),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// The board's end on stdin and stdout, for --local
+fn serve(rom: &str) -> Result<(), String> {
+    serve_on(&read_rom(rom)?, io::stdin(), io::stdout())
+}
+
+// Runs in 60 Hz frames like the board, until `input` closes
+fn serve_on(
+    program: &[u8],
+    input: impl Read + Send + 'static,
+    mut out: impl Write,
+) -> Result<(), String> {
+    let mut chip8 = Chip8::new();
+    chip8.load_program(program).map_err(|e| e.to_string())?;
+    // Halted before the first instruction, the host knows without being told
+    let mut server = DebugServer::new();
+    server.feed(debug::PAUSE, &mut chip8);
+    server.clear_output();
+    let bytes = spawn_reader(input);
+    let mut next_frame = Instant::now();
+    loop {
+        loop {
+            match bytes.try_recv() {
+                Ok(byte) => server.feed(byte, &mut chip8),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+        for _ in 0..INSTRUCTIONS_PER_FRAME {
+            if !server.may_run(chip8.program_counter) {
+                break;
+            }
+            chip8
+                .emulate_cycle()
+                .map_err(|e| format!("the game stopped: {}", e))?;
+            server.ran();
+        }
+        if !server.is_halted() {
+            chip8.tick_timers();
+        }
+        out.write_all(server.output())
+            .and_then(|_| out.flush())
+            .map_err(|e| e.to_string())?;
+        server.clear_output();
+        next_frame += FRAME;
+        std::thread::sleep(next_frame.saturating_duration_since(Instant::now()));
+    }
+}
+
+fn read_rom(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    match args {
+        [flag, rom] if flag == "--serve" => serve(rom),
+        [flag, rom] if flag == "--local" => {
+            let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+            let mut child = Command::new(exe)
+                .args(["--serve", rom])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .map_err(|e| e.to_string())?;
+            let (stdin, stdout) = (child.stdin.take().unwrap(), child.stdout.take().unwrap());
+            let mut conn = Connection::new(stdout, Box::new(stdin));
+            let result = session(&mut conn);
+            // Closing its stdin ends the server
+            drop(conn);
+            let _ = child.wait();
+            result
+        }
+        [port] if !port.starts_with('-') => {
+            let port = serialport::new(port, BAUD_RATE)
+                .timeout(Duration::from_millis(100))
+                .open()
+                .map_err(|e| format!("failed to open {}: {}", port, e))?;
+            let reader = port.try_clone().map_err(|e| e.to_string())?;
+            session(&mut Connection::new(reader, Box::new(port)))
+        }
+        _ => usage(),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("chipdbg: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::JoinHandle;
+
+    // V0 = 5, then V0 += 1 at 202 forever
+    const PROGRAM: [u8; 6] = [0x60, 0x05, 0x70, 0x01, 0x12, 0x02];
+
+    // A host emulator on the far end of two pipes, like --local
+    fn connect() -> (Connection, JoinHandle<Result<(), String>>) {
+        let (from_host, to_server) = io::pipe().unwrap();
+        let (from_server, to_host) = io::pipe().unwrap();
+        let server = std::thread::spawn(move || serve_on(&PROGRAM, from_host, to_host));
+        let mut conn = Connection::new(from_server, Box::new(to_server));
+        conn.attach().unwrap();
+        (conn, server)
+    }
+
+    fn pc(conn: &mut Connection) -> u16 {
+        read_registers(conn).unwrap().program_counter
+    }
+
+    // Closing the host's end stops the server
+    fn disconnect(conn: Connection, server: JoinHandle<Result<(), String>>) {
+        drop(conn);
+        server.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn starts_halted_and_steps() {
+        let (mut conn, server) = connect();
+        assert!(!conn.running);
+        assert_eq!(conn.stop, debug::STOP_PAUSED);
+        assert_eq!(pc(&mut conn), 0x200);
+
+        conn.resume("s").unwrap();
+        conn.wait_stop().unwrap();
+        let chip8 = read_registers(&mut conn).unwrap();
+        assert_eq!(chip8.program_counter, 0x202);
+        assert_eq!(chip8.registers[0], 5);
+        disconnect(conn, server);
+    }
+
+    #[test]
+    fn runs_to_a_breakpoint_and_pauses() {
+        let (mut conn, server) = connect();
+        command(&mut conn, &["break", "204"]).unwrap();
+        conn.resume("c").unwrap();
+        conn.wait_stop().unwrap();
+        assert_eq!(conn.stop, debug::STOP_TRAP);
+        assert_eq!(pc(&mut conn), 0x204);
+
+        command(&mut conn, &["delete", "204"]).unwrap();
+        conn.resume("c").unwrap();
+        conn.pause().unwrap();
+        assert!(!conn.running);
+        assert_eq!(conn.stop, debug::STOP_PAUSED);
+        assert!(read_registers(&mut conn).unwrap().registers[0] > 5);
+        disconnect(conn, server);
+    }
+
+    #[test]
+    fn quirks_come_from_the_server() {
+        let (mut conn, server) = connect();
+        assert_eq!(read_quirks(&mut conn).unwrap(), Quirks::default());
+        disconnect(conn, server);
+    }
+
+    #[test]
+    fn changes_registers_and_memory() {
+        let (mut conn, server) = connect();
+        set_register(&mut conn, "v3", 0x42).unwrap();
+        set_register(&mut conn, "I", 0x300).unwrap();
+        let chip8 = read_registers(&mut conn).unwrap();
+        assert_eq!(chip8.registers[3], 0x42);
+        assert_eq!(chip8.index_register, 0x300);
+
+        // Longer than one transfer, so it goes in several packets
+        let bytes: Vec<u8> = (0..2 * MAX_TRANSFER + 3).map(|i| i as u8).collect();
+        write_memory(&mut conn, 0x300, &bytes).unwrap();
+        assert_eq!(read_memory(&mut conn, 0x300, bytes.len()).unwrap(), bytes);
+        assert_eq!(read_memory(&mut conn, 0x200, 2).unwrap(), PROGRAM[..2]);
+
+        let error = command(&mut conn, &["set", "V3", "100"]).unwrap_err();
+        assert_eq!(error, "V3 takes a byte");
+        disconnect(conn, server);
+    }
+}
@@ -0,0 +1,226 @@
+// Runs a ROM on the host in a window, for trying ROMs and emulator changes
+// without a board. The PC keyboard stands in for the keypad, in the usual
+// layout for emulators:
+//   1 2 3 4      1 2 3 C
+//   Q W E R  ->  4 5 6 D
+//   A S D F      7 8 9 E
+//   Z X C V      A 0 B F
+//
+// --preview <panel> shows what that panel would show instead of the bare
+// 64x32 screen. The image goes through the firmware's own rendering, so its
+// place on the panel is the board's; --invert and --rotate apply the display
+// settings of the same name. Esc quits, F5 starts the ROM over. There is no
+// sound, tools/romwav records it.
+#[allow(dead_code)]
+#[path = "../../../src/chip8.rs"]
+mod chip8;
+#[allow(dead_code)]
+#[path = "../../../src/display.rs"]
+mod display;
+#[path = "../../../src/logging.rs"]
+mod logging;
+#[allow(dead_code)]
+#[path = "../../../src/panel.rs"]
+mod panel;
+
+use chip8::{Chip8, InputSource, KEY_COUNT, SCREEN_HEIGHT, SCREEN_WIDTH};
+use display::{FrameBuffer, Layout, MAX_PAGES, PAGE_BYTES};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+use panel::Panel;
+
+const FRAMES_PER_SECOND: usize = 60;
+// The firmware's speed for ROMs without a .meta file
+const INSTRUCTIONS_PER_FRAME: u32 = 10;
+// Either view is 512 pixels wide by default
+const SCALE: usize = 8;
+const PREVIEW_SCALE: usize = 4;
+const ON: u32 = 0xFFFFFF;
+const OFF: u32 = 0x000000;
+
+const KEYS: [(Key, u8); KEY_COUNT] = [
+    (Key::Key1, 0x1),
+    (Key::Key2, 0x2),
+    (Key::Key3, 0x3),
+    (Key::Key4, 0xC),
+    (Key::Q, 0x4),
+    (Key::W, 0x5),
+    (Key::E, 0x6),
+    (Key::R, 0xD),
+    (Key::A, 0x7),
+    (Key::S, 0x8),
+    (Key::D, 0x9),
+    (Key::F, 0xE),
+    (Key::Z, 0xA),
+    (Key::X, 0x0),
+    (Key::C, 0xB),
+    (Key::V, 0xF),
+];
+
+struct WindowKeys<'a>(&'a Window);
+
+impl InputSource for WindowKeys<'_> {
+    fn read_keys(&mut self, keys: &mut [u8; KEY_COUNT]) {
+        for (key, hex) in KEYS {
+            keys[hex as usize] = self.0.is_key_down(key) as u8;
+        }
+    }
+}
+
+type Screen = [u8; SCREEN_WIDTH * SCREEN_HEIGHT];
+
+// A panel to preview: its size and the firmware's rendering for it
+#[derive(Clone, Copy)]
+struct Preview {
+    width: usize,
+    height: usize,
+    render: fn(&Screen, &mut FrameBuffer),
+}
+
+impl Preview {
+    fn of<P: Panel>() -> Preview {
+        Preview {
+            width: P::WIDTH,
+            height: P::HEIGHT,
+            render: display::render_frame::<P>,
+        }
+    }
+
+    // Named like the firmware's panel-* features
+    fn named(name: &str) -> Option<Preview> {
+        match name {
+            "ssd1306" => Some(Preview::of::<panel::Ssd1306x64>()),
+            "ssd1306-128x32" => Some(Preview::of::<panel::Ssd1306x32>()),
+            "ssd1309" => Some(Preview::of::<panel::Ssd1309>()),
+            "sh1106" => Some(Preview::of::<panel::Sh1106>()),
+            "st7565" => Some(Preview::of::<panel::St7565>()),
+            _ => None,
+        }
+    }
+}
+
+struct Options {
+    rom: String,
+    scale: Option<usize>,
+    ipf: u32,
+    preview: Option<Preview>,
+    inverted: bool,
+    rotated: bool,
+}
+
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut options = Options {
+        rom: String::new(),
+        scale: None,
+        ipf: INSTRUCTIONS_PER_FRAME,
+        preview: None,
+        inverted: false,
+        rotated: false,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scale" => options.scale = Some(args.next()?.parse().ok().filter(|&s| s > 0)?),
+            "--ipf" => options.ipf = args.next()?.parse().ok()?,
+            "--preview" => options.preview = Some(Preview::named(args.next()?)?),
+            "--invert" => options.inverted = true,
+            "--rotate" => options.rotated = true,
+            rom if !rom.starts_with('-') && options.rom.is_empty() => options.rom = rom.into(),
+            _ => return None,
+        }
+    }
+    (!options.rom.is_empty()).then_some(options)
+}
+
+// What the view shows before scaling, row major
+fn picture(chip8: &Chip8, options: &Options, frame: &mut FrameBuffer, pixels: &mut Vec<bool>) {
+    pixels.clear();
+    let Some(preview) = options.preview else {
+        pixels.extend(chip8.screen.iter().map(|&pixel| pixel == 1));
+        return;
+    };
+    (preview.render)(&chip8.screen, frame);
+    for y in 0..preview.height {
+        for x in 0..preview.width {
+            // Turned by 180° the panel shows its RAM from the other corner
+            let (x, y) = match options.rotated {
+                true => (preview.width - 1 - x, preview.height - 1 - y),
+                false => (x, y),
+            };
+            let lit = frame[y / 8][1 + x] & (1 << (y % 8)) != 0;
+            pixels.push(lit != options.inverted);
+        }
+    }
+}
+
+fn draw(pixels: &[bool], width: usize, scale: usize, buffer: &mut [u32]) {
+    let out_width = width * scale;
+    for (i, out) in buffer.iter_mut().enumerate() {
+        let (x, y) = (i % out_width / scale, i / out_width / scale);
+        *out = if pixels[y * width + x] { ON } else { OFF };
+    }
+}
+
+fn load(program: &[u8]) -> Result<Chip8, String> {
+    let mut chip8 = Chip8::new();
+    chip8.load_program(program).map_err(|e| e.to_string())?;
+    Ok(chip8)
+}
+
+fn run(options: &Options) -> Result<(), Box<dyn std::error::Error>> {
+    let program = std::fs::read(&options.rom)?;
+    let mut chip8 = load(&program)?;
+    let (width, height, scale) = match options.preview {
+        Some(preview) => {
+            let layout = Layout::for_panel(preview.width, preview.height);
+            println!(
+                "{}x{} panel, the screen at {},{}",
+                preview.width, preview.height, layout.x_offset, layout.y_offset
+            );
+            let scale = options.scale.unwrap_or(PREVIEW_SCALE);
+            (preview.width, preview.height, scale)
+        }
+        None => (SCREEN_WIDTH, SCREEN_HEIGHT, options.scale.unwrap_or(SCALE)),
+    };
+    let title = format!("{} - desktop", options.rom);
+    let mut window = Window::new(
+        &title,
+        width * scale,
+        height * scale,
+        WindowOptions::default(),
+    )?;
+    window.set_target_fps(FRAMES_PER_SECOND);
+
+    let mut frame: FrameBuffer = [[0; PAGE_BYTES]; MAX_PAGES];
+    let mut pixels = Vec::with_capacity(width * height);
+    let mut buffer = vec![OFF; width * scale * height * scale];
+    while window.is_open() && !window.is_key_down(Key::Escape) {
+        if window.is_key_pressed(Key::F5, KeyRepeat::No) {
+            chip8 = load(&program)?;
+        }
+        // Like the board: the keys, the frame's instructions, then one tick
+        // of the timers
+        chip8.poll_input(&mut WindowKeys(&window));
+        for _ in 0..options.ipf {
+            chip8.emulate_cycle().map_err(|e| e.to_string())?;
+        }
+        chip8.tick_timers();
+        picture(&chip8, options, &mut frame, &mut pixels);
+        draw(&pixels, width, scale, &mut buffer);
+        window.update_with_buffer(&buffer, width * scale, height * scale)?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(options) = parse_args(&args) else {
+        eprintln!("usage: desktop <rom.ch8> [--scale N] [--ipf N]");
+        eprintln!("       desktop <rom.ch8> --preview <panel> [--scale N] [--ipf N] [--invert] [--rotate]");
+        eprintln!("panels: ssd1306, ssd1306-128x32, ssd1309, sh1106, st7565");
+        std::process::exit(2);
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("desktop: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,41 @@
+// Downloads the patched SVD file that the debugger configs in .vscode use
+// from stm32-rs into the repository root. This used to happen in build.rs,
+// which made every build need the network; now it is only run by hand:
+//
+//   cargo run -p fetch-svd [chip]
+//
+// The chip defaults to $CHIPSERIE, then stm32f411.
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+    let chip = env::args()
+        .nth(1)
+        .or_else(|| env::var("CHIPSERIE").ok())
+        .unwrap_or_else(|| String::from("stm32f411"));
+    if let Err(e) = fetch(&chip) {
+        eprintln!("fetch-svd: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn fetch(chip: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let filename = format!("{}.svd.patched", chip);
+    let url = format!("https://stm32-rs.github.io/stm32-rs/{}", filename);
+    // tools/ sits in the repository root
+    let output_path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../..")
+        .join(&filename);
+
+    if output_path.exists() {
+        println!("{} already exists, skipping download", filename);
+        return Ok(());
+    }
+    println!("Downloading SVD file from {}...", url);
+    let response = reqwest::blocking::get(&url)?.error_for_status()?;
+    let content = response.text()?;
+    fs::write(&output_path, content)?;
+    println!("SVD file saved to {:?}", output_path);
+    Ok(())
+}
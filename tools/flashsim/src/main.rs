@@ -0,0 +1,221 @@
+// Runs the firmware's flash storage against flash kept in RAM: random
+// writes of settings, ROMs and save states, with the power cut part way
+// through some of them. After every write the storage is mounted again and
+// checked against what was written, and at the end the erase count of each
+// sector shows how evenly the wear is spread.
+#[path = "../../../src/crc.rs"]
+mod crc;
+#[allow(dead_code)]
+#[path = "../../../src/storage.rs"]
+mod storage;
+
+use storage::{Flash, Key, Storage, StorageError};
+
+// Small sectors, so they fill up and rotate often
+const SECTOR_SIZE: usize = 4096;
+const SECTOR_COUNT: usize = 3;
+// One write in CUT_EVERY loses power
+const CUT_EVERY: u32 = 8;
+
+#[derive(Debug)]
+enum FakeError {
+    // Programming a byte that wasn't erased, which real flash would corrupt
+    NotErased,
+    PowerCut,
+}
+
+struct RamFlash {
+    bytes: Vec<u8>,
+    erases: [u32; SECTOR_COUNT],
+    // Bytes left to program before the power goes, None for no cut
+    power_left: Option<usize>,
+}
+
+impl RamFlash {
+    fn new() -> RamFlash {
+        RamFlash {
+            // Not erased, like a part that held something else before
+            bytes: vec![0; SECTOR_SIZE * SECTOR_COUNT],
+            erases: [0; SECTOR_COUNT],
+            power_left: None,
+        }
+    }
+}
+
+// By reference, so the same flash can be mounted again after a power cut
+impl Flash for &mut RamFlash {
+    type Error = FakeError;
+    const SECTOR_SIZE: usize = SECTOR_SIZE;
+    const SECTOR_COUNT: usize = SECTOR_COUNT;
+
+    fn read(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    fn erase(&mut self, sector: usize) -> Result<(), FakeError> {
+        if self.power_left == Some(0) {
+            return Err(FakeError::PowerCut);
+        }
+        self.erases[sector] += 1;
+        self.bytes[sector * SECTOR_SIZE..(sector + 1) * SECTOR_SIZE].fill(0xFF);
+        Ok(())
+    }
+
+    fn program(&mut self, offset: usize, bytes: &[u8]) -> Result<(), FakeError> {
+        for (i, &byte) in bytes.iter().enumerate() {
+            if let Some(left) = self.power_left.as_mut() {
+                if *left == 0 {
+                    return Err(FakeError::PowerCut);
+                }
+                *left -= 1;
+            }
+            let cell = &mut self.bytes[offset + i];
+            if *cell != 0xFF && byte != 0xFF {
+                return Err(FakeError::NotErased);
+            }
+            *cell &= byte;
+        }
+        Ok(())
+    }
+}
+
+// xorshift32, enough to vary the workload without a dependency
+struct Random(u32);
+
+impl Random {
+    fn next(&mut self, below: u32) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 % below
+    }
+}
+
+fn run(rounds: u32, seed: u32) -> Result<[u32; SECTOR_COUNT], String> {
+    let mut flash = RamFlash::new();
+    let mut random = Random(seed.max(1));
+    // What should be stored, in the order it was first written
+    let mut expected: Vec<(Key, Vec<u8>)> = Vec::new();
+    let mut cuts = 0;
+
+    for round in 0..rounds {
+        let key = match random.next(3) {
+            0 => Key::Settings,
+            1 => Key::Rom(random.next(4) as u8),
+            _ => Key::SaveState(random.next(2) as u8),
+        };
+        // Short enough that one of each key fits into a sector
+        let len = 1 + random.next(500) as usize;
+        let data: Vec<u8> = (0..len).map(|_| random.next(256) as u8).collect();
+        if random.next(CUT_EVERY) == 0 {
+            flash.power_left = Some(random.next(2 * len as u32) as usize);
+        }
+
+        let result = {
+            let mut storage = Storage::mount(&mut flash).map_err(|e| format!("{:?}", e))?;
+            storage.write(key, &data)
+        };
+        flash.power_left = None;
+        let storage = Storage::mount(&mut flash).map_err(|e| format!("{:?}", e))?;
+        let stored = storage.read(key).map(|bytes| bytes.to_vec());
+        let before = expected.iter().find(|(k, _)| *k == key).map(|(_, v)| v);
+        match result {
+            Ok(()) if stored.as_ref() != Some(&data) => {
+                return Err(format!("round {}: {:?} reads back wrong", round, key))
+            }
+            Ok(()) => {}
+            // Either the old or the new data, never anything else
+            Err(StorageError::Flash(FakeError::PowerCut)) => {
+                cuts += 1;
+                if stored.as_ref() != Some(&data) && stored.as_ref() != before {
+                    return Err(format!("round {}: {:?} torn by a power cut", round, key));
+                }
+            }
+            Err(e) => {
+                return Err(format!(
+                    "round {}: writing {:?} failed: {:?}",
+                    round, key, e
+                ))
+            }
+        }
+        if stored.as_ref() == Some(&data) {
+            expected.retain(|(k, _)| *k != key);
+            expected.push((key, data));
+        }
+        for (key, data) in &expected {
+            if storage.read(*key) != Some(data) {
+                return Err(format!("round {}: lost {:?}", round, key));
+            }
+        }
+    }
+    println!("{} writes, {} power cuts", rounds, cuts);
+    Ok(flash.erases)
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let number = |index: usize, default: u32| match args.get(index).map(|s| s.parse()) {
+        None => default,
+        Some(Ok(n)) => n,
+        Some(Err(_)) => {
+            eprintln!("usage: flashsim [writes] [seed]");
+            std::process::exit(2);
+        }
+    };
+    match run(number(0, 5000), number(1, 1)) {
+        Ok(erases) => println!("erases per sector: {:?}", erases),
+        Err(e) => {
+            eprintln!("flashsim: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_writes_with_power_cuts() {
+        for seed in 1..=3 {
+            run(1000, seed).unwrap();
+        }
+    }
+
+    #[test]
+    fn wear_is_spread_over_every_sector() {
+        // Power cuts during a copy cost an extra erase, so it is not exact
+        let erases = run(3000, 1).unwrap();
+        let (least, most) = (erases.iter().min().unwrap(), erases.iter().max().unwrap());
+        assert!(
+            *least > 0 && most - least <= most / 10,
+            "erases {:?}",
+            erases
+        );
+    }
+
+    #[test]
+    fn cut_at_every_byte_leaves_old_or_new_data() {
+        let old = vec![0x11; 64];
+        let new = vec![0x22; 64];
+        for cut in 0..2 * new.len() {
+            let mut flash = RamFlash::new();
+            Storage::mount(&mut flash)
+                .unwrap()
+                .write(Key::Settings, &old)
+                .unwrap();
+            flash.power_left = Some(cut);
+            let _ = Storage::mount(&mut flash)
+                .unwrap()
+                .write(Key::Settings, &new);
+            flash.power_left = None;
+            let storage = Storage::mount(&mut flash).unwrap();
+            let stored = storage.read(Key::Settings);
+            assert!(
+                stored == Some(&old[..]) || stored == Some(&new[..]),
+                "cut after {} bytes",
+                cut
+            );
+        }
+    }
+}
@@ -0,0 +1,246 @@
+// Sends CHIP-8 key events typed on the PC keyboard to the board's USB serial
+// port, or with --pty to a pseudo terminal for testing without a board.
+//
+// Keys use the usual layout for emulators on a PC keyboard:
+//   1 2 3 4      1 2 3 C
+//   Q W E R  ->  4 5 6 D
+//   A S D F      7 8 9 E
+//   Z X C V      A 0 B F
+//
+// Terminals report key presses but not releases, so a key is released once
+// its auto-repeat stops arriving. Esc or Ctrl-C quits.
+#[allow(dead_code)]
+#[path = "../../../src/chip8.rs"]
+mod chip8;
+#[path = "../../../src/logging.rs"]
+mod logging;
+#[allow(dead_code)]
+#[path = "../../../src/serial_keys.rs"]
+mod serial_keys;
+
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::terminal;
+use serial_keys::KeyEvent;
+use serialport::SerialPort;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+// Longer than the gap between auto-repeats, shorter than a deliberate pause
+const HOLD: Duration = Duration::from_millis(150);
+// Key repeat takes a while to start, give the first press longer
+const FIRST_HOLD: Duration = Duration::from_millis(550);
+
+fn chip8_key(c: char) -> Option<u8> {
+    const LAYOUT: [(char, u8); 16] = [
+        ('1', 0x1),
+        ('2', 0x2),
+        ('3', 0x3),
+        ('4', 0xC),
+        ('q', 0x4),
+        ('w', 0x5),
+        ('e', 0x6),
+        ('r', 0xD),
+        ('a', 0x7),
+        ('s', 0x8),
+        ('d', 0x9),
+        ('f', 0xE),
+        ('z', 0xA),
+        ('x', 0x0),
+        ('c', 0xB),
+        ('v', 0xF),
+    ];
+    let c = c.to_ascii_lowercase();
+    LAYOUT.iter().find(|(k, _)| *k == c).map(|(_, key)| *key)
+}
+
+fn usage() -> ! {
+    eprintln!("usage: keysend <serial port>");
+    eprintln!("       keysend --pty");
+    std::process::exit(2);
+}
+
+fn open(arg: &str) -> Result<Box<dyn Write>, serialport::Error> {
+    if arg == "--pty" {
+        let (master, slave) = serialport::TTYPort::pair()?;
+        println!(
+            "key events go to {}",
+            slave.name().unwrap_or_else(|| "?".into())
+        );
+        // The slave end has to stay open or writes to the master fail
+        std::mem::forget(slave);
+        Ok(Box::new(master))
+    } else {
+        // CDC-ACM ignores the baud rate
+        let port = serialport::new(arg, 115_200)
+            .timeout(Duration::from_millis(100))
+            .open()?;
+        Ok(Box::new(port))
+    }
+}
+
+fn send(port: &mut dyn Write, key: u8, pressed: bool) -> std::io::Result<()> {
+    port.write_all(&serial_keys::encode(KeyEvent { key, pressed }))?;
+    port.flush()
+}
+
+fn run(port: &mut dyn Write) -> std::io::Result<()> {
+    // When each held key was pressed first and last seen
+    let mut held: [Option<(Instant, Instant)>; 16] = [None; 16];
+    loop {
+        if event::poll(Duration::from_millis(10))? {
+            if let Event::Key(key) = event::read()? {
+                let quit = key.code == KeyCode::Esc
+                    || (key.code == KeyCode::Char('c')
+                        && key.modifiers.contains(KeyModifiers::CONTROL));
+                if quit {
+                    break;
+                }
+                if let KeyCode::Char(c) = key.code {
+                    if let Some(k) = chip8_key(c) {
+                        let slot = &mut held[k as usize];
+                        match (key.kind, *slot) {
+                            (KeyEventKind::Release, Some(_)) => {
+                                *slot = None;
+                                send(port, k, false)?;
+                            }
+                            (KeyEventKind::Release, None) => {}
+                            (_, Some((first, _))) => *slot = Some((first, Instant::now())),
+                            (_, None) => {
+                                *slot = Some((Instant::now(), Instant::now()));
+                                send(port, k, true)?;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let now = Instant::now();
+        for (k, slot) in held.iter_mut().enumerate() {
+            if let Some((first, last)) = *slot {
+                let hold = if first == last { FIRST_HOLD } else { HOLD };
+                if now - last >= hold {
+                    *slot = None;
+                    send(port, k as u8, false)?;
+                }
+            }
+        }
+    }
+    // Leave nothing held down on the board
+    for (k, slot) in held.iter().enumerate() {
+        if slot.is_some() {
+            send(port, k as u8, false)?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let arg = std::env::args().nth(1).unwrap_or_else(|| usage());
+    let mut port = match open(&arg) {
+        Ok(port) => port,
+        Err(e) => {
+            eprintln!("keysend: can't open {}: {}", arg, e);
+            std::process::exit(1);
+        }
+    };
+    println!("press keys to send them, Esc to quit\r");
+    if let Err(e) = terminal::enable_raw_mode() {
+        eprintln!("keysend: {}", e);
+        std::process::exit(1);
+    }
+    let result = run(&mut *port);
+    let _ = terminal::disable_raw_mode();
+    if let Err(e) = result {
+        eprintln!("keysend: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chip8::{InputSource, KEY_COUNT};
+    use serial_keys::{KeyEventParser, RemoteKeys};
+    use serialport::TTYPort;
+    use std::io::Read;
+
+    fn parse(bytes: &[u8]) -> Vec<KeyEvent> {
+        let mut parser = KeyEventParser::new();
+        bytes.iter().filter_map(|&b| parser.feed(b)).collect()
+    }
+
+    fn down(key: u8) -> KeyEvent {
+        KeyEvent { key, pressed: true }
+    }
+
+    fn up(key: u8) -> KeyEvent {
+        KeyEvent {
+            key,
+            pressed: false,
+        }
+    }
+
+    #[test]
+    fn every_event_goes_through_the_parser() {
+        let events: Vec<KeyEvent> = (0..KEY_COUNT as u8)
+            .flat_map(|k| [down(k), up(k)])
+            .collect();
+        let bytes: Vec<u8> = events
+            .iter()
+            .flat_map(|&e| serial_keys::encode(e))
+            .collect();
+        assert_eq!(parse(&bytes), events);
+    }
+
+    #[test]
+    fn keys_past_f_are_sent_as_their_low_digit() {
+        assert_eq!(serial_keys::encode(down(0x1A)), *b"+A");
+        assert_eq!(parse(&serial_keys::encode(up(0xFF))), [up(0xF)]);
+    }
+
+    #[test]
+    fn parser_resyncs_after_garbage() {
+        // Stray digits, a sign without a key and a sign followed by a non-hex
+        // byte are all skipped
+        assert_eq!(parse(b"5\r\n+-3"), [up(0x3)]);
+        assert_eq!(parse(b"+G+4xx-4"), [down(0x4), up(0x4)]);
+        assert_eq!(parse(b"++a-+b"), [down(0xA), down(0xB)]);
+        assert_eq!(parse(b"+ 7"), []);
+    }
+
+    fn held(remote: &mut RemoteKeys) -> Vec<u8> {
+        let mut keys = [0; KEY_COUNT];
+        remote.read_keys(&mut keys);
+        (0..KEY_COUNT as u8)
+            .filter(|&k| keys[k as usize] != 0)
+            .collect()
+    }
+
+    // Reads what has arrived on `port` into `remote`, returns whether a key
+    // went down
+    fn receive(port: &mut TTYPort, remote: &mut RemoteKeys, len: usize) -> bool {
+        let mut bytes = vec![0; len];
+        port.read_exact(&mut bytes).unwrap();
+        remote.feed(&bytes)
+    }
+
+    #[test]
+    fn remote_keys_follow_events_over_a_pty() {
+        let (mut host, mut device) = TTYPort::pair().unwrap();
+        device.set_timeout(Duration::from_secs(2)).unwrap();
+        let mut remote = RemoteKeys::new();
+
+        send(&mut host, 0x5, true).unwrap();
+        send(&mut host, 0xC, true).unwrap();
+        assert!(receive(&mut device, &mut remote, 4));
+        assert_eq!(held(&mut remote), [0x5, 0xC]);
+
+        send(&mut host, 0x5, false).unwrap();
+        assert!(!receive(&mut device, &mut remote, 2));
+        assert_eq!(held(&mut remote), [0xC]);
+
+        // A lost sender leaves nothing held down
+        remote.release_all();
+        assert_eq!(held(&mut remote), []);
+    }
+}
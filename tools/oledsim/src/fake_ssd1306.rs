@@ -0,0 +1,241 @@
+// An SSD1306 on a fake I2C bus, for running the firmware's display code on
+// the host. Every write is decoded like the controller does: a control byte
+// with the Co and D/C# bits, then commands or data. Data goes into a model
+// of the 128x64 GDDRAM at the address pointer, which moves on as the
+// addressing mode says. The commands the firmware and the ssd1306 crate
+// send are tracked, anything else is logged and ignored.
+//
+// From the SSD1306 datasheet, rev 1.1, sections 8.1.5 and 10.
+use embedded_hal::i2c::{ErrorKind, ErrorType, I2c, NoAcknowledgeSource, Operation};
+
+pub const WIDTH: usize = 128;
+pub const PAGES: usize = 8;
+pub const ADDRESS: u8 = 0x3C;
+
+// Control byte bits
+const CONTINUATION: u8 = 0x80;
+const DATA: u8 = 0x40;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AddressingMode {
+    Horizontal,
+    Vertical,
+    Page,
+}
+
+// The controller's registers, at their reset values from `new`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct State {
+    pub display_on: bool,
+    pub contrast: u8,
+    pub inverted: bool,
+    // 0xA5, every pixel lit whatever the RAM holds
+    pub entire_display_on: bool,
+    pub mode: AddressingMode,
+    pub column: usize,
+    pub page: usize,
+    // Window of the horizontal and vertical modes, inclusive
+    pub columns: (usize, usize),
+    pub pages: (usize, usize),
+    pub start_line: u8,
+    pub display_offset: u8,
+    pub multiplex: u8,
+    // 0xA1, column 127 drives SEG0
+    pub segment_remap: bool,
+    // 0xC8, COM scan from COM[N-1] to COM0
+    pub com_remap: bool,
+    pub charge_pump: bool,
+    pub scrolling: bool,
+}
+
+pub struct FakeSsd1306 {
+    pub gddram: [[u8; WIDTH]; PAGES],
+    pub state: State,
+    // Command bytes still to come: the command and the arguments so far
+    command: Vec<u8>,
+    // Commands that weren't understood
+    pub unknown: Vec<u8>,
+}
+
+impl FakeSsd1306 {
+    pub fn new() -> FakeSsd1306 {
+        FakeSsd1306 {
+            gddram: [[0; WIDTH]; PAGES],
+            state: State {
+                display_on: false,
+                contrast: 0x7F,
+                inverted: false,
+                entire_display_on: false,
+                mode: AddressingMode::Page,
+                column: 0,
+                page: 0,
+                columns: (0, WIDTH - 1),
+                pages: (0, PAGES - 1),
+                start_line: 0,
+                display_offset: 0,
+                multiplex: 63,
+                segment_remap: false,
+                com_remap: false,
+                charge_pump: false,
+                scrolling: false,
+            },
+            command: Vec::new(),
+            unknown: Vec::new(),
+        }
+    }
+
+    // Whether pixel (x, y) of the GDDRAM is set
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.gddram[y / 8][x] & (1 << (y % 8)) != 0
+    }
+
+    // One I2C write, starting with a control byte
+    fn write(&mut self, bytes: &[u8]) {
+        let mut bytes = bytes.iter();
+        while let Some(&control) = bytes.next() {
+            if control & CONTINUATION == 0 {
+                // The rest of the write is of one kind
+                for &byte in bytes.by_ref() {
+                    self.byte(control & DATA != 0, byte);
+                }
+            } else if let Some(&byte) = bytes.next() {
+                self.byte(control & DATA != 0, byte);
+            }
+        }
+    }
+
+    fn byte(&mut self, data: bool, byte: u8) {
+        if data {
+            self.data(byte);
+        } else {
+            self.command.push(byte);
+            if self.command.len() == 1 + arguments(self.command[0]) {
+                let command = std::mem::take(&mut self.command);
+                self.run(command[0], &command[1..]);
+            }
+        }
+    }
+
+    fn data(&mut self, byte: u8) {
+        let state = &mut self.state;
+        self.gddram[state.page][state.column] = byte;
+        match state.mode {
+            // The page stays, the column wraps around to the start
+            AddressingMode::Page => state.column = (state.column + 1) % WIDTH,
+            AddressingMode::Horizontal => {
+                state.column += 1;
+                if state.column > state.columns.1 {
+                    state.column = state.columns.0;
+                    state.page = next_in(state.page, state.pages);
+                }
+            }
+            AddressingMode::Vertical => {
+                state.page += 1;
+                if state.page > state.pages.1 {
+                    state.page = state.pages.0;
+                    state.column = next_in(state.column, state.columns);
+                }
+            }
+        }
+    }
+
+    fn run(&mut self, command: u8, args: &[u8]) {
+        let state = &mut self.state;
+        let page_mode = state.mode == AddressingMode::Page;
+        match command {
+            // Column start and page start only apply in page addressing mode
+            0x00..=0x0F | 0x10..=0x1F | 0xB0..=0xB7 if !page_mode => {
+                log::warn!("{:02X} ignored outside page addressing mode", command);
+            }
+            0x00..=0x0F => state.column = (state.column & 0xF0) | (command & 0x0F) as usize,
+            0x10..=0x1F => {
+                state.column = ((command & 0x0F) as usize) << 4 | (state.column & 0x0F);
+                if state.column >= WIDTH {
+                    log::warn!("column {} is past the end of the RAM", state.column);
+                    state.column %= WIDTH;
+                }
+            }
+            0xB0..=0xB7 => state.page = (command & 0x07) as usize,
+            0x20 => {
+                state.mode = match args[0] & 0x03 {
+                    0x00 => AddressingMode::Horizontal,
+                    0x01 => AddressingMode::Vertical,
+                    _ => AddressingMode::Page,
+                }
+            }
+            0x21 => {
+                state.columns = ((args[0] & 0x7F) as usize, (args[1] & 0x7F) as usize);
+                state.column = state.columns.0;
+            }
+            0x22 => {
+                state.pages = ((args[0] & 0x07) as usize, (args[1] & 0x07) as usize);
+                state.page = state.pages.0;
+            }
+            0x40..=0x7F => state.start_line = command & 0x3F,
+            0x81 => state.contrast = args[0],
+            0x8D => state.charge_pump = args[0] & 0x04 != 0,
+            0xA0 | 0xA1 => state.segment_remap = command == 0xA1,
+            0xA4 | 0xA5 => state.entire_display_on = command == 0xA5,
+            0xA6 | 0xA7 => state.inverted = command == 0xA7,
+            0xA8 => state.multiplex = args[0] & 0x3F,
+            0xAE | 0xAF => state.display_on = command == 0xAF,
+            0xC0 | 0xC8 => state.com_remap = command == 0xC8,
+            0xD3 => state.display_offset = args[0] & 0x3F,
+            0x2E => state.scrolling = false,
+            0x2F => state.scrolling = true,
+            // Timing and electrical settings, and scroll setup, have no
+            // effect on the picture in the RAM
+            0x26 | 0x27 | 0x29 | 0x2A | 0xA3 | 0xD5 | 0xD9 | 0xDA | 0xDB | 0xE3 => {}
+            _ => {
+                log::warn!("unknown command {:02X}", command);
+                self.unknown.push(command);
+            }
+        }
+    }
+}
+
+// Arguments that follow each command
+fn arguments(command: u8) -> usize {
+    match command {
+        0x20 | 0x81 | 0x8D | 0xA8 | 0xD3 | 0xD5 | 0xD9 | 0xDA | 0xDB => 1,
+        0x21 | 0x22 | 0xA3 => 2,
+        0x29 | 0x2A => 5,
+        0x26 | 0x27 => 6,
+        _ => 0,
+    }
+}
+
+fn next_in(value: usize, (start, end): (usize, usize)) -> usize {
+    if value >= end {
+        start
+    } else {
+        value + 1
+    }
+}
+
+impl ErrorType for FakeSsd1306 {
+    type Error = ErrorKind;
+}
+
+impl I2c for FakeSsd1306 {
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        if address != ADDRESS {
+            return Err(ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address));
+        }
+        for operation in operations {
+            match operation {
+                Operation::Write(bytes) => self.write(bytes),
+                // The status byte, bit 6 is set while the display is off
+                Operation::Read(buffer) => {
+                    let status = if self.state.display_on { 0x00 } else { 0x40 };
+                    buffer.fill(status);
+                }
+            }
+        }
+        Ok(())
+    }
+}
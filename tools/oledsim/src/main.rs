@@ -0,0 +1,477 @@
+// Runs a ROM through the firmware's display path into an emulated SSD1306,
+// for testing the display code without a board. The panel is set up with
+// Panel::init through the ssd1306 crate's I2C interface, like on the board,
+// then each frame is rendered with display::render_frame and pushed by
+// FrameFlusher page by page. --blocking sends the frames with
+// send_frame_blocking instead, the fault screen's path.
+//
+// The I2C bus is fake_ssd1306.rs, which decodes the bytes like the
+// controller would. Afterwards the controller's GDDRAM has to hold the last
+// frame and the display has to be on, or the tool fails. The GDDRAM is
+// written out as a 128x64 PBM image, lit pixels white, and the controller's
+// registers are printed. RUST_LOG=warn shows commands the controller would
+// ignore.
+//
+// --contrast, --invert and --rotate send the display settings of the same
+// name after init, like apply_settings does on the board.
+#[allow(dead_code)]
+#[path = "../../../src/chip8.rs"]
+mod chip8;
+#[allow(dead_code)]
+#[path = "../../../src/display.rs"]
+mod display;
+mod fake_ssd1306;
+#[path = "../../../src/logging.rs"]
+mod logging;
+#[allow(dead_code)]
+#[path = "../../../src/panel.rs"]
+mod panel;
+
+use chip8::{Chip8, InputSource, KEY_COUNT};
+use display::{
+    FrameBuffer, FrameFlusher, FrameTransport, COMMAND_CONTROL_BYTE, I2C_COMMANDS_PER_WRITE,
+    MAX_PAGES, PAGE_BYTES,
+};
+use display_interface::{DataFormat, WriteOnlyDataCommand};
+use embedded_hal::i2c::{ErrorKind, I2c};
+use fake_ssd1306::{AddressingMode, FakeSsd1306, ADDRESS, PAGES, WIDTH};
+use panel::Panel;
+use ssd1306::I2CDisplayInterface;
+use std::io::Write;
+
+// The firmware's speed for ROMs without a .meta file
+const INSTRUCTIONS_PER_FRAME: u32 = 10;
+const FRAMES: u32 = 60;
+
+// No keys are pressed
+struct NoKeys;
+
+impl InputSource for NoKeys {
+    fn read_keys(&mut self, keys: &mut [u8; KEY_COUNT]) {
+        keys.fill(0);
+    }
+}
+
+// The writes I2cDmaTransport makes on the board, blocking. The bytes come
+// from the same helpers in display.rs
+struct I2cTransport<'a>(&'a mut FakeSsd1306);
+
+impl FrameTransport for I2cTransport<'_> {
+    type Error = ErrorKind;
+
+    fn start_page(&mut self, page: u8, column: u8, data: &'static [u8]) -> Result<(), Self::Error> {
+        let mut commands = vec![COMMAND_CONTROL_BYTE];
+        commands.extend(display::page_commands(page, column));
+        self.0.write(ADDRESS, &commands)?;
+        self.0.write(ADDRESS, data)
+    }
+
+    fn is_busy(&mut self) -> bool {
+        false
+    }
+
+    fn send_commands(&mut self, commands: &[u8]) -> Result<(), Self::Error> {
+        for chunk in commands.chunks(I2C_COMMANDS_PER_WRITE) {
+            let mut bytes = vec![COMMAND_CONTROL_BYTE];
+            bytes.extend_from_slice(chunk);
+            self.0.write(ADDRESS, &bytes)?;
+        }
+        Ok(())
+    }
+}
+
+struct Options {
+    rom: String,
+    image: String,
+    panel: String,
+    frames: u32,
+    ipf: u32,
+    contrast: Option<u8>,
+    inverted: bool,
+    rotated: bool,
+    blocking: bool,
+}
+
+fn parse_args(args: &[String]) -> Option<Options> {
+    let mut options = Options {
+        rom: String::new(),
+        image: String::new(),
+        panel: "ssd1306".into(),
+        frames: FRAMES,
+        ipf: INSTRUCTIONS_PER_FRAME,
+        contrast: None,
+        inverted: false,
+        rotated: false,
+        blocking: false,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--panel" => options.panel = args.next()?.clone(),
+            "--frames" => options.frames = args.next()?.parse().ok()?,
+            "--ipf" => options.ipf = args.next()?.parse().ok()?,
+            "--contrast" => options.contrast = Some(args.next()?.parse().ok()?),
+            "--invert" => options.inverted = true,
+            "--rotate" => options.rotated = true,
+            "--blocking" => options.blocking = true,
+            path if !path.starts_with('-') && options.rom.is_empty() => options.rom = path.into(),
+            path if !path.starts_with('-') && options.image.is_empty() => {
+                options.image = path.into()
+            }
+            _ => return None,
+        }
+    }
+    (!options.image.is_empty()).then_some(options)
+}
+
+// The display settings to send after init, empty without any of the options
+fn settings_commands<P: Panel>(options: &Options) -> Vec<u8> {
+    let mut commands = Vec::new();
+    if let Some(level) = options.contrast {
+        commands.extend(P::contrast_commands(level));
+    }
+    if options.rotated {
+        commands.extend(P::rotation_commands(true));
+    }
+    if options.inverted {
+        commands.push(P::invert_command(true));
+    }
+    commands
+}
+
+fn step(chip8: &mut Chip8, ipf: u32) -> Result<(), String> {
+    chip8.poll_input(&mut NoKeys);
+    for _ in 0..ipf {
+        chip8.emulate_cycle().map_err(|e| e.to_string())?;
+    }
+    chip8.tick_timers();
+    Ok(())
+}
+
+fn write_failed(e: impl std::fmt::Debug) -> String {
+    format!("display write failed: {:?}", e)
+}
+
+// Runs the ROM into `device` and returns the last frame rendered
+fn drive<P: Panel>(
+    device: &mut FakeSsd1306,
+    chip8: &mut Chip8,
+    options: &Options,
+) -> Result<FrameBuffer, String> {
+    let mut interface = I2CDisplayInterface::new(&mut *device);
+    P::init(&mut interface).map_err(write_failed)?;
+    let commands = settings_commands::<P>(options);
+    let mut last: FrameBuffer = [[0; PAGE_BYTES]; MAX_PAGES];
+
+    if options.blocking {
+        if !commands.is_empty() {
+            interface
+                .send_commands(DataFormat::U8(&commands))
+                .map_err(write_failed)?;
+        }
+        for _ in 0..options.frames {
+            step(chip8, options.ipf)?;
+            display::render_frame::<P>(&chip8.screen, &mut last);
+            display::send_frame_blocking::<P, _>(&mut interface, &last).map_err(write_failed)?;
+        }
+        return Ok(last);
+    }
+
+    let buffers = Box::leak(Box::new([[[0; PAGE_BYTES]; MAX_PAGES]; 2]));
+    let mut flusher = FrameFlusher::<P, _>::new(I2cTransport(&mut *device), buffers);
+    if !commands.is_empty() {
+        flusher.send_commands(&commands).map_err(write_failed)?;
+    }
+    for _ in 0..options.frames {
+        step(chip8, options.ipf)?;
+        display::render_frame::<P>(&chip8.screen, flusher.back_buffer());
+        display::render_frame::<P>(&chip8.screen, &mut last);
+        flusher.present().map_err(write_failed)?;
+        while flusher.is_busy().map_err(write_failed)? {}
+    }
+    Ok(last)
+}
+
+// What the board would have on its panel doesn't match the frame
+fn check<P: Panel>(device: &FakeSsd1306, frame: &FrameBuffer) -> Result<(), String> {
+    if let Some(command) = device.unknown.first() {
+        return Err(format!(
+            "the controller got unknown command {:02X}",
+            command
+        ));
+    }
+    if !device.state.display_on {
+        return Err("the display was left off".into());
+    }
+    for (page, data) in frame.iter().take(P::PAGES).enumerate() {
+        for x in 0..P::WIDTH {
+            let column = P::COLUMN_OFFSET as usize + x;
+            let (ram, expected) = (device.gddram[page][column], data[1 + x]);
+            if ram != expected {
+                return Err(format!(
+                    "GDDRAM page {} column {} holds {:02X}, the frame has {:02X}",
+                    page, column, ram, expected
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn write_image(device: &FakeSsd1306, path: &str) -> std::io::Result<()> {
+    let height = PAGES * 8;
+    let mut bytes = format!("P4\n{} {}\n", WIDTH, height).into_bytes();
+    for y in 0..height {
+        for x in (0..WIDTH).step_by(8) {
+            // PBM bits are black, so only unlit pixels are set
+            let row = (0..8).fold(0u8, |row, bit| {
+                row | (!device.pixel(x + bit, y) as u8) << (7 - bit)
+            });
+            bytes.push(row);
+        }
+    }
+    std::fs::File::create(path)?.write_all(&bytes)
+}
+
+fn print_state(device: &FakeSsd1306) {
+    let state = &device.state;
+    let on_off = |on: bool| if on { "on" } else { "off" };
+    let mode = match state.mode {
+        AddressingMode::Horizontal => "horizontal",
+        AddressingMode::Vertical => "vertical",
+        AddressingMode::Page => "page",
+    };
+    println!(
+        "display {}, contrast {:02X}, inverted {}, entire display on {}",
+        on_off(state.display_on),
+        state.contrast,
+        on_off(state.inverted),
+        on_off(state.entire_display_on)
+    );
+    println!(
+        "{} addressing, segment remap {}, COM scan remap {}",
+        mode,
+        on_off(state.segment_remap),
+        on_off(state.com_remap)
+    );
+    println!(
+        "multiplex {}, start line {}, display offset {}, charge pump {}, scrolling {}",
+        state.multiplex as usize + 1,
+        state.start_line,
+        state.display_offset,
+        on_off(state.charge_pump),
+        on_off(state.scrolling)
+    );
+}
+
+fn simulate<P: Panel>(options: &Options) -> Result<(), String> {
+    let program = std::fs::read(&options.rom)
+        .map_err(|e| format!("failed to read {}: {}", options.rom, e))?;
+    let mut chip8 = Chip8::new();
+    chip8.load_program(&program).map_err(|e| e.to_string())?;
+    let mut device = FakeSsd1306::new();
+    let frame = drive::<P>(&mut device, &mut chip8, options)?;
+    print_state(&device);
+    write_image(&device, &options.image)
+        .map_err(|e| format!("failed to write {}: {}", options.image, e))?;
+    check::<P>(&device, &frame)?;
+    println!("GDDRAM matches the last frame");
+    Ok(())
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    // Named like the firmware's panel-* features. The SH1106 and ST7565
+    // have their own command sets and aren't emulated.
+    match options.panel.as_str() {
+        "ssd1306" => simulate::<panel::Ssd1306x64>(options),
+        "ssd1306-128x32" => simulate::<panel::Ssd1306x32>(options),
+        "ssd1309" => simulate::<panel::Ssd1309>(options),
+        name => Err(format!("no emulated controller for panel {}", name)),
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some(options) = parse_args(&args) else {
+        eprintln!("usage: oledsim <rom.ch8> <out.pbm> [--panel <panel>] [--frames N] [--ipf N]");
+        eprintln!("               [--contrast N] [--invert] [--rotate] [--blocking]");
+        eprintln!("panels: ssd1306, ssd1306-128x32, ssd1309");
+        std::process::exit(2);
+    };
+    if let Err(e) = run(&options) {
+        eprintln!("oledsim: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use display::FlushState;
+    use panel::{Ssd1306x32, Ssd1306x64, Ssd1309};
+
+    const ROM: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/../../roms/Chip8 Picture.ch8");
+
+    fn options() -> Options {
+        let args = [ROM.to_string(), "unused.pbm".to_string()];
+        parse_args(&args).unwrap()
+    }
+
+    // Runs the ROM into a fresh controller, checks GDDRAM against the last
+    // frame and hands the controller back
+    fn simulated<P: Panel>(options: &Options) -> FakeSsd1306 {
+        let mut chip8 = Chip8::new();
+        chip8.load_program(&std::fs::read(ROM).unwrap()).unwrap();
+        let mut device = FakeSsd1306::new();
+        let frame = drive::<P>(&mut device, &mut chip8, options).unwrap();
+        // A blank frame would match a controller that got nothing
+        assert!(frame
+            .iter()
+            .take(P::PAGES)
+            .any(|page| page[1..].iter().any(|&b| b != 0)));
+        check::<P>(&device, &frame).unwrap();
+        device
+    }
+
+    #[test]
+    fn flushed_frames_reach_gddram() {
+        let options = options();
+        simulated::<Ssd1306x64>(&options);
+        simulated::<Ssd1306x32>(&options);
+        simulated::<Ssd1309>(&options);
+    }
+
+    #[test]
+    fn blocking_frames_reach_gddram() {
+        let options = Options {
+            blocking: true,
+            ..options()
+        };
+        simulated::<Ssd1306x64>(&options);
+        simulated::<Ssd1306x32>(&options);
+        simulated::<Ssd1309>(&options);
+    }
+
+    #[test]
+    fn settings_reach_the_controller() {
+        let plain = simulated::<Ssd1306x64>(&options());
+        for blocking in [false, true] {
+            let options = Options {
+                contrast: Some(0x20),
+                inverted: true,
+                rotated: true,
+                blocking,
+                ..options()
+            };
+            let device = simulated::<Ssd1306x64>(&options);
+            assert_eq!(device.state.contrast, 0x20);
+            assert!(device.state.inverted);
+            assert_ne!(device.state.segment_remap, plain.state.segment_remap);
+            assert_ne!(device.state.com_remap, plain.state.com_remap);
+        }
+    }
+
+    #[test]
+    fn check_catches_a_wrong_byte() {
+        let mut chip8 = Chip8::new();
+        chip8.load_program(&std::fs::read(ROM).unwrap()).unwrap();
+        let mut device = FakeSsd1306::new();
+        let frame = drive::<Ssd1306x64>(&mut device, &mut chip8, &options()).unwrap();
+        device.gddram[3][40] ^= 0x10;
+        assert!(check::<Ssd1306x64>(&device, &frame).is_err());
+    }
+
+    // Stays busy after each page, like the DMA, until `complete` is called.
+    // Records the page, the column and the first data byte after the control
+    // byte of each page started.
+    #[derive(Default)]
+    struct BusyTransport {
+        busy: bool,
+        pages: Vec<(u8, u8, u8)>,
+    }
+
+    impl BusyTransport {
+        fn complete(&mut self) {
+            self.busy = false;
+        }
+    }
+
+    impl FrameTransport for BusyTransport {
+        type Error = ();
+
+        fn start_page(&mut self, page: u8, column: u8, data: &'static [u8]) -> Result<(), ()> {
+            assert!(!self.busy, "page {} started during a transfer", page);
+            self.busy = true;
+            self.pages.push((page, column, data[1]));
+            Ok(())
+        }
+
+        fn is_busy(&mut self) -> bool {
+            self.busy
+        }
+
+        fn send_commands(&mut self, _commands: &[u8]) -> Result<(), ()> {
+            assert!(!self.busy, "commands sent during a transfer");
+            Ok(())
+        }
+    }
+
+    fn flusher() -> FrameFlusher<Ssd1306x64, BusyTransport> {
+        let buffers = Box::leak(Box::new([[[0; PAGE_BYTES]; MAX_PAGES]; 2]));
+        FrameFlusher::new(BusyTransport::default(), buffers)
+    }
+
+    // Marks every page of the back buffer with `value` in its first column
+    fn fill(flusher: &mut FrameFlusher<Ssd1306x64, BusyTransport>, value: u8) {
+        for page in flusher.back_buffer().iter_mut() {
+            page[1] = value;
+        }
+    }
+
+    #[test]
+    fn present_is_refused_while_a_frame_is_in_flight() {
+        let mut flusher = flusher();
+        assert_eq!(flusher.present(), Ok(true));
+        assert_eq!(flusher.present(), Ok(false));
+        assert_eq!(flusher.transport().pages.len(), 1);
+    }
+
+    #[test]
+    fn poll_moves_on_one_page_per_completion() {
+        let mut flusher = flusher();
+        flusher.present().unwrap();
+        for page in 0..Ssd1306x64::PAGES as u8 {
+            // Nothing moves while the page is still being sent
+            assert_eq!(flusher.poll(), Ok(FlushState::Sending(page)));
+            assert_eq!(flusher.poll(), Ok(FlushState::Sending(page)));
+            assert_eq!(flusher.transport().pages.len(), page as usize + 1);
+            flusher.transport().complete();
+        }
+        assert_eq!(flusher.poll(), Ok(FlushState::Idle));
+        let pages: Vec<u8> = flusher.transport().pages.iter().map(|p| p.0).collect();
+        assert_eq!(pages, (0..Ssd1306x64::PAGES as u8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn second_present_waits_for_the_flush_to_finish() {
+        let mut flusher = flusher();
+        fill(&mut flusher, 0xAA);
+        flusher.present().unwrap();
+        // The next frame goes into the other buffer while the first is sent
+        fill(&mut flusher, 0x55);
+        for _ in 0..Ssd1306x64::PAGES - 1 {
+            assert_eq!(flusher.present(), Ok(false));
+            flusher.transport().complete();
+            flusher.poll().unwrap();
+        }
+        assert_eq!(flusher.present(), Ok(false));
+        flusher.transport().complete();
+        assert_eq!(flusher.present(), Ok(true));
+
+        let sent: Vec<u8> = flusher.transport().pages.iter().map(|p| p.2).collect();
+        let mut expected = vec![0xAA; Ssd1306x64::PAGES];
+        expected.push(0x55);
+        assert_eq!(sent, expected);
+    }
+}
@@ -0,0 +1,211 @@
+// Sends a ROM to the board's upload UART, which runs it straight away.
+//
+// Without a board, --fake-device opens a pseudo terminal that answers like
+// the firmware does, and --loopback runs both ends in this process to check
+// a ROM goes through the framing intact.
+#[path = "../../../src/crc.rs"]
+mod crc;
+#[allow(dead_code)]
+#[path = "../../../src/upload.rs"]
+mod upload;
+
+use serialport::{SerialPort, TTYPort};
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+use upload::{UploadError, UploadReceiver, ACK, NAK};
+
+const BAUD_RATE: u32 = 115_200;
+// A frame of the largest ROM takes about 310 ms at 115200 baud
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+// A bad CRC is most likely a dropped byte, worth trying again
+const ATTEMPTS: u32 = 3;
+
+fn usage() -> ! {
+    eprintln!("usage: romupload <serial port> <rom.ch8>");
+    eprintln!("       romupload --fake-device");
+    eprintln!("       romupload --loopback <rom.ch8>");
+    std::process::exit(2);
+}
+
+#[derive(Debug)]
+enum Outcome {
+    Accepted,
+    Rejected(Option<UploadError>),
+    NoReply,
+}
+
+fn send_frame(port: &mut dyn SerialPort, image: &[u8]) -> io::Result<Outcome> {
+    let (header, crc) = upload::frame(image);
+    port.clear(serialport::ClearBuffer::Input)?;
+    port.write_all(&header)?;
+    port.write_all(image)?;
+    port.write_all(&crc)?;
+    port.flush()?;
+
+    let deadline = Instant::now() + REPLY_TIMEOUT;
+    let mut byte = [0u8];
+    let mut nak = false;
+    while Instant::now() < deadline {
+        match port.read(&mut byte) {
+            Ok(0) => {}
+            Ok(_) if nak => return Ok(Outcome::Rejected(UploadError::from_code(byte[0]))),
+            Ok(_) if byte[0] == ACK => return Ok(Outcome::Accepted),
+            Ok(_) if byte[0] == NAK => nak = true,
+            // Anything else is line noise from before the frame
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(if nak {
+        Outcome::Rejected(None)
+    } else {
+        Outcome::NoReply
+    })
+}
+
+fn upload(port: &mut dyn SerialPort, image: &[u8]) -> Result<(), String> {
+    if image.len() > upload::MAX_IMAGE_BYTES {
+        return Err(UploadError::TooLarge.to_string());
+    }
+    for attempt in 1..=ATTEMPTS {
+        match send_frame(port, image).map_err(|e| e.to_string())? {
+            Outcome::Accepted => return Ok(()),
+            Outcome::Rejected(Some(UploadError::BadCrc)) | Outcome::NoReply
+                if attempt < ATTEMPTS =>
+            {
+                eprintln!("attempt {} failed, retrying", attempt);
+            }
+            Outcome::Rejected(Some(e)) => return Err(format!("board rejected the ROM: {}", e)),
+            Outcome::Rejected(None) => return Err("board rejected the ROM".into()),
+            Outcome::NoReply => return Err("no reply from the board".into()),
+        }
+    }
+    unreachable!()
+}
+
+// Answers frames on `port` the way the firmware does. Returns after
+// `frames` frames, or never with None.
+fn fake_device(port: &mut TTYPort, frames: Option<usize>) -> io::Result<Vec<Vec<u8>>> {
+    let start = Instant::now();
+    let mut receiver = UploadReceiver::new();
+    let mut images = Vec::new();
+    let mut buf = [0u8; 256];
+    while frames != Some(images.len()) {
+        let count = match port.read(&mut buf) {
+            Ok(count) => count,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        let now_ms = start.elapsed().as_millis() as u32;
+        for &byte in &buf[..count] {
+            if let Some(result) = receiver.feed(byte, now_ms) {
+                match result {
+                    Ok(image) => {
+                        println!("device: received {} bytes", image.len());
+                        images.push(image.to_vec());
+                    }
+                    Err(e) => println!("device: rejected frame, {}", e),
+                }
+                port.write_all(upload::reply(result.map(|_| ())))?;
+            }
+        }
+    }
+    Ok(images)
+}
+
+fn pty_pair() -> Result<(TTYPort, TTYPort), String> {
+    let (mut device, mut host) = TTYPort::pair().map_err(|e| e.to_string())?;
+    for port in [&mut device, &mut host] {
+        port.set_timeout(Duration::from_millis(100))
+            .map_err(|e| e.to_string())?;
+    }
+    Ok((device, host))
+}
+
+// Uploads `image` to a fake device in this process, returns what it received
+fn loopback(image: &[u8]) -> Result<Vec<u8>, String> {
+    let (device, mut host) = pty_pair()?;
+    // The device end is handed back rather than closed, so the reply is
+    // still there to read once the thread is done
+    let device = std::thread::spawn(move || {
+        let mut device = device;
+        fake_device(&mut device, Some(1)).map(|images| (device, images))
+    });
+    upload(&mut host, image)?;
+    let (_device, mut images) = device.join().unwrap().map_err(|e| e.to_string())?;
+    Ok(images.remove(0))
+}
+
+fn read_rom(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))
+}
+
+fn run(args: &[String]) -> Result<(), String> {
+    match args {
+        [flag] if flag == "--fake-device" => {
+            let (mut device, host) = pty_pair()?;
+            println!(
+                "fake device on {}",
+                host.name().unwrap_or_else(|| "?".into())
+            );
+            // The host end has to stay open or reads on the device end fail
+            std::mem::forget(host);
+            fake_device(&mut device, None).map_err(|e| e.to_string())?;
+            Ok(())
+        }
+        [flag, rom] if flag == "--loopback" => {
+            let image = read_rom(rom)?;
+            if loopback(&image)? != image {
+                return Err("the device received a different image".into());
+            }
+            println!("loopback ok, {} bytes", image.len());
+            Ok(())
+        }
+        [port, rom] if !port.starts_with('-') => {
+            let image = read_rom(rom)?;
+            let mut port = serialport::new(port, BAUD_RATE)
+                .timeout(Duration::from_millis(100))
+                .open()
+                .map_err(|e| format!("failed to open {}: {}", port, e))?;
+            upload(port.as_mut(), &image)?;
+            println!("uploaded {} bytes", image.len());
+            Ok(())
+        }
+        _ => usage(),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if let Err(e) = run(&args) {
+        eprintln!("romupload: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bundled_rom_goes_through_intact() {
+        let rom = concat!(env!("CARGO_MANIFEST_DIR"), "/../../roms/tetris.ch8");
+        let image = read_rom(rom).unwrap();
+        assert_eq!(loopback(&image).unwrap(), image);
+    }
+
+    #[test]
+    fn largest_image_goes_through_intact() {
+        let image: Vec<u8> = (0..upload::MAX_IMAGE_BYTES).map(|i| i as u8).collect();
+        assert_eq!(loopback(&image).unwrap(), image);
+    }
+
+    #[test]
+    fn oversized_image_is_refused_before_sending() {
+        let (_device, mut host) = pty_pair().unwrap();
+        let image = vec![0; upload::MAX_IMAGE_BYTES + 1];
+        let error = upload(&mut host, &image).unwrap_err();
+        assert_eq!(error, UploadError::TooLarge.to_string());
+    }
+}
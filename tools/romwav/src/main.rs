@@ -0,0 +1,239 @@
+// Runs a ROM on the host and writes what the buzzer would play to a WAV file,
+// for checking sound without a board. No keys are pressed.
+//
+// Runs in 60 Hz frames like the board: the frame's instructions, then one
+// tick of the timers. The speed and quirks come from the ROM's .meta file
+// when it has one, like for the ROMs bundled from roms/.
+#[allow(dead_code)]
+#[path = "../../../src/audio.rs"]
+mod audio;
+#[allow(dead_code)]
+#[path = "../../../src/chip8.rs"]
+mod chip8;
+#[path = "../../../src/logging.rs"]
+mod logging;
+#[allow(dead_code)]
+#[path = "../../../src/rom_meta.rs"]
+mod rom_meta;
+
+use audio::{AudioSettings, AudioSink, Sound};
+use chip8::{Chip8, Quirks};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+const SAMPLE_RATE: u32 = 44_100;
+const FRAMES_PER_SECOND: u32 = 60;
+
+// One bit samples of the beep at a fixed sample rate
+struct SampleGenerator {
+    sample_rate: u32,
+    // Fraction of a half period, in 1 / sample_rate units
+    accumulator: u32,
+    // Half periods of the tone played so far
+    step: u32,
+}
+
+impl SampleGenerator {
+    fn new(sample_rate: u32) -> SampleGenerator {
+        SampleGenerator {
+            sample_rate,
+            accumulator: 0,
+            step: 0,
+        }
+    }
+
+    fn next(&mut self, sound: Sound, settings: &AudioSettings) -> bool {
+        if sound == Sound::Silent {
+            self.accumulator = 0;
+            self.step = 0;
+            return false;
+        }
+        let level = self.step & 1 == 0;
+        self.accumulator += settings.tone_hz as u32 * 2;
+        self.step = self.step.wrapping_add(self.accumulator / self.sample_rate);
+        self.accumulator %= self.sample_rate;
+        level
+    }
+}
+
+struct WavSink {
+    writer: WavWriter<BufWriter<File>>,
+    generator: SampleGenerator,
+    sound: Sound,
+    settings: AudioSettings,
+}
+
+impl WavSink {
+    fn create(path: &str) -> hound::Result<WavSink> {
+        let spec = WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        };
+        Ok(WavSink {
+            writer: WavWriter::create(path, spec)?,
+            generator: SampleGenerator::new(SAMPLE_RATE),
+            sound: Sound::Silent,
+            settings: AudioSettings::default(),
+        })
+    }
+
+    // Write `count` samples of the current sound
+    fn advance(&mut self, count: u32) -> hound::Result<()> {
+        let amplitude = i16::MAX as i32 * self.settings.volume.min(100) as i32 / 100;
+        for _ in 0..count {
+            let sample = match self.sound {
+                Sound::Silent => 0,
+                _ if self.generator.next(self.sound, &self.settings) => amplitude,
+                _ => -amplitude,
+            };
+            self.writer.write_sample(sample as i16)?;
+        }
+        Ok(())
+    }
+}
+
+impl AudioSink for WavSink {
+    fn play(&mut self, sound: Sound, settings: &AudioSettings) {
+        self.sound = sound;
+        self.settings = *settings;
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 || args.len() > 4 {
+        eprintln!("usage: romwav <rom.ch8> <out.wav> [seconds]");
+        std::process::exit(2);
+    }
+    let seconds: u32 = match args.get(3).map(|s| s.parse()) {
+        None => 10,
+        Some(Ok(seconds)) => seconds,
+        Some(Err(_)) => {
+            eprintln!("romwav: seconds must be a whole number");
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = run(&args[1], &args[2], seconds) {
+        eprintln!("romwav: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run(rom: &str, out: &str, seconds: u32) -> Result<(), Box<dyn std::error::Error>> {
+    let program = std::fs::read(rom)?;
+    let meta_path = Path::new(rom).with_extension("meta");
+    let meta =
+        rom_meta::parse_meta(&meta_path).map_err(|e| format!("{}: {}", meta_path.display(), e))?;
+    let mut chip8 = Chip8::new();
+    chip8.quirks = Quirks::from_bits(meta.quirk_bits());
+    chip8.load_program(&program).map_err(|e| e.to_string())?;
+    let mut sink = WavSink::create(out)?;
+    let settings = AudioSettings::default();
+
+    let samples_per_frame = SAMPLE_RATE / FRAMES_PER_SECOND;
+    for _ in 0..seconds * FRAMES_PER_SECOND {
+        chip8.tick_timers();
+        for _ in 0..meta.ipf {
+            chip8.emulate_cycle().map_err(|e| e.to_string())?;
+        }
+        sink.play(audio::sound_for(&chip8), &settings);
+        sink.advance(samples_per_frame)?;
+    }
+    sink.writer.finalize()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // A ROM and its WAV in the temp directory, removed again on drop
+    struct TempRom(PathBuf);
+
+    impl TempRom {
+        fn new(name: &str, program: &[u8], meta: Option<&str>) -> TempRom {
+            let file = format!("romwav-{}-{}", std::process::id(), name);
+            let rom = TempRom(std::env::temp_dir().join(file));
+            std::fs::write(rom.path("ch8"), program).unwrap();
+            if let Some(meta) = meta {
+                std::fs::write(rom.path("meta"), meta).unwrap();
+            }
+            rom
+        }
+
+        fn path(&self, extension: &str) -> String {
+            let path = self.0.with_extension(extension);
+            path.to_string_lossy().into_owned()
+        }
+
+        // Runs the ROM for a second, returns the samples written
+        fn render(&self) -> Vec<i16> {
+            run(&self.path("ch8"), &self.path("wav"), 1).unwrap();
+            let mut reader = hound::WavReader::open(self.path("wav")).unwrap();
+            reader.samples().map(Result::unwrap).collect()
+        }
+    }
+
+    impl Drop for TempRom {
+        fn drop(&mut self) {
+            for extension in ["ch8", "meta", "wav"] {
+                let _ = std::fs::remove_file(self.path(extension));
+            }
+        }
+    }
+
+    // Start and end of each run of samples with the tone on
+    fn tone_spans(samples: &[i16]) -> Vec<(usize, usize)> {
+        let mut spans = Vec::new();
+        let mut start = None;
+        for (at, &sample) in samples.iter().chain([&0]).enumerate() {
+            match (start, sample != 0) {
+                (None, true) => start = Some(at),
+                (Some(from), false) => {
+                    spans.push((from, at));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        spans
+    }
+
+    const FRAME: usize = (SAMPLE_RATE / FRAMES_PER_SECOND) as usize;
+
+    #[test]
+    fn sound_timer_sets_the_length_of_the_tone() {
+        // LD V0, 0A; LD ST, V0; then loop
+        let rom = TempRom::new("st", &[0x60, 0x0A, 0xF0, 0x18, 0x12, 0x04], None);
+        let samples = rom.render();
+        assert_eq!(samples.len(), SAMPLE_RATE as usize);
+        // Everything runs in the first frame, the timer counts down over the
+        // next nine
+        assert_eq!(tone_spans(&samples), [(0, 10 * FRAME)]);
+    }
+
+    #[test]
+    fn speed_and_quirks_come_from_the_meta_file() {
+        // LD V0, 0A; JP V0, 206 lands on 206 only with jump_vx (V2 is 0),
+        // where LD ST, V0 starts the tone. Without it the jump lands on the
+        // silent loop at 210
+        let program = [
+            0x60, 0x0A, 0xB2, 0x06, 0x00, 0x00, 0xF0, 0x18, 0x12, 0x08, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x12, 0x10,
+        ];
+        let rom = TempRom::new("meta", &program, Some("ipf = 1\nquirks = jump_vx\n"));
+        let samples = rom.render();
+        assert_eq!(samples.len(), SAMPLE_RATE as usize);
+        // One instruction a frame, LD ST runs in the third
+        assert_eq!(tone_spans(&samples), [(2 * FRAME, 12 * FRAME)]);
+
+        let rom = TempRom::new("plain", &program, None);
+        assert_eq!(tone_spans(&rom.render()), []);
+    }
+}
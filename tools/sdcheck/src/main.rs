@@ -0,0 +1,279 @@
+// Runs the firmware's SD card ROM library against a disk image instead of a
+// card. `make` writes an image the way a PC would format a card, with an MBR
+// and one FAT16 partition holding the given ROMs. `check` lists and loads
+// the ROMs through the firmware code, saves and loads a state, and compares
+// everything with what an independent FAT implementation reads.
+#[allow(dead_code)]
+#[path = "../../../src/chip8.rs"]
+mod chip8;
+#[path = "../../../src/logging.rs"]
+mod logging;
+#[allow(dead_code)]
+#[path = "../../../src/rom_library.rs"]
+mod rom_library;
+
+use chip8::Chip8;
+use embedded_sdmmc::{Block, BlockCount, BlockDevice, BlockIdx};
+use rom_library::{NoClock, RomLibrary};
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const BLOCK_BYTES: u64 = 512;
+// The partition starts at 1 MiB like on a card formatted by a PC
+const PARTITION_START: u64 = 2048;
+const IMAGE_BYTES: u64 = 32 * 1024 * 1024;
+const PARTITION_TYPE_FAT16: u8 = 0x06;
+// Program memory from 0x200 to the end of the 4 KiB
+const MAX_ROM_BYTES: usize = 4096 - 0x200;
+
+// The image as the SD card driver sees the card
+struct ImageDevice(RefCell<File>);
+
+impl BlockDevice for ImageDevice {
+    type Error = io::Error;
+
+    fn read(&self, blocks: &mut [Block], start: BlockIdx, _reason: &str) -> io::Result<()> {
+        let mut file = self.0.borrow_mut();
+        file.seek(SeekFrom::Start(start.into_bytes()))?;
+        for block in blocks {
+            file.read_exact(&mut block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn write(&self, blocks: &[Block], start: BlockIdx) -> io::Result<()> {
+        let mut file = self.0.borrow_mut();
+        file.seek(SeekFrom::Start(start.into_bytes()))?;
+        for block in blocks {
+            file.write_all(&block.contents)?;
+        }
+        Ok(())
+    }
+
+    fn num_blocks(&self) -> io::Result<BlockCount> {
+        let len = self.0.borrow().metadata()?.len();
+        Ok(BlockCount((len / BLOCK_BYTES) as u32))
+    }
+}
+
+// The partition on its own, for the fatfs crate which expects a whole volume
+struct Partition {
+    file: File,
+    start: u64,
+    len: u64,
+    position: u64,
+}
+
+impl Partition {
+    fn open(path: &Path) -> io::Result<Partition> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len() - PARTITION_START * BLOCK_BYTES;
+        Ok(Partition {
+            file,
+            start: PARTITION_START * BLOCK_BYTES,
+            len,
+            position: 0,
+        })
+    }
+}
+
+impl Read for Partition {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = buf.len().min((self.len - self.position) as usize);
+        self.file
+            .seek(SeekFrom::Start(self.start + self.position))?;
+        let count = self.file.read(&mut buf[..count])?;
+        self.position += count as u64;
+        Ok(count)
+    }
+}
+
+impl Write for Partition {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = buf.len().min((self.len - self.position) as usize);
+        self.file
+            .seek(SeekFrom::Start(self.start + self.position))?;
+        let count = self.file.write(&buf[..count])?;
+        self.position += count as u64;
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for Partition {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::End(offset) => self.len as i64 + offset,
+            SeekFrom::Current(offset) => self.position as i64 + offset,
+        };
+        if position < 0 || position as u64 > self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek outside the partition",
+            ));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+}
+
+fn make(image: &Path, roms: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut file = File::create(image)?;
+    file.set_len(IMAGE_BYTES)?;
+    // MBR with a single partition entry
+    let mut mbr = [0u8; BLOCK_BYTES as usize];
+    let entry = &mut mbr[446..462];
+    entry[4] = PARTITION_TYPE_FAT16;
+    entry[8..12].copy_from_slice(&(PARTITION_START as u32).to_le_bytes());
+    let blocks = (IMAGE_BYTES / BLOCK_BYTES - PARTITION_START) as u32;
+    entry[12..16].copy_from_slice(&blocks.to_le_bytes());
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    file.write_all(&mbr)?;
+    drop(file);
+
+    fatfs::format_volume(
+        Partition::open(image)?,
+        fatfs::FormatVolumeOptions::new().fat_type(fatfs::FatType::Fat16),
+    )?;
+    let fs = fatfs::FileSystem::new(Partition::open(image)?, fatfs::FsOptions::new())?;
+    for rom in roms {
+        let path = Path::new(rom);
+        let name = path.file_name().ok_or("not a file name")?.to_string_lossy();
+        let mut out = fs.root_dir().create_file(&name)?;
+        out.write_all(&std::fs::read(path)?)?;
+        out.flush()?;
+        println!("added {}", name);
+    }
+    fs.unmount()?;
+    Ok(())
+}
+
+// The same file read by fatfs, which knows nothing of the firmware code
+fn read_with_fatfs(image: &Path, name: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let fs = fatfs::FileSystem::new(Partition::open(image)?, fatfs::FsOptions::new())?;
+    let mut bytes = Vec::new();
+    fs.root_dir().open_file(name)?.read_to_end(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn check(image: &Path) -> Result<(), Box<dyn std::error::Error>> {
+    let file = OpenOptions::new().read(true).write(true).open(image)?;
+    let mut library = RomLibrary::new(ImageDevice(RefCell::new(file)), NoClock);
+    let names = library.list().map_err(|e| format!("{:?}", e))?;
+    if names.is_empty() {
+        return Err("no ROMs on the image".into());
+    }
+
+    let mut buffer = [0u8; MAX_ROM_BYTES];
+    for name in &names {
+        let len = library
+            .load(name, &mut buffer)
+            .map_err(|e| format!("loading {}: {:?}", name, e))?;
+        if buffer[..len] != read_with_fatfs(image, name)? {
+            return Err(format!("{} reads differently through fatfs", name).into());
+        }
+        println!("{:<12} {:>5} bytes ok", name, len);
+    }
+
+    // Run the first ROM for a bit and put its state through the card
+    let rom = &names[0];
+    let len = library
+        .load(rom, &mut buffer)
+        .map_err(|e| format!("{:?}", e))?;
+    let mut chip8 = Chip8::new();
+    chip8
+        .load_program(&buffer[..len])
+        .map_err(|e| e.to_string())?;
+    for _ in 0..200 {
+        if chip8.emulate_cycle().is_err() {
+            break;
+        }
+    }
+    let state = chip8.save_state();
+    library
+        .save_state(rom, &state)
+        .map_err(|e| format!("saving state: {:?}", e))?;
+    let mut loaded = vec![0u8; chip8::STATE_BYTES];
+    let len = library
+        .load_state(rom, &mut loaded)
+        .map_err(|e| format!("loading state: {:?}", e))?;
+    let state_file = format!("{}.SAV", rom.split('.').next().unwrap());
+    if loaded[..len] != state[..] || read_with_fatfs(image, &state_file)? != state[..] {
+        return Err("the save state reads back differently".into());
+    }
+    let restored = Chip8::from_state(&loaded).ok_or("the save state doesn't load")?;
+    if restored.screen != chip8.screen || restored.program_counter != chip8.program_counter {
+        return Err("the save state restores a different machine".into());
+    }
+    println!("{:<12} {:>5} bytes ok", state_file, len);
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.split_first() {
+        Some((command, [image, roms @ ..])) if command == "make" => make(Path::new(image), roms),
+        Some((command, [image])) if command == "check" => check(Path::new(image)),
+        _ => {
+            eprintln!("usage: sdcheck make <image> <rom.ch8>...");
+            eprintln!("       sdcheck check <image>");
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("sdcheck: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Removed again when the test is done, pass or fail
+    struct TempImage(PathBuf);
+
+    impl TempImage {
+        fn new(name: &str) -> TempImage {
+            let file = format!("sdcheck-{}-{}.img", std::process::id(), name);
+            TempImage(std::env::temp_dir().join(file))
+        }
+    }
+
+    impl Drop for TempImage {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn bundled_roms_round_trip_through_the_image() {
+        let roms_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/../../roms");
+        let mut roms: Vec<String> = std::fs::read_dir(roms_dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "ch8"))
+            .map(|path| path.to_string_lossy().into_owned())
+            .collect();
+        roms.sort();
+        let image = TempImage::new("roms");
+        make(&image.0, &roms).unwrap();
+        check(&image.0).unwrap();
+    }
+
+    #[test]
+    fn empty_image_has_no_roms() {
+        let image = TempImage::new("empty");
+        make(&image.0, &[]).unwrap();
+        let error = check(&image.0).unwrap_err();
+        assert_eq!(error.to_string(), "no ROMs on the image");
+    }
+}
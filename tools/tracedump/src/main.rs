@@ -0,0 +1,273 @@
+// Turns the instruction trace of a firmware built with the `trace` feature
+// into a listing, one instruction per line with the registers it changed:
+//
+//   $ tracedump show trace.bin
+//   2A4  6A02  LD VA, 02         VA=02
+//
+// `record` runs a ROM in the host emulator and writes the same records, for
+// comparing against a trace from the board.
+//
+// Records don't say which quirks the ROM ran with. --quirks takes them like
+// a .meta file does, `--quirks shift_vy,jump_vx`, for `show` to disassemble
+// the shifts and BNNN the way they ran and for `record` to run the ROM with.
+#[allow(dead_code)]
+#[path = "../../../src/chip8.rs"]
+mod chip8;
+#[path = "../../../src/disasm.rs"]
+mod disasm;
+#[path = "../../../src/logging.rs"]
+mod logging;
+#[path = "../../../src/trace.rs"]
+mod trace;
+
+use chip8::{Chip8, Quirks};
+use disasm::Disassembly;
+use std::fmt::Write as _;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use trace::TraceRecord;
+
+// In the order of Quirks::to_bits, named like in .meta files
+const QUIRK_NAMES: [&str; 4] = ["shift_vy", "load_store_increment_i", "jump_vx", "vf_reset"];
+
+fn usage() -> ! {
+    eprintln!("usage: tracedump show <trace.bin | -> [--quirks <names>]");
+    eprintln!("       tracedump record <rom.ch8> <trace.bin> <instructions> [--quirks <names>]");
+    std::process::exit(2);
+}
+
+fn parse_quirks(names: &str) -> Result<Quirks, String> {
+    let mut bits = 0;
+    for name in names.split([',', ' ']).filter(|name| !name.is_empty()) {
+        let bit = QUIRK_NAMES
+            .iter()
+            .position(|&known| known == name)
+            .ok_or_else(|| {
+                format!(
+                    "unknown quirk {}, expected one of {}",
+                    name,
+                    QUIRK_NAMES.join(", ")
+                )
+            })?;
+        bits |= 1 << bit;
+    }
+    Ok(Quirks::from_bits(bits))
+}
+
+fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let quirks = match args.iter().position(|arg| arg == "--quirks") {
+        Some(at) if at + 1 < args.len() => {
+            let names = args.drain(at..at + 2).nth(1).unwrap();
+            parse_quirks(&names)
+        }
+        Some(_) => usage(),
+        None => Ok(Quirks::default()),
+    };
+    let result = quirks
+        .map_err(Into::into)
+        .and_then(|quirks| match args.as_slice() {
+            [command, trace] if command == "show" => show(trace, quirks),
+            [command, rom, out, count] if command == "record" => match count.parse() {
+                Ok(count) => record(Path::new(rom), Path::new(out), count, quirks),
+                Err(_) => Err("the instruction count must be a whole number".into()),
+            },
+            _ => usage(),
+        });
+    if let Err(e) = result {
+        eprintln!("tracedump: {}", e);
+        std::process::exit(1);
+    }
+}
+
+// `-` reads the trace from stdin
+fn show(path: &str, quirks: Quirks) -> Result<(), Box<dyn std::error::Error>> {
+    let mut bytes = Vec::new();
+    if path == "-" {
+        io::stdin().read_to_end(&mut bytes)?;
+    } else {
+        bytes = std::fs::read(path)?;
+    }
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut at = 0;
+    while let Some((record, len)) = TraceRecord::decode(&bytes[at..]) {
+        writeln!(out, "{}", line(&record, quirks))?;
+        at += len;
+    }
+    if at != bytes.len() {
+        eprintln!("tracedump: {} bytes left over", bytes.len() - at);
+    }
+    Ok(())
+}
+
+fn record(
+    rom: &Path,
+    out: &Path,
+    count: usize,
+    quirks: Quirks,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let program = std::fs::read(rom)?;
+    let mut chip8 = Chip8::new();
+    chip8.quirks = quirks;
+    chip8.load_program(&program).map_err(|e| e.to_string())?;
+    let mut out = BufWriter::new(std::fs::File::create(out)?);
+    let mut bytes = [0; trace::MAX_RECORD_BYTES];
+    for _ in 0..count {
+        let record = trace::step(&mut chip8).map_err(|e| e.to_string())?;
+        let len = record.encode(&mut bytes);
+        out.write_all(&bytes[..len])?;
+    }
+    Ok(())
+}
+
+fn line(record: &TraceRecord, quirks: Quirks) -> String {
+    let mut line = format!(
+        "{:03X}  {:04X}  {:<16}",
+        record.pc,
+        record.opcode,
+        Disassembly(record.opcode, quirks).to_string()
+    );
+    for n in 0..16 {
+        if record.changed_v & (1 << n) != 0 {
+            let _ = write!(line, "  V{:X}={:02X}", n, record.after.v[n]);
+        }
+    }
+    let after = &record.after;
+    for (bit, name, value) in [
+        (0, "I", format!("{:03X}", after.i)),
+        (1, "SP", after.sp.to_string()),
+        (2, "DT", format!("{:02X}", after.dt)),
+        (3, "ST", format!("{:02X}", after.st)),
+    ] {
+        if record.changed & (1 << bit) != 0 {
+            let _ = write!(line, "  {}={}", name, value);
+        }
+    }
+    line.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use trace::{Registers, MAX_RECORD_BYTES};
+
+    fn encode(record: &TraceRecord) -> Vec<u8> {
+        let mut bytes = [0; MAX_RECORD_BYTES];
+        let len = record.encode(&mut bytes);
+        bytes[..len].to_vec()
+    }
+
+    // Every register changed, the longest record there is
+    fn all_changed() -> TraceRecord {
+        let mut after = Registers {
+            i: 0x345,
+            sp: 2,
+            dt: 3,
+            st: 4,
+            ..Registers::default()
+        };
+        for (n, v) in after.v.iter_mut().enumerate() {
+            *v = 0x10 + n as u8;
+        }
+        TraceRecord::new(0x2A4, 0x6A02, &Registers::default(), &after)
+    }
+
+    #[test]
+    fn record_without_changes_is_just_the_header() {
+        let registers = Registers::default();
+        let record = TraceRecord::new(0x200, 0x00E0, &registers, &registers);
+        let bytes = encode(&record);
+        assert_eq!(bytes, [0x00, 0x02, 0xE0, 0x00, 0, 0, 0]);
+        assert_eq!(TraceRecord::decode(&bytes), Some((record, bytes.len())));
+    }
+
+    #[test]
+    fn record_with_every_register_changed_round_trips() {
+        let record = all_changed();
+        assert_eq!((record.changed_v, record.changed), (0xFFFF, 0b1111));
+        let bytes = encode(&record);
+        assert_eq!(bytes.len(), MAX_RECORD_BYTES);
+        assert_eq!(
+            TraceRecord::decode(&bytes),
+            Some((record, MAX_RECORD_BYTES))
+        );
+    }
+
+    #[test]
+    fn truncated_trailing_record_is_left_over() {
+        let first = encode(&all_changed());
+        for cut in 0..first.len() {
+            let mut bytes = first.clone();
+            bytes.extend_from_slice(&first[..cut]);
+            let (record, len) = TraceRecord::decode(&bytes).unwrap();
+            assert_eq!(record, all_changed());
+            assert_eq!(TraceRecord::decode(&bytes[len..]), None, "cut at {}", cut);
+        }
+    }
+
+    // A ROM and the trace recorded from it in the temp directory, removed
+    // again on drop
+    struct TempTrace(PathBuf);
+
+    impl TempTrace {
+        fn new(program: &[u8]) -> TempTrace {
+            let file = format!("tracedump-{}", std::process::id());
+            let trace = TempTrace(std::env::temp_dir().join(file));
+            std::fs::write(trace.0.with_extension("ch8"), program).unwrap();
+            trace
+        }
+    }
+
+    impl Drop for TempTrace {
+        fn drop(&mut self) {
+            for extension in ["ch8", "bin"] {
+                let _ = std::fs::remove_file(self.0.with_extension(extension));
+            }
+        }
+    }
+
+    #[test]
+    fn recorded_trace_decodes_to_the_instructions_run() {
+        let temp = TempTrace::new(&[
+            0x6A, 0x02, // 200: LD VA, 02
+            0xA3, 0x00, // 202: LD I, 300
+            0x22, 0x08, // 204: CALL 208
+            0x12, 0x06, // 206: JP 206
+            0x61, 0x05, // 208: LD V1, 05
+            0xF1, 0x15, // 20A: LD DT, V1
+            0xF1, 0x18, // 20C: LD ST, V1
+        ]);
+        let (rom, out) = (temp.0.with_extension("ch8"), temp.0.with_extension("bin"));
+        record(&rom, &out, 6, Quirks::default()).unwrap();
+
+        let bytes = std::fs::read(&out).unwrap();
+        let mut records = Vec::new();
+        let mut at = 0;
+        while let Some((record, len)) = TraceRecord::decode(&bytes[at..]) {
+            records.push(record);
+            at += len;
+        }
+        assert_eq!(at, bytes.len());
+        let summary: Vec<_> = records
+            .iter()
+            .map(|r| (r.pc, r.opcode, r.changed_v, r.changed))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (0x200, 0x6A02, 1 << 0xA, 0),
+                (0x202, 0xA300, 0, 0b0001),
+                (0x204, 0x2208, 0, 0b0010),
+                (0x208, 0x6105, 1 << 1, 0),
+                (0x20A, 0xF115, 0, 0b0100),
+                (0x20C, 0xF118, 0, 0b1000),
+            ]
+        );
+        assert_eq!(records[0].after.v[0xA], 0x02);
+        assert_eq!(records[1].after.i, 0x300);
+        assert_eq!(records[2].after.sp, 1);
+        assert_eq!(records[3].after.v[1], 0x05);
+        assert_eq!((records[4].after.dt, records[5].after.st), (0x05, 0x05));
+    }
+}
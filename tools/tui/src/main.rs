@@ -0,0 +1,475 @@
+// Runs a ROM in the terminal, for SSH sessions and CI logs. The screen is
+// drawn with half-block characters, two pixel rows to a line, next to the
+// registers, the stack and a disassembly around PC.
+//
+// Keys use the usual layout for emulators on a PC keyboard:
+//   1 2 3 4      1 2 3 C
+//   Q W E R  ->  4 5 6 D
+//   A S D F      7 8 9 E
+//   Z X C V      A 0 B F
+//
+// The arrow keys, Space and Enter are the board's d-pad and A/B buttons,
+// pressing keys through the ROM's key map from src/keymap.rs. Most terminals
+// report key presses but not releases, so like in tools/keysend a key is
+// released once its auto-repeat stops arriving. Esc or Ctrl-C quits.
+//
+// --frames N runs that many frames without a terminal or keys and prints the
+// last one, for CI logs.
+#[allow(dead_code)]
+#[path = "../../../src/chip8.rs"]
+mod chip8;
+#[path = "../../../src/disasm.rs"]
+mod disasm;
+#[allow(dead_code)]
+#[path = "../../../src/keymap.rs"]
+mod keymap;
+#[path = "../../../src/logging.rs"]
+mod logging;
+
+use chip8::{Chip8, InputSource, KEY_COUNT, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, style, terminal, QueueableCommand};
+use disasm::Disassembly;
+use keymap::{
+    Button, ButtonMask, ButtonSource, EitherInput, KeyMap, MappedButtons, BUTTON_COUNT, HEX_KEYMAP,
+};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+const FRAME: Duration = Duration::from_micros(16_667);
+// The firmware's speed for ROMs without a .meta file
+const INSTRUCTIONS_PER_FRAME: u32 = 10;
+// Longer than the gap between auto-repeats, shorter than a deliberate pause
+const HOLD: Duration = Duration::from_millis(150);
+// Key repeat takes a while to start, give the first press longer
+const FIRST_HOLD: Duration = Duration::from_millis(550);
+// Instructions shown before PC in the disassembly
+const DISASSEMBLY_BEFORE: usize = 2;
+
+fn chip8_key(c: char) -> Option<u8> {
+    const LAYOUT: [(char, u8); 16] = [
+        ('1', 0x1),
+        ('2', 0x2),
+        ('3', 0x3),
+        ('4', 0xC),
+        ('q', 0x4),
+        ('w', 0x5),
+        ('e', 0x6),
+        ('r', 0xD),
+        ('a', 0x7),
+        ('s', 0x8),
+        ('d', 0x9),
+        ('f', 0xE),
+        ('z', 0xA),
+        ('x', 0x0),
+        ('c', 0xB),
+        ('v', 0xF),
+    ];
+    let c = c.to_ascii_lowercase();
+    LAYOUT.iter().find(|(k, _)| *k == c).map(|(_, key)| *key)
+}
+
+fn chip8_key_code(code: KeyCode) -> Option<u8> {
+    match code {
+        KeyCode::Char(c) => chip8_key(c),
+        _ => None,
+    }
+}
+
+fn button(code: KeyCode) -> Option<Button> {
+    match code {
+        KeyCode::Up => Some(Button::Up),
+        KeyCode::Down => Some(Button::Down),
+        KeyCode::Left => Some(Button::Left),
+        KeyCode::Right => Some(Button::Right),
+        KeyCode::Char(' ') => Some(Button::A),
+        KeyCode::Enter => Some(Button::B),
+        _ => None,
+    }
+}
+
+// When each held key was pressed first and last seen
+struct Held<const N: usize>([Option<(Instant, Instant)>; N]);
+
+impl<const N: usize> Held<N> {
+    fn new() -> Held<N> {
+        Held([None; N])
+    }
+
+    fn press(&mut self, index: usize, kind: KeyEventKind) {
+        let slot = &mut self.0[index];
+        *slot = match (kind, *slot) {
+            (KeyEventKind::Release, _) => None,
+            (_, Some((first, _))) => Some((first, Instant::now())),
+            (_, None) => Some((Instant::now(), Instant::now())),
+        };
+    }
+
+    // Whether each is still held, letting go of the ones whose auto-repeat
+    // has stopped
+    fn poll(&mut self) -> [bool; N] {
+        let now = Instant::now();
+        let mut held = [false; N];
+        for (held, slot) in held.iter_mut().zip(self.0.iter_mut()) {
+            if let Some((first, last)) = *slot {
+                let hold = if first == last { FIRST_HOLD } else { HOLD };
+                if now - last >= hold {
+                    *slot = None;
+                }
+            }
+            *held = slot.is_some();
+        }
+        held
+    }
+}
+
+impl InputSource for Held<KEY_COUNT> {
+    fn read_keys(&mut self, keys: &mut [u8; KEY_COUNT]) {
+        for (key, held) in keys.iter_mut().zip(self.poll()) {
+            *key = held as u8;
+        }
+    }
+}
+
+impl ButtonSource for Held<BUTTON_COUNT> {
+    fn read_buttons(&mut self) -> ButtonMask {
+        let held = self.poll();
+        (0..BUTTON_COUNT)
+            .filter(|&i| held[i])
+            .fold(0, |mask, i| mask | 1 << i)
+    }
+}
+
+struct Keyboard {
+    keys: Held<KEY_COUNT>,
+    buttons: Held<BUTTON_COUNT>,
+}
+
+impl Keyboard {
+    fn new() -> Keyboard {
+        Keyboard {
+            keys: Held::new(),
+            buttons: Held::new(),
+        }
+    }
+
+    // Returns false on Esc or Ctrl-C
+    fn handle(&mut self, event: Event) -> bool {
+        let Event::Key(key) = event else {
+            return true;
+        };
+        let quit = key.code == KeyCode::Esc
+            || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL));
+        if quit {
+            return false;
+        }
+        if let Some(k) = chip8_key_code(key.code) {
+            self.keys.press(k as usize, key.kind);
+        }
+        if let Some(button) = button(key.code) {
+            self.buttons.press(button as usize, key.kind);
+        }
+        true
+    }
+}
+
+fn screen_lines(chip8: &Chip8) -> Vec<String> {
+    (0..SCREEN_HEIGHT / 2)
+        .map(|row| {
+            (0..SCREEN_WIDTH)
+                .map(|x| {
+                    let top = chip8.screen[row * 2 * SCREEN_WIDTH + x] == 1;
+                    let bottom = chip8.screen[(row * 2 + 1) * SCREEN_WIDTH + x] == 1;
+                    match (top, bottom) {
+                        (true, true) => '█',
+                        (true, false) => '▀',
+                        (false, true) => '▄',
+                        (false, false) => ' ',
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
+
+fn side_lines(chip8: &Chip8) -> Vec<String> {
+    let mut lines = vec![
+        format!(
+            "PC {:03X}  I {:03X}  SP {:X}",
+            chip8.program_counter, chip8.index_register, chip8.stack_pointer
+        ),
+        format!(
+            "DT {:02X}   ST {:02X}",
+            chip8.delay_timer, chip8.sound_timer
+        ),
+    ];
+    for (row, values) in chip8.registers.chunks(4).enumerate() {
+        let registers: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(n, value)| format!("V{:X} {:02X}", row * 4 + n, value))
+            .collect();
+        lines.push(registers.join("  "));
+    }
+    let stack: Vec<String> = chip8.return_stack[..chip8.stack_pointer as usize]
+        .iter()
+        .map(|entry| format!("{:03X}", entry))
+        .collect();
+    lines.push(format!("stack {}", stack.join(" ")));
+    lines.push(String::new());
+    // Fills the rest of the screen's height
+    let pc = chip8.program_counter as usize;
+    let start = pc.saturating_sub(DISASSEMBLY_BEFORE * 2);
+    let count = SCREEN_HEIGHT / 2 - lines.len();
+    for address in (start..chip8.memory.len() - 1).step_by(2).take(count) {
+        let opcode = u16::from_be_bytes([chip8.memory[address], chip8.memory[address + 1]]);
+        let marker = if address == pc { '>' } else { ' ' };
+        lines.push(format!(
+            "{}{:03X}  {:04X}  {}",
+            marker,
+            address,
+            opcode,
+            Disassembly(opcode, chip8.quirks)
+        ));
+    }
+    lines
+}
+
+// The screen and the panes next to it
+fn lines(chip8: &Chip8) -> Vec<String> {
+    let side = side_lines(chip8);
+    screen_lines(chip8)
+        .into_iter()
+        .enumerate()
+        .map(|(row, line)| match side.get(row) {
+            Some(side) => format!("{}  {}", line, side),
+            None => line,
+        })
+        .collect()
+}
+
+fn draw(out: &mut impl Write, chip8: &Chip8) -> io::Result<()> {
+    for (row, line) in lines(chip8).iter().enumerate() {
+        out.queue(cursor::MoveTo(0, row as u16))?
+            .queue(style::Print(line))?
+            .queue(terminal::Clear(terminal::ClearType::UntilNewLine))?;
+    }
+    out.flush()
+}
+
+fn load(path: &str) -> Result<Chip8, String> {
+    let program = std::fs::read(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    let mut chip8 = Chip8::new();
+    chip8.load_program(&program).map_err(|e| e.to_string())?;
+    Ok(chip8)
+}
+
+// Like the board: the keys, the frame's instructions, then one tick of the
+// timers
+fn frame(chip8: &mut Chip8, keyboard: &mut Keyboard, map: &KeyMap, ipf: u32) -> Result<(), String> {
+    let buttons = MappedButtons {
+        source: &mut keyboard.buttons,
+        map,
+    };
+    chip8.poll_input(&mut EitherInput(&mut keyboard.keys, buttons));
+    for _ in 0..ipf {
+        chip8.emulate_cycle().map_err(|e| e.to_string())?;
+    }
+    chip8.tick_timers();
+    Ok(())
+}
+
+fn play(chip8: &mut Chip8, map: &KeyMap, ipf: u32) -> Result<(), String> {
+    let mut out = io::stdout();
+    let mut keyboard = Keyboard::new();
+    let mut next_frame = Instant::now();
+    loop {
+        while event::poll(Duration::ZERO).map_err(|e| e.to_string())? {
+            if !keyboard.handle(event::read().map_err(|e| e.to_string())?) {
+                return Ok(());
+            }
+        }
+        frame(chip8, &mut keyboard, map, ipf)?;
+        draw(&mut out, chip8).map_err(|e| e.to_string())?;
+        next_frame += FRAME;
+        std::thread::sleep(next_frame.saturating_duration_since(Instant::now()));
+    }
+}
+
+fn run(rom: &str, ipf: u32, frames: Option<u32>) -> Result<(), String> {
+    let mut chip8 = load(rom)?;
+    // Buttons on ROMs without a key map of their own press the hex keys
+    // around 5, like on the board
+    let map = keymap::keymap_for(rom).unwrap_or(&HEX_KEYMAP);
+    if let Some(frames) = frames {
+        let mut keyboard = Keyboard::new();
+        for _ in 0..frames {
+            frame(&mut chip8, &mut keyboard, map, ipf)?;
+        }
+        for line in lines(&chip8) {
+            println!("{}", line.trim_end());
+        }
+        return Ok(());
+    }
+    let mut out = io::stdout();
+    terminal::enable_raw_mode().map_err(|e| e.to_string())?;
+    let _ = out
+        .queue(terminal::EnterAlternateScreen)
+        .and_then(|out| out.queue(cursor::Hide))
+        .and_then(|out| out.flush());
+    let result = play(&mut chip8, map, ipf);
+    let _ = out
+        .queue(cursor::Show)
+        .and_then(|out| out.queue(terminal::LeaveAlternateScreen))
+        .and_then(|out| out.flush());
+    let _ = terminal::disable_raw_mode();
+    result
+}
+
+// The ROM, the instructions per frame and the frames to run headless
+fn parse_args(args: &[String]) -> Option<(&str, u32, Option<u32>)> {
+    let mut rom = None;
+    let mut ipf = INSTRUCTIONS_PER_FRAME;
+    let mut frames = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--ipf" => ipf = args.next()?.parse().ok()?,
+            "--frames" => frames = Some(args.next()?.parse().ok()?),
+            path if !path.starts_with('-') && rom.is_none() => rom = Some(path),
+            _ => return None,
+        }
+    }
+    Some((rom?, ipf, frames))
+}
+
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let Some((rom, ipf, frames)) = parse_args(&args) else {
+        eprintln!("usage: tui <rom.ch8> [--ipf N] [--frames N]");
+        std::process::exit(2);
+    };
+    if let Err(e) = run(rom, ipf, frames) {
+        eprintln!("tui: {}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keymap::{button_bit, buttons_from_hex, keymap_for};
+
+    // Presses the same keys every time it is read
+    struct Fixed(&'static [u8]);
+
+    impl InputSource for Fixed {
+        fn read_keys(&mut self, keys: &mut [u8; KEY_COUNT]) {
+            keys.fill(0);
+            for &key in self.0 {
+                keys[key as usize] = 1;
+            }
+        }
+    }
+
+    fn pressed(keys: &[u8; KEY_COUNT]) -> Vec<u8> {
+        (0..KEY_COUNT as u8)
+            .filter(|&k| keys[k as usize] != 0)
+            .collect()
+    }
+
+    #[test]
+    fn key_map_presses_the_keys_of_the_buttons_held() {
+        let map = KeyMap::new(0x2, 0x8, 0x4, 0x6, 0x5, 0x0);
+        let mut keys = [1; KEY_COUNT];
+        map.apply(button_bit(Button::Up) | button_bit(Button::A), &mut keys);
+        assert_eq!(pressed(&keys), [0x2, 0x5]);
+        map.apply(0, &mut keys);
+        assert_eq!(pressed(&keys), []);
+    }
+
+    #[test]
+    fn key_map_leaves_out_unassigned_buttons_and_the_menu() {
+        let mut map = KeyMap::new(0x1, 0x4, 0x1, 0x4, 0xC, 0xD);
+        map.keys[Button::B as usize] = None;
+        let mut keys = [0; KEY_COUNT];
+        map.apply(
+            button_bit(Button::B) | button_bit(Button::Menu) | button_bit(Button::Down),
+            &mut keys,
+        );
+        assert_eq!(pressed(&keys), [0x4]);
+    }
+
+    #[test]
+    fn key_maps_are_found_by_file_name() {
+        let pong = keymap_for("pong").unwrap();
+        assert_eq!(keymap_for("PONG.ch8"), Some(pong));
+        assert_eq!(
+            keymap_for("../roms/Pong [Paul Vervalin, 1990].ch8"),
+            Some(pong)
+        );
+        assert_eq!(
+            keymap_for("Space Invaders (David Winter).ch8")
+                .unwrap()
+                .keys[2],
+            Some(0x4)
+        );
+        assert_eq!(keymap_for("tetris.ch8"), None);
+        // Only the .ch8 extension is dropped
+        assert_eq!(keymap_for("pong.rom"), None);
+    }
+
+    #[test]
+    fn hex_keypad_cluster_is_read_as_buttons() {
+        let mut keys = [0; KEY_COUNT];
+        assert_eq!(buttons_from_hex(&keys), 0);
+        keys[0x2] = 1;
+        keys[0x6] = 1;
+        keys[0x0] = 1;
+        // Not part of the cluster
+        keys[0xF] = 1;
+        let expected = button_bit(Button::Up) | button_bit(Button::Right) | button_bit(Button::B);
+        assert_eq!(buttons_from_hex(&keys), expected);
+        // Round trips through the matching key map
+        let mut mapped = [0; KEY_COUNT];
+        HEX_KEYMAP.apply(expected, &mut mapped);
+        assert_eq!(pressed(&mapped), [0x0, 0x2, 0x6]);
+    }
+
+    #[test]
+    fn either_input_presses_the_keys_of_both_sources() {
+        let mut keys = [0; KEY_COUNT];
+        EitherInput(Fixed(&[0x1, 0x5]), Fixed(&[0x5, 0xA])).read_keys(&mut keys);
+        assert_eq!(pressed(&keys), [0x1, 0x5, 0xA]);
+        EitherInput(Fixed(&[]), Fixed(&[0xF])).read_keys(&mut keys);
+        assert_eq!(pressed(&keys), [0xF]);
+    }
+
+    #[test]
+    fn key_skips_use_the_low_nibble_of_vx() {
+        // V0 = 0x15, SKP V0, SKNP V0
+        let program = [0x60, 0x15, 0xE0, 0x9E, 0x00, 0xE0, 0xE0, 0xA1];
+        let mut chip8 = Chip8::new();
+        chip8.load_program(&program).unwrap();
+        chip8.keys[0x5] = 1;
+        for _ in 0..2 {
+            chip8.emulate_cycle().unwrap();
+        }
+        assert_eq!(chip8.program_counter, 0x206);
+        chip8.emulate_cycle().unwrap();
+        assert_eq!(chip8.program_counter, 0x208);
+    }
+
+    #[test]
+    fn buttons_reach_the_emulator_through_the_map() {
+        let mut keyboard = Keyboard::new();
+        keyboard.keys.press(0x1, KeyEventKind::Press);
+        keyboard
+            .buttons
+            .press(Button::Left as usize, KeyEventKind::Press);
+        let mut chip8 = Chip8::new();
+        let map = keymap_for("brix").unwrap();
+        frame(&mut chip8, &mut keyboard, map, 0).unwrap();
+        assert_eq!(pressed(&chip8.keys), [0x1, 0x4]);
+    }
+}